@@ -0,0 +1,373 @@
+"use strict";
+
+// Tempo range of the dial, the + and - buttons and tap tempo go beyond it
+const DIAL_MIN = 30;
+const DIAL_MAX = 300;
+// Degrees covered by the dial, the gap is at the bottom
+const DIAL_SWEEP = 300;
+// Longest time between two tempo changes sent while dragging the dial
+const DIAL_SEND_INTERVAL = 100;
+
+const $ = (id) => document.getElementById(id);
+
+const state = {
+  bpm: 120,
+  playing: false,
+  measure: "",
+};
+
+// Pairing, only needed when the server was started with it
+
+let token = localStorage.getItem("token");
+
+async function pair(code) {
+  const response = await fetch("api/pair", {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ code, name: navigator.userAgent }),
+  });
+  if (response.status === 409) {
+    return;
+  }
+  if (!response.ok) {
+    throw new Error((await response.text()) || response.statusText);
+  }
+  token = (await response.json()).token;
+  localStorage.setItem("token", token);
+}
+
+let pairing = null;
+
+// Asks for the code shown by the host once, however many requests were refused meanwhile
+function askCode() {
+  if (pairing === null) {
+    pairing = (async () => {
+      for (;;) {
+        const code = prompt("Pairing code shown by the metronome");
+        if (code === null) {
+          throw new Error("not paired");
+        }
+        try {
+          return await pair(code);
+        } catch (error) {
+          alert(error.message);
+        }
+      }
+    })().finally(() => {
+      pairing = null;
+    });
+  }
+  return pairing;
+}
+
+// Viewers can only follow, the admin code shown by the host lets them take control
+function showRole(role) {
+  const viewer = role === "viewer";
+  document.body.classList.toggle("viewer", viewer);
+  $("control").hidden = !viewer;
+  for (const control of document.querySelectorAll("main button:not(#control), #measure, #silent")) {
+    control.disabled = viewer;
+  }
+}
+
+$("control").addEventListener("click", async () => {
+  const code = prompt("Admin pairing code shown by the metronome");
+  if (code === null) {
+    return;
+  }
+  try {
+    await pair(code);
+    location.reload();
+  } catch (error) {
+    alert(error.message);
+  }
+});
+
+async function api(path, options = {}) {
+  const send = () =>
+    fetch("api" + path, {
+      ...options,
+      headers: token ? { ...options.headers, Authorization: `Bearer ${token}` } : options.headers,
+    });
+  let response = await send();
+  if (response.status === 401) {
+    await askCode();
+    response = await send();
+  }
+  if (!response.ok && response.status !== 204) {
+    throw new Error((await response.text()) || response.statusText);
+  }
+  return response;
+}
+
+function post(path, body) {
+  const options = { method: "POST" };
+  if (body !== undefined) {
+    options.headers = { "Content-Type": "text/plain" };
+    options.body = body;
+  }
+  return api(path, options);
+}
+
+// Dial
+
+function polar(degrees, radius) {
+  const radians = ((degrees - 90) * Math.PI) / 180;
+  return [100 + radius * Math.cos(radians), 100 + radius * Math.sin(radians)];
+}
+
+function dialAngle(bpm) {
+  const clamped = Math.min(DIAL_MAX, Math.max(DIAL_MIN, bpm));
+  return -DIAL_SWEEP / 2 + ((clamped - DIAL_MIN) / (DIAL_MAX - DIAL_MIN)) * DIAL_SWEEP;
+}
+
+function showBpm(bpm) {
+  $("bpm").textContent = bpm;
+  $("dial").setAttribute("aria-valuenow", bpm);
+  const start = -DIAL_SWEEP / 2;
+  const end = dialAngle(bpm);
+  const [x0, y0] = polar(start, 84);
+  const [x1, y1] = polar(end, 84);
+  const large = end - start > 180 ? 1 : 0;
+  $("arc").setAttribute("d", `M ${x0} ${y0} A 84 84 0 ${large} 1 ${x1} ${y1}`);
+  const [kx, ky] = polar(end, 84);
+  $("knob").setAttribute("cx", kx);
+  $("knob").setAttribute("cy", ky);
+}
+
+let dragging = false;
+let lastSent = 0;
+let pending = null;
+
+function sendBpm(bpm) {
+  clearTimeout(pending);
+  const wait = lastSent + DIAL_SEND_INTERVAL - Date.now();
+  if (wait > 0) {
+    pending = setTimeout(() => sendBpm(bpm), wait);
+    return;
+  }
+  lastSent = Date.now();
+  post(`/set_bpm/${bpm}`).catch(showOffline);
+}
+
+function setBpm(bpm) {
+  bpm = Math.min(1000, Math.max(1, Math.round(bpm)));
+  state.bpm = bpm;
+  showBpm(bpm);
+  sendBpm(bpm);
+}
+
+function dialBpm(event) {
+  const rect = $("dial").getBoundingClientRect();
+  const x = event.clientX - rect.left - rect.width / 2;
+  const y = event.clientY - rect.top - rect.height / 2;
+  let degrees = (Math.atan2(x, -y) * 180) / Math.PI;
+  degrees = Math.min(DIAL_SWEEP / 2, Math.max(-DIAL_SWEEP / 2, degrees));
+  return DIAL_MIN + ((degrees + DIAL_SWEEP / 2) / DIAL_SWEEP) * (DIAL_MAX - DIAL_MIN);
+}
+
+$("dial").addEventListener("pointerdown", (event) => {
+  dragging = true;
+  $("dial").setPointerCapture(event.pointerId);
+  setBpm(dialBpm(event));
+});
+$("dial").addEventListener("pointermove", (event) => {
+  if (dragging) {
+    setBpm(dialBpm(event));
+  }
+});
+$("dial").addEventListener("pointerup", () => {
+  dragging = false;
+});
+$("dial").addEventListener("keydown", (event) => {
+  const step = { ArrowUp: 1, ArrowRight: 1, ArrowDown: -1, ArrowLeft: -1 }[event.key];
+  if (step) {
+    event.preventDefault();
+    setBpm(state.bpm + step);
+  }
+});
+
+$("slower").addEventListener("click", () => setBpm(state.bpm - 1));
+$("faster").addEventListener("click", () => setBpm(state.bpm + 1));
+
+$("tap").addEventListener("click", async () => {
+  const bpm = await (await post("/tap")).json();
+  if (bpm !== null) {
+    state.bpm = bpm;
+    showBpm(bpm);
+  }
+});
+
+// Start and stop
+
+function showPlaying(playing) {
+  state.playing = playing;
+  $("toggle").textContent = playing ? "Stop" : "Start";
+}
+
+$("toggle").addEventListener("click", () => {
+  post(state.playing ? "/stop" : "/start").catch(showOffline);
+});
+
+// Measure
+
+// One dot for each of `beats`, the sound types of the beats
+function showMeasure(measure, beats) {
+  state.measure = measure;
+  if (document.activeElement !== $("measure")) {
+    $("measure").value = measure;
+  }
+  $("beats").replaceChildren(
+    ...beats.map((beat) => {
+      const dot = document.createElement("span");
+      dot.className = beat;
+      return dot;
+    }),
+  );
+}
+
+$("measure-form").addEventListener("submit", async (event) => {
+  event.preventDefault();
+  try {
+    await post("/measure", $("measure").value);
+    $("measure-error").hidden = true;
+    $("measure").blur();
+  } catch (error) {
+    $("measure-error").textContent = error.message;
+    $("measure-error").hidden = false;
+  }
+});
+
+// Silent mode and beat flashes
+
+$("silent").addEventListener("change", (event) => {
+  post(`/set_silent/${event.target.checked}`).catch(showOffline);
+  if (event.target.checked) {
+    $("vibrate").checked = true;
+  }
+});
+
+function flash(cue) {
+  const overlay = $("flash");
+  overlay.style.transition = "none";
+  overlay.style.background = cue.color;
+  overlay.style.opacity = cue.intensity * 0.6;
+  requestAnimationFrame(() => {
+    overlay.style.transition = `opacity ${cue.flash}ms ease-out`;
+    overlay.style.opacity = 0;
+  });
+  const dots = $("beats").children;
+  for (let i = 0; i < dots.length; i++) {
+    dots[i].classList.toggle("on", i === cue.beat && cue.sound_type !== null);
+  }
+  if ($("vibrate").checked && navigator.vibrate) {
+    navigator.vibrate(cue.vibration);
+  }
+}
+
+// Asks for every beat ahead of time. `at` is on the server's clock, so it's only passed back and
+// the flash is timed from `in_ms`, whatever the phone's clock says.
+async function followCues() {
+  let after = null;
+  for (;;) {
+    try {
+      const response = await api("/cue" + (after === null ? "" : `?after=${after}`));
+      if (response.status === 204) {
+        continue;
+      }
+      const cue = await response.json();
+      after = cue.at;
+      setTimeout(() => flash(cue), cue.in_ms);
+    } catch (error) {
+      after = null;
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+    }
+  }
+}
+
+// State
+
+function showOffline() {
+  $("connection").textContent = "offline";
+  $("connection").className = "offline";
+}
+
+async function refresh() {
+  const status = await (await api("/status")).json();
+  state.bpm = status.bpm;
+  showBpm(status.bpm);
+  showPlaying(status.playing);
+  showMeasure(status.measure, status.beats);
+  $("silent").checked = status.silent;
+  showRole(status.role);
+}
+
+function followEvents() {
+  // EventSource can't send headers
+  const events = new EventSource("api/events" + (token ? `?token=${encodeURIComponent(token)}` : ""));
+  events.onopen = () => {
+    $("connection").textContent = "connected";
+    $("connection").className = "";
+    refresh().catch(showOffline);
+  };
+  events.onerror = () => {
+    showOffline();
+    // Probably refused, asking for the status pairs again if needed
+    if (events.readyState === EventSource.CLOSED) {
+      refresh()
+        .then(followEvents)
+        .catch(() => setTimeout(followEvents, 1000));
+    }
+  };
+  events.onmessage = (message) => {
+    const event = JSON.parse(message.data);
+    switch (event.type) {
+      case "start":
+      case "play":
+        showPlaying(true);
+        break;
+      case "pause":
+      case "stop":
+        showPlaying(false);
+        break;
+      case "bpm":
+        if (!dragging) {
+          state.bpm = event.bpm;
+          showBpm(event.bpm);
+        }
+        break;
+      case "measure":
+        showMeasure(event.measure, event.beats);
+        break;
+      case "silent":
+        $("silent").checked = event.silent;
+        break;
+      case "preset":
+      case "arrangement":
+        refresh().catch(showOffline);
+        break;
+    }
+  };
+  // Named events don't reach `onmessage`
+  for (const type of ["start", "play", "pause", "stop", "bpm", "measure", "silent", "preset", "arrangement"]) {
+    events.addEventListener(type, events.onmessage);
+  }
+}
+
+async function start() {
+  // The QR code shown by the host carries the code
+  const params = new URLSearchParams(location.search);
+  if (params.has("code")) {
+    try {
+      await pair(params.get("code"));
+    } catch (error) {
+      alert(error.message);
+    }
+    history.replaceState(null, "", location.pathname);
+  }
+  followEvents();
+  followCues();
+}
+
+showBpm(state.bpm);
+start();
@@ -0,0 +1,396 @@
+//! Where the player's measures go: an output device, the plugin's process buffer, or memory
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use rodio::{buffer::SamplesBuffer, source::UniformSourceIterator, Sink};
+
+use crate::{
+    mixer::{CHANNELS, SAMPLE_RATE},
+    player::QUEUED_MEASURES,
+};
+
+/// A queue of rendered measures, as interleaved stereo frames at `mixer::SAMPLE_RATE`
+pub trait ClickSink: Send {
+    /// Queues the frames after the ones already queued
+    fn append(&mut self, frames: Vec<f32>);
+    /// Drops everything that is queued
+    fn stop(&mut self);
+    fn play(&mut self);
+    fn pause(&mut self);
+    /// Buffers that aren't done playing, including the one being played
+    fn queued(&self) -> usize;
+    /// How far the buffer being played is
+    fn position(&self) -> Duration;
+    fn set_volume(&mut self, volume: f32);
+    fn volume(&self) -> f32;
+}
+
+/// A rodio sink, playing on an output device
+pub struct RodioSink(Sink);
+
+impl RodioSink {
+    /// A sink of an output device, see `device::DeviceOutput`
+    pub fn new(sink: Sink) -> Self {
+        RodioSink(sink)
+    }
+}
+
+impl ClickSink for RodioSink {
+    fn append(&mut self, frames: Vec<f32>) {
+        self.0
+            .append(SamplesBuffer::new(CHANNELS, SAMPLE_RATE, frames));
+    }
+
+    fn stop(&mut self) {
+        self.0.stop();
+    }
+
+    fn play(&mut self) {
+        self.0.play();
+    }
+
+    fn pause(&mut self) {
+        self.0.pause();
+    }
+
+    fn queued(&self) -> usize {
+        self.0.len()
+    }
+
+    fn position(&self) -> Duration {
+        self.0.get_pos()
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.0.set_volume(volume);
+    }
+
+    fn volume(&self) -> f32 {
+        self.0.volume()
+    }
+}
+
+/// Measures handed to the audio thread at once. The player keeps `QUEUED_MEASURES` queued,
+/// plus the end sound, so they always fit and `ProcessSink::pending` stays empty.
+const PROCESS_BUFFERS: usize = 16;
+const _: () = assert!(PROCESS_BUFFERS > QUEUED_MEASURES);
+
+/// A measure converted to the host's sample rate, numbered in the order it was queued
+struct Buffer {
+    index: u64,
+    samples: Vec<f32>,
+}
+
+/// What both ends of a `ProcessSink` share, the audio thread only touches atomics
+#[derive(Default)]
+struct ProcessState {
+    /// Buffers finished or dropped by the audio thread
+    consumed: AtomicU64,
+    /// Buffers numbered below it were stopped
+    stopped: AtomicU64,
+    /// Samples played of the current buffer
+    position: AtomicUsize,
+    paused: AtomicBool,
+    /// Bits of the `f32` volume
+    volume: AtomicU32,
+}
+
+/// Queues the click for `Plugin::process()`, which pulls it from the `ProcessOutput`. The
+/// measures are converted to the host's sample rate before they reach the audio thread, they're
+/// passed over bounded channels so the audio thread neither locks nor allocates, and they're
+/// sent back to be freed here.
+pub struct ProcessSink {
+    buffers: SyncSender<Buffer>,
+    done: Receiver<Buffer>,
+    /// Buffers the audio thread had no room for yet, see `PROCESS_BUFFERS`
+    pending: VecDeque<Buffer>,
+    queued: u64,
+    sample_rate: u32,
+    state: Arc<ProcessState>,
+}
+
+impl ProcessSink {
+    pub fn new(channels: u16, sample_rate: u32) -> (Self, ProcessOutput) {
+        let (buffers, buffers_rx) = mpsc::sync_channel(PROCESS_BUFFERS);
+        // Room for every buffer and the one being played, so the audio thread never frees one
+        let (done_tx, done) = mpsc::sync_channel(PROCESS_BUFFERS + 1);
+        let state = Arc::new(ProcessState::default());
+        state.volume.store(1.0f32.to_bits(), Ordering::Relaxed);
+        let sink = ProcessSink {
+            buffers,
+            done,
+            pending: VecDeque::new(),
+            queued: 0,
+            sample_rate,
+            state: state.clone(),
+        };
+        let output = ProcessOutput {
+            buffers: buffers_rx,
+            done: done_tx,
+            current: None,
+            mono: channels == 1,
+            state,
+        };
+        (sink, output)
+    }
+
+    /// Hands over what the audio thread has room for and frees what it's done with
+    fn flush(&mut self) {
+        while self.done.try_recv().is_ok() {}
+        while let Some(buffer) = self.pending.pop_front() {
+            if let Err(TrySendError::Full(buffer)) = self.buffers.try_send(buffer) {
+                self.pending.push_front(buffer);
+                break;
+            }
+        }
+    }
+}
+
+impl ClickSink for ProcessSink {
+    fn append(&mut self, frames: Vec<f32>) {
+        let samples = if self.sample_rate == SAMPLE_RATE {
+            frames
+        } else {
+            let source = SamplesBuffer::new(CHANNELS, SAMPLE_RATE, frames);
+            UniformSourceIterator::new(source, CHANNELS, self.sample_rate).collect()
+        };
+        self.pending.push_back(Buffer {
+            index: self.queued,
+            samples,
+        });
+        self.queued += 1;
+        self.flush();
+    }
+
+    fn stop(&mut self) {
+        self.pending.clear();
+        self.state.stopped.store(self.queued, Ordering::Release);
+        self.flush();
+    }
+
+    fn play(&mut self) {
+        self.state.paused.store(false, Ordering::Relaxed);
+    }
+
+    fn pause(&mut self) {
+        self.state.paused.store(true, Ordering::Relaxed);
+    }
+
+    fn queued(&self) -> usize {
+        let consumed = self.state.consumed.load(Ordering::Acquire);
+        let done = consumed.max(self.state.stopped.load(Ordering::Relaxed));
+        self.queued.saturating_sub(done) as usize
+    }
+
+    fn position(&self) -> Duration {
+        let samples = self.state.position.load(Ordering::Relaxed);
+        Duration::from_secs_f64(samples as f64 / (CHANNELS as u32 * self.sample_rate) as f64)
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.state.volume.store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    fn volume(&self) -> f32 {
+        f32::from_bits(self.state.volume.load(Ordering::Relaxed))
+    }
+}
+
+/// The click of a `ProcessSink`, in the host's channel count. It yields silence once nothing
+/// is queued or while paused, and never allocates or locks.
+pub struct ProcessOutput {
+    buffers: Receiver<Buffer>,
+    done: SyncSender<Buffer>,
+    /// The buffer being played and how far it is
+    current: Option<(Buffer, usize)>,
+    /// Both channels are mixed down, so clicks routed to either side are heard
+    mono: bool,
+    state: Arc<ProcessState>,
+}
+
+impl ProcessOutput {
+    fn sample(&mut self) -> f32 {
+        if self.state.paused.load(Ordering::Relaxed) {
+            return 0.0;
+        }
+        let stopped = self.state.stopped.load(Ordering::Acquire);
+        loop {
+            match &self.current {
+                Some((buffer, position))
+                    if buffer.index >= stopped && *position < buffer.samples.len() =>
+                {
+                    break
+                }
+                Some(_) => {
+                    let (buffer, _) = self.current.take().unwrap();
+                    // Only fails if the sink is gone, which frees it here anyway
+                    let _ = self.done.try_send(buffer);
+                    self.state.consumed.fetch_add(1, Ordering::Release);
+                }
+                None => match self.buffers.try_recv() {
+                    Ok(buffer) => {
+                        self.state.position.store(0, Ordering::Relaxed);
+                        self.current = Some((buffer, 0));
+                    }
+                    Err(_) => return 0.0,
+                },
+            }
+        }
+        let (buffer, position) = self.current.as_mut().unwrap();
+        let sample = buffer.samples[*position];
+        *position += 1;
+        self.state.position.store(*position, Ordering::Relaxed);
+        sample * f32::from_bits(self.state.volume.load(Ordering::Relaxed))
+    }
+}
+
+impl Iterator for ProcessOutput {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let left = self.sample();
+        if !self.mono {
+            return Some(left);
+        }
+        let right = self.sample();
+        Some((left + right) * 0.5)
+    }
+}
+
+/// Keeps the measures in memory instead of playing them, e.g. for tests or a machine without a
+/// sound card. Every measure counts as played as soon as it's queued. Clones share the recording.
+#[derive(Debug, Clone)]
+pub struct RecordingSink {
+    recording: Arc<Mutex<Vec<f32>>>,
+    volume: f32,
+}
+
+impl Default for RecordingSink {
+    fn default() -> Self {
+        RecordingSink {
+            recording: Arc::new(Mutex::new(Vec::new())),
+            volume: 1.0,
+        }
+    }
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every frame queued so far, with the volume applied
+    pub fn recording(&self) -> Vec<f32> {
+        self.recording.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.recording.lock().unwrap().clear();
+    }
+}
+
+impl ClickSink for RecordingSink {
+    fn append(&mut self, frames: Vec<f32>) {
+        self.recording
+            .lock()
+            .unwrap()
+            .extend(frames.iter().map(|sample| sample * self.volume));
+    }
+
+    fn stop(&mut self) {}
+
+    fn play(&mut self) {}
+
+    fn pause(&mut self) {}
+
+    fn queued(&self) -> usize {
+        0
+    }
+
+    fn position(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+    }
+
+    fn volume(&self) -> f32 {
+        self.volume
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pull(output: &mut ProcessOutput, samples: usize) -> Vec<f32> {
+        output.take(samples).collect()
+    }
+
+    #[test]
+    fn plays_in_order() {
+        let (mut sink, mut output) = ProcessSink::new(2, SAMPLE_RATE);
+        sink.append(vec![1.0; 4]);
+        sink.append(vec![2.0; 2]);
+        assert_eq!(sink.queued(), 2);
+
+        assert_eq!(pull(&mut output, 2), [1.0, 1.0]);
+        assert_eq!(
+            sink.position(),
+            Duration::from_secs_f64(1.0 / SAMPLE_RATE as f64)
+        );
+        assert_eq!(pull(&mut output, 6), [1.0, 1.0, 2.0, 2.0, 0.0, 0.0]);
+        assert_eq!(sink.queued(), 0);
+    }
+
+    #[test]
+    fn stop_drops_the_queue() {
+        let (mut sink, mut output) = ProcessSink::new(2, SAMPLE_RATE);
+        sink.append(vec![1.0; 4]);
+        sink.append(vec![1.0; 4]);
+        pull(&mut output, 2);
+        sink.stop();
+        assert_eq!(sink.queued(), 0);
+
+        sink.append(vec![2.0; 2]);
+        assert_eq!(sink.queued(), 1);
+        assert_eq!(pull(&mut output, 3), [2.0, 2.0, 0.0]);
+        assert_eq!(sink.queued(), 0);
+    }
+
+    #[test]
+    fn pause_and_volume() {
+        let (mut sink, mut output) = ProcessSink::new(2, SAMPLE_RATE);
+        sink.append(vec![1.0; 4]);
+        sink.pause();
+        assert_eq!(pull(&mut output, 2), [0.0, 0.0]);
+        sink.play();
+        sink.set_volume(0.5);
+        assert_eq!(pull(&mut output, 4), [0.5; 4]);
+    }
+
+    #[test]
+    fn mono_mixes_down() {
+        let (mut sink, mut output) = ProcessSink::new(1, SAMPLE_RATE);
+        sink.append(vec![1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(pull(&mut output, 2), [0.5, 0.5]);
+    }
+
+    #[test]
+    fn converts_the_sample_rate() {
+        let (mut sink, mut output) = ProcessSink::new(2, SAMPLE_RATE * 2);
+        sink.append(vec![1.0; 200]);
+        let samples = pull(&mut output, 500);
+        let played = samples.iter().filter(|sample| **sample != 0.0).count();
+        assert!((390..=400).contains(&played), "{played}");
+    }
+}
@@ -1,105 +1,199 @@
 use std::{
-    fs::{self, File},
-    io::Write,
-    net::{IpAddr, Ipv4Addr},
-    sync::{Arc, Mutex},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    process::ExitCode,
+    thread,
+    time::Duration,
 };
 
-use local_ip_address::{list_afinet_netifas, local_ip};
-use poem::{listener::TcpListener, middleware::AddData, web::Data, EndpointExt, Route, Server};
-use poem_openapi::{param::Path, OpenApi, OpenApiService};
+use egui::{Color32, ColorImage, TextureHandle, TextureOptions};
 use port_check::free_local_port_in_range;
-use qrcode::{render::unicode, QrCode};
-use racoon::pusher::Pusher;
-use racoon::{discovery_server::DiscoveryServer, State};
-use racoon::{player::RodioPlayer, Api};
+use qrcode::QrCode;
+use racoon::{
+    backend::RodioSink,
+    device::DeviceOutput,
+    discovery_server::DiscoveryServer,
+    measure::{Measure, NUMERATOR_RANGE},
+    player::{Player, BPM_RANGE},
+    presets::Presets,
+    server, AppState, State,
+};
+use tokio::runtime::Runtime;
+
+struct RacoonApp {
+    state: AppState,
+    _discovery_server: Option<DiscoveryServer>,
+    url: Option<String>,
+    qr: Option<TextureHandle>,
+    bpm: u64,
+    beats_per_measure: usize,
+}
 
-#[tokio::main]
-async fn main() -> Result<(), std::io::Error> {
-    let up = fs::read("assets/up.wav").unwrap();
-    let down = fs::read("assets/down.wav").unwrap();
-    let (player, _stream, _stream_handle) = RodioPlayer::new(up, down);
+/// How often the window catches up with changes made from the remote, MIDI or tapping
+const REFRESH: Duration = Duration::from_millis(250);
+
+impl RacoonApp {
+    fn new(cc: &eframe::CreationContext<'_>, output: DeviceOutput, sink: RodioSink) -> Self {
+        let player = Player::new(sink);
+        let bpm = player.bpm();
+        let beats_per_measure = player.measure().beats_per_measure();
+
+        let state = State::new(player, Presets::load_default(), Default::default());
+        state.set_output(output);
+
+        let Some(port) = free_local_port_in_range(20000..=60000) else {
+            panic!(
+                "Couldn't find an open port, you shouldn't realistically be seeing this, exiting..."
+            );
+        };
+
+        let server_state = state.clone();
+        thread::spawn(move || {
+            let rt = Runtime::new().unwrap();
+            rt.block_on(server::serve(
+                server_state,
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+                None,
+            ))
+        });
+
+        let url = server::lan_url(port, false);
+        let qr = url.as_deref().and_then(qr_image).map(|image| {
+            cc.egui_ctx
+                .load_texture("lan-url-qr", image, TextureOptions::NEAREST)
+        });
+
+        Self {
+            state,
+            _discovery_server: DiscoveryServer::new(port.into(), false),
+            url,
+            qr,
+            bpm,
+            beats_per_measure,
+        }
+    }
+}
 
-    let player = Arc::new(Mutex::new(player));
+fn qr_image(data: &str) -> Option<ColorImage> {
+    const QUIET_ZONE: usize = 2;
 
-    let pusher = Mutex::new(Pusher::new(player.clone()));
+    let code = QrCode::new(data).ok()?;
+    let width = code.width();
+    let size = width + QUIET_ZONE * 2;
+    let mut image = ColorImage::new([size, size], Color32::WHITE);
+    for (i, color) in code.to_colors().into_iter().enumerate() {
+        if color == qrcode::Color::Dark {
+            image[(i % width + QUIET_ZONE, i / width + QUIET_ZONE)] = Color32::BLACK;
+        }
+    }
+    Some(image)
+}
 
-    let state = Arc::new(State { player, pusher });
+impl eframe::App for RacoonApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        ctx.request_repaint_after(REFRESH);
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.heading("Racoon Metronome");
+
+            ui.horizontal(|ui| {
+                let playing = self.state.player.lock().unwrap().playing();
+                if ui.button(if playing { "Stop" } else { "Start" }).clicked() {
+                    self.state.toggle();
+                }
+                if ui.button("Play").clicked() {
+                    self.state.play();
+                }
+                if ui.button("Pause").clicked() {
+                    self.state.pause();
+                }
+            });
+
+            // Only applied once the user is done dragging, a new measure starts over. Otherwise
+            // they follow the player, which the remote, MIDI or tapping may have changed.
+            ui.horizontal(|ui| {
+                let bpm = ui.add(egui::Slider::new(&mut self.bpm, BPM_RANGE).text("BPM"));
+                if bpm.drag_stopped() || (bpm.changed() && !bpm.dragged()) {
+                    self.state.set_bpm(self.bpm);
+                } else if !bpm.dragged() {
+                    self.bpm = self.state.player.lock().unwrap().bpm();
+                }
+                if ui.button("Tap").clicked() {
+                    if let Some(bpm) = self.state.tap() {
+                        self.bpm = bpm;
+                    }
+                }
+            });
+
+            let beats = ui.add(
+                egui::Slider::new(&mut self.beats_per_measure, NUMERATOR_RANGE)
+                    .text("Beats per measure"),
+            );
+            if beats.drag_stopped() || (beats.changed() && !beats.dragged()) {
+                self.state.set_measure(Measure::new(self.beats_per_measure));
+            } else if !beats.dragged() {
+                self.beats_per_measure = self
+                    .state
+                    .player
+                    .lock()
+                    .unwrap()
+                    .measure()
+                    .beats_per_measure();
+            }
 
-    let Some(port) = free_local_port_in_range(20000..=60000) else {
-        panic!(
-            "Couldn't find an open port, you shouldn't realistically be seeing this, exiting..."
-        );
-    };
+            let mut volume = self.state.player.lock().unwrap().volume();
+            if ui
+                .add(egui::Slider::new(&mut volume, 0.0..=1.0).text("Volume"))
+                .changed()
+            {
+                self.state.set_volume(volume);
+            }
 
-    let api_service = OpenApiService::new(Api, "Racoon Metronome", "0.1")
-        .server(&format!("http://localhost:{port}/api"));
+            ui.separator();
 
-    #[cfg(debug_assertions)]
-    {
-        let mut file = File::create("openapi.yml")?;
-        file.write_all(api_service.spec_yaml().as_bytes())?;
+            match &self.url {
+                Some(url) => {
+                    ui.label("Scan to open the remote:");
+                    ui.hyperlink(url);
+                }
+                None => {
+                    ui.label("Couldn't find a LAN address, the remote is only available locally");
+                }
+            }
+            if let Some(qr) = &self.qr {
+                ui.add(egui::Image::new((qr.id(), egui::vec2(200.0, 200.0))));
+            }
+        });
     }
+}
 
-    let ui = api_service.swagger_ui();
-    let app = Route::new()
-        .nest("/api", api_service)
-        .nest("/doc", ui)
-        .with(AddData::new(state));
-
-    let _discovery_server = DiscoveryServer::new(port.into());
-    let network_interfaces = list_afinet_netifas().unwrap();
-    let mut loopback: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-    let likely_local = local_ip();
-    let mut locals = Vec::with_capacity(10);
-
-    let likely_local_qr = if let Ok(ip) = likely_local {
-        let code = QrCode::new(&format!("http://{ip}:{port}")).unwrap();
-        code.render::<unicode::Dense1x2>()
-            .quiet_zone(true)
-            .dark_color(unicode::Dense1x2::Light)
-            .light_color(unicode::Dense1x2::Dark)
-            .build()
-    } else {
-        String::from("")
-    };
-
-    for (_, ip) in network_interfaces.iter() {
-        if ip.is_loopback() {
-            loopback = *ip;
+fn main() -> ExitCode {
+    let (output, sink) = match DeviceOutput::open(None) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("error: couldn't open the output device: {e}");
+            return ExitCode::FAILURE;
         }
-        if let IpAddr::V4(ipv4) = ip.to_canonical() {
-            if ipv4.is_private() {
-                locals.push(ip);
-            }
+    };
+    let native_options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default()
+            .with_inner_size([400.0, 460.0])
+            .with_min_inner_size([300.0, 220.0])
+            .with_icon(
+                // NOTE: Adding an icon is optional
+                eframe::icon_data::from_png_bytes(&include_bytes!("../../assets/icon-256.png")[..])
+                    .expect("Failed to load icon"),
+            ),
+        ..Default::default()
+    };
+    let result = eframe::run_native(
+        "Racoon Metronome",
+        native_options,
+        Box::new(|cc| Ok(Box::new(RacoonApp::new(cc, output, sink)))),
+    );
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
         }
     }
-
-    let mut qr_str = locals
-        .iter()
-        .map(|e| e.to_string())
-        .collect::<Vec<String>>()
-        .join(",");
-    qr_str.push_str(&format!(":{port}"));
-
-    let code = QrCode::new(&qr_str).unwrap();
-    let code = code
-        .render::<unicode::Dense1x2>()
-        .quiet_zone(true)
-        .dark_color(unicode::Dense1x2::Light)
-        .light_color(unicode::Dense1x2::Dark)
-        .build();
-
-    println!("{loopback}",);
-    println!("{likely_local_qr}",);
-    println!("{code}",);
-
-    Server::new(TcpListener::bind(&format!("0.0.0.0:{port}")))
-        .name("racoon")
-        .run(app)
-        .await
 }
-
-/*
-
-*/
@@ -0,0 +1,264 @@
+//! Estimates the output latency by playing clicks and listening for them on an input, e.g. a
+//! microphone next to the speaker or a cable from the output to the input
+
+use std::{
+    fmt::Display,
+    io,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use rodio::{
+    cpal::{
+        self,
+        traits::{HostTrait, StreamTrait},
+        FromSample, SampleFormat, SizedSample, StreamConfig,
+    },
+    DeviceTrait,
+};
+
+use crate::{
+    mixer::{self, CHANNELS, SAMPLE_RATE},
+    player::Player,
+};
+
+const CLICKS: usize = 6;
+/// Between the clicks, in seconds
+const INTERVAL: f64 = 1.0;
+/// Of silence before the first click, to measure the noise
+const LEAD_IN: f64 = 0.25;
+const CLICK_VOLUME: f32 = 0.5;
+/// Longer latencies are mistaken for shorter ones, as the click is heard after the next one is
+/// played
+pub const MAX_LATENCY: Duration = Duration::from_millis(800);
+/// Quieter recordings don't count as hearing the click
+const MIN_LEVEL: f32 = 0.01;
+
+#[derive(Debug)]
+pub enum CalibrationError {
+    /// Not enough clicks stood out from the noise
+    NotHeard,
+    Io(io::Error),
+}
+
+impl Display for CalibrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CalibrationError::NotHeard => write!(f, "the clicks weren't heard on the input"),
+            CalibrationError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for CalibrationError {}
+
+impl From<io::Error> for CalibrationError {
+    fn from(e: io::Error) -> Self {
+        CalibrationError::Io(e)
+    }
+}
+
+/// Mono samples of an input
+pub struct Recording {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+/// An output connected to an input
+pub trait Loopback {
+    /// Plays interleaved stereo `frames` at `mixer::SAMPLE_RATE` and records the input until
+    /// they're done playing. The recording starts when `frames` are handed to the output.
+    fn play_and_record(&mut self, frames: Vec<f32>) -> io::Result<Recording>;
+}
+
+/// Plays through the player's output device and records an input device
+pub struct DeviceLoopback {
+    player: Arc<Mutex<Player>>,
+    /// The default input device if `None`
+    input: Option<String>,
+}
+
+impl DeviceLoopback {
+    pub fn new(player: Arc<Mutex<Player>>, input: Option<String>) -> Self {
+        DeviceLoopback { player, input }
+    }
+}
+
+/// Records every channel mixed down into `recording`
+fn input_stream<T: SizedSample>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    recording: Arc<Mutex<Vec<f32>>>,
+) -> io::Result<cpal::Stream>
+where
+    f32: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    device
+        .build_input_stream(
+            config,
+            move |data: &[T], _: &cpal::InputCallbackInfo| {
+                recording
+                    .lock()
+                    .unwrap()
+                    .extend(data.chunks(channels).map(|frame| {
+                        frame.iter().map(|s| s.to_sample::<f32>()).sum::<f32>() / channels as f32
+                    }));
+            },
+            |e| eprintln!("Couldn't record the input: {e}"),
+            None,
+        )
+        .map_err(io::Error::other)
+}
+
+impl Loopback for DeviceLoopback {
+    fn play_and_record(&mut self, frames: Vec<f32>) -> io::Result<Recording> {
+        let host = cpal::default_host();
+        let device = match &self.input {
+            Some(name) => host
+                .input_devices()
+                .map_err(io::Error::other)?
+                .find(|device| device.name().is_ok_and(|n| n == *name)),
+            None => host.default_input_device(),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such input device"))?;
+        let supported = device.default_input_config().map_err(io::Error::other)?;
+        let config = supported.config();
+        let recording = Arc::new(Mutex::new(Vec::new()));
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => input_stream::<f32>(&device, &config, recording.clone()),
+            SampleFormat::I16 => input_stream::<i16>(&device, &config, recording.clone()),
+            SampleFormat::U16 => input_stream::<u16>(&device, &config, recording.clone()),
+            format => Err(io::Error::other(format!(
+                "unsupported input sample format {format}"
+            ))),
+        }?;
+        stream.play().map_err(io::Error::other)?;
+
+        let length = frames.len() as f64 / (SAMPLE_RATE as f64 * CHANNELS as f64);
+        let start = {
+            let mut player = self.player.lock().unwrap();
+            let start = recording.lock().unwrap().len();
+            player.play_frames(frames);
+            start
+        };
+        thread::sleep(Duration::from_secs_f64(length));
+        drop(stream);
+        let mut samples = std::mem::take(&mut *recording.lock().unwrap());
+        samples.drain(..start.min(samples.len()));
+        Ok(Recording {
+            samples,
+            sample_rate: config.sample_rate.0,
+        })
+    }
+}
+
+/// The clicks to play, and when each of them is played in seconds
+fn signal() -> (Vec<f32>, Vec<f64>) {
+    let click = mixer::tone(1760.0, 40, SAMPLE_RATE);
+    let times: Vec<f64> = (0..CLICKS).map(|i| LEAD_IN + i as f64 * INTERVAL).collect();
+    let channels = CHANNELS as usize;
+    let length = LEAD_IN + CLICKS as f64 * INTERVAL;
+    let mut frames = vec![0.0; (length * SAMPLE_RATE as f64) as usize * channels];
+    for time in &times {
+        let start = (time * SAMPLE_RATE as f64) as usize * channels;
+        for (out, sample) in frames[start..].iter_mut().zip(click.iter()) {
+            *out = sample * CLICK_VOLUME;
+        }
+    }
+    (frames, times)
+}
+
+/// The median delay between playing a click and its onset in the recording
+fn estimate(recording: &Recording, clicks: &[f64]) -> Option<Duration> {
+    let rate = recording.sample_rate as f64;
+    let samples = &recording.samples;
+    let at = |seconds: f64| ((seconds * rate) as usize).min(samples.len());
+    let peak = |samples: &[f32]| samples.iter().fold(0.0_f32, |peak, s| peak.max(s.abs()));
+    let noise = peak(&samples[..at(clicks[0])]);
+    let mut latencies: Vec<f64> = clicks
+        .iter()
+        .filter_map(|&click| {
+            let window = &samples[at(click)..at(click + MAX_LATENCY.as_secs_f64())];
+            let level = peak(window);
+            if level < MIN_LEVEL || level < noise * 4.0 {
+                return None;
+            }
+            let onset = window.iter().position(|s| s.abs() >= level * 0.5)?;
+            Some(onset as f64 / rate)
+        })
+        .collect();
+    if latencies.len() * 2 < clicks.len() {
+        return None;
+    }
+    latencies.sort_by(f64::total_cmp);
+    Some(Duration::from_secs_f64(latencies[latencies.len() / 2]))
+}
+
+/// Plays a few clicks and returns the output latency, including the input's. Nothing else
+/// should be playing meanwhile.
+pub fn calibrate(loopback: &mut impl Loopback) -> Result<Duration, CalibrationError> {
+    let (frames, clicks) = signal();
+    let recording = loopback.play_and_record(frames)?;
+    estimate(&recording, &clicks).ok_or(CalibrationError::NotHeard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hears the output after `latency`, quieter, with some noise and at another sample rate
+    struct SimulatedLoopback {
+        latency: Duration,
+        gain: f32,
+    }
+
+    impl Loopback for SimulatedLoopback {
+        fn play_and_record(&mut self, frames: Vec<f32>) -> io::Result<Recording> {
+            let sample_rate = 44_100;
+            let delay = (self.latency.as_secs_f64() * sample_rate as f64) as usize;
+            let length = frames.len() / CHANNELS as usize * sample_rate / SAMPLE_RATE as usize;
+            let mut seed = 0x2545_f491_4f6c_dd1du64;
+            let samples = (0..length)
+                .map(|i| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    let noise = (seed >> 40) as f32 / (1u64 << 24) as f32 * 0.002 - 0.001;
+                    let played = i
+                        .checked_sub(delay)
+                        .map(|i| i * SAMPLE_RATE as usize / sample_rate * CHANNELS as usize)
+                        .map_or(0.0, |frame| frames[frame] + frames[frame + 1]);
+                    played * self.gain + noise
+                })
+                .collect();
+            Ok(Recording {
+                samples,
+                sample_rate: sample_rate as u32,
+            })
+        }
+    }
+
+    #[test]
+    fn simulated_loopback() {
+        for millis in [0, 37, 250, 700] {
+            let latency = Duration::from_millis(millis);
+            let mut loopback = SimulatedLoopback { latency, gain: 0.2 };
+            let estimate = calibrate(&mut loopback).unwrap();
+            assert!(
+                estimate.abs_diff(latency) < Duration::from_millis(1),
+                "{estimate:?} instead of {latency:?}"
+            );
+        }
+
+        let mut silent = SimulatedLoopback {
+            latency: Duration::ZERO,
+            gain: 0.0,
+        };
+        assert!(matches!(
+            calibrate(&mut silent),
+            Err(CalibrationError::NotHeard)
+        ));
+    }
+}
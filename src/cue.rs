@@ -0,0 +1,47 @@
+//! Visual and haptic cues for following the beats without hearing them, e.g. in silent mode
+
+use crate::measure::SoundType;
+
+/// How to flash a screen and vibrate a phone on a beat, each sound type feels different
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    /// CSS color of the flash
+    pub color: &'static str,
+    /// Of the flash, from 0.0 to 1.0
+    pub intensity: f32,
+    /// Length of the flash in milliseconds
+    pub flash: u64,
+    /// Alternating vibration and pause lengths in milliseconds, as taken by
+    /// `navigator.vibrate()`
+    pub vibration: &'static [u64],
+}
+
+/// The cue of a beat, `None` is the count-in
+pub fn cue(sound_type: Option<SoundType>) -> Cue {
+    match sound_type {
+        Some(SoundType::Up) => Cue {
+            color: "#ff3b30",
+            intensity: 1.0,
+            flash: 150,
+            vibration: &[120],
+        },
+        Some(SoundType::Mid) => Cue {
+            color: "#ff9500",
+            intensity: 0.7,
+            flash: 100,
+            vibration: &[50, 30, 50],
+        },
+        Some(SoundType::Down) => Cue {
+            color: "#ffffff",
+            intensity: 0.4,
+            flash: 80,
+            vibration: &[40],
+        },
+        None => Cue {
+            color: "#34c759",
+            intensity: 0.6,
+            flash: 80,
+            vibration: &[20, 20, 20],
+        },
+    }
+}
@@ -0,0 +1,194 @@
+//! Output devices of the desktop app and the headless server, the plugin plays through the host
+
+use std::{
+    fmt::Display,
+    io,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use rodio::{
+    cpal::{self, traits::HostTrait},
+    DeviceTrait, OutputStream, Sink,
+};
+
+use crate::{backend::RodioSink, player::Player};
+
+/// How often the device being played is checked for being unplugged
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub enum DeviceError {
+    NotFound,
+    Io(io::Error),
+}
+
+impl Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceError::NotFound => write!(f, "no output device with this name"),
+            DeviceError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+impl From<io::Error> for DeviceError {
+    fn from(e: io::Error) -> Self {
+        DeviceError::Io(e)
+    }
+}
+
+/// Names of the output devices, in the order the host lists them
+pub fn output_devices() -> Vec<String> {
+    match cpal::default_host().output_devices() {
+        Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn default_device() -> Option<String> {
+    cpal::default_host()
+        .default_output_device()
+        .and_then(|device| device.name().ok())
+}
+
+/// Opens the device named `name`, or the default device
+fn open(name: Option<&str>) -> Result<(OutputStream, RodioSink, String), DeviceError> {
+    let host = cpal::default_host();
+    let device = match name {
+        Some(name) => host
+            .output_devices()
+            .map_err(io::Error::other)?
+            .find(|device| device.name().is_ok_and(|n| n == name))
+            .ok_or(DeviceError::NotFound)?,
+        None => host
+            .default_output_device()
+            .ok_or_else(|| io::Error::other("no default output device"))?,
+    };
+    let name = device.name().map_err(io::Error::other)?;
+    let (stream, handle) = OutputStream::try_from_device(&device).map_err(io::Error::other)?;
+    let sink = Sink::try_new(&handle).map_err(io::Error::other)?;
+    Ok((stream, RodioSink::new(sink), name))
+}
+
+enum Command {
+    Attach(Arc<Mutex<Player>>),
+    Open(Option<String>, mpsc::Sender<Result<String, DeviceError>>),
+}
+
+/// Keeps the stream of the device being played on its own thread, as streams aren't `Send`. The
+/// player goes back to the default device when its device is unplugged.
+pub struct DeviceOutput {
+    commands: mpsc::Sender<Command>,
+    current: Arc<Mutex<String>>,
+}
+
+impl DeviceOutput {
+    /// Opens the device named `name`, the default device if it's `None` or can't be found. The
+    /// sink is for the player, which then needs to be attached.
+    pub fn open(name: Option<&str>) -> io::Result<(Self, RodioSink)> {
+        let name = name.map(str::to_string);
+        let current = Arc::new(Mutex::new(String::new()));
+        let (commands, receiver) = mpsc::channel();
+        let (opened, result) = mpsc::channel();
+        let thread_current = current.clone();
+        thread::Builder::new()
+            .name(String::from("output device"))
+            .spawn(move || {
+                let device = match open(name.as_deref()) {
+                    Err(DeviceError::NotFound) => {
+                        eprintln!(
+                            "Couldn't find the output device {}, using the default one",
+                            name.unwrap_or_default()
+                        );
+                        open(None)
+                    }
+                    result => result,
+                };
+                match device {
+                    Ok((stream, sink, name)) => {
+                        *thread_current.lock().unwrap() = name;
+                        let _ = opened.send(Ok(sink));
+                        Self::run(stream, receiver, thread_current);
+                    }
+                    Err(e) => {
+                        let _ = opened.send(Err(io::Error::other(e)));
+                    }
+                }
+            })?;
+        let sink = result
+            .recv()
+            .map_err(|_| io::Error::other("the output device thread stopped"))??;
+        Ok((DeviceOutput { commands, current }, sink))
+    }
+
+    fn run(
+        mut stream: OutputStream,
+        receiver: mpsc::Receiver<Command>,
+        current: Arc<Mutex<String>>,
+    ) {
+        let mut player: Option<Arc<Mutex<Player>>> = None;
+        loop {
+            let (name, reply) = match receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(Command::Attach(attached)) => {
+                    player = Some(attached);
+                    continue;
+                }
+                Ok(Command::Open(name, reply)) => (name, Some(reply)),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    let name = current.lock().unwrap().clone();
+                    if output_devices().contains(&name) {
+                        continue;
+                    }
+                    eprintln!("The output device {name} is gone, using the default one");
+                    (None, None)
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            };
+            let result = open(name.as_deref()).map(|(new_stream, sink, name)| {
+                if let Some(player) = &player {
+                    player.lock().unwrap().set_sink(sink);
+                }
+                // The previous stream stops once the player moved on
+                stream = new_stream;
+                *current.lock().unwrap() = name.clone();
+                name
+            });
+            match reply {
+                Some(reply) => {
+                    let _ = reply.send(result);
+                }
+                None => {
+                    if let Err(e) = result {
+                        eprintln!("Couldn't open the default output device: {e}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves `player` to the device when switching or falling back to the default device
+    pub fn attach(&self, player: Arc<Mutex<Player>>) {
+        let _ = self.commands.send(Command::Attach(player));
+    }
+
+    /// Switches to the device named `name`, or to the default device, carrying on playing.
+    /// Returns the name of the device.
+    pub fn set_device(&self, name: Option<&str>) -> Result<String, DeviceError> {
+        let (reply, result) = mpsc::channel();
+        self.commands
+            .send(Command::Open(name.map(str::to_string), reply))
+            .map_err(|_| io::Error::other("the output device thread stopped"))?;
+        result
+            .recv()
+            .map_err(|_| io::Error::other("the output device thread stopped"))?
+    }
+
+    /// Name of the device being played
+    pub fn device(&self) -> String {
+        self.current.lock().unwrap().clone()
+    }
+}
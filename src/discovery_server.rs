@@ -17,8 +17,11 @@ pub struct DiscoveryServer {
 }
 
 impl DiscoveryServer {
-    pub fn new(port: usize) -> Option<Self> {
-        let p = port.to_le_bytes();
+    /// Answers with the port of the server in little endian, followed by a byte set to 1 if it
+    /// serves HTTPS
+    pub fn new(port: usize, tls: bool) -> Option<Self> {
+        let mut p = port.to_le_bytes().to_vec();
+        p.push(tls as u8);
         let socket = find_available_port(15987, 16000)?;
         let thread = thread::spawn(move || {
             //FIXME get free ports etc
@@ -0,0 +1,81 @@
+//! Changes of the metronome's state, for the clients following them
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::mixer::Levels;
+
+/// Events a client hasn't received yet, it misses the older ones once it falls further behind
+const CAPACITY: usize = 64;
+
+/// Serialized with its name as `type`, e.g. `{"type":"bpm","bpm":140}`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// From the downbeat
+    Start,
+    Play,
+    Pause,
+    Stop,
+    Bpm {
+        bpm: u64,
+    },
+    /// In the text notation, also on a subdivision, swing or humanize change
+    Measure {
+        measure: String,
+        /// `up`, `mid`, `down` or `rest` for every beat
+        beats: Vec<&'static str>,
+    },
+    Layers {
+        count: usize,
+    },
+    Routing {
+        routing: &'static str,
+    },
+    Levels {
+        levels: Levels,
+    },
+    Volume {
+        volume: f32,
+    },
+    /// In milliseconds
+    Latency {
+        latency: f64,
+    },
+    Silent {
+        silent: bool,
+    },
+    Device {
+        name: String,
+    },
+    Arrangement {
+        sections: usize,
+    },
+    /// Loaded, changing the other settings with it
+    Preset {
+        name: String,
+    },
+    /// A preset was saved, renamed or deleted
+    Presets,
+}
+
+/// A broadcast channel of the events, every subscriber receives every event
+pub struct Events(broadcast::Sender<Event>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Events(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl Events {
+    /// Dropped if nobody is subscribed
+    pub fn publish(&self, event: Event) {
+        let _ = self.0.send(event);
+    }
+
+    /// Receives the events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+}
@@ -1,124 +1,581 @@
 use std::{
     fs::{self, File},
     io::Write,
-    net::{IpAddr, Ipv4Addr},
-    sync::{Arc, Mutex},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    time::Duration,
 };
 
-use local_ip_address::{list_afinet_netifas, local_ip};
-use poem::{listener::TcpListener, middleware::AddData, web::Data, EndpointExt, Route, Server};
-use poem_openapi::{param::Path, OpenApi, OpenApiService};
+use clap::{Args, Parser, Subcommand};
 use port_check::free_local_port_in_range;
-use qrcode::{render::unicode, QrCode};
-use racoon::pusher::Pusher;
-use racoon::{discovery_server::DiscoveryServer, State};
-use racoon::{player::RodioPlayer, Api};
-
-// #[tokio::main]
-// async fn main() -> Result<(), std::io::Error> {
-//     let up = fs::read("assets/up.wav").unwrap();
-//     let down = fs::read("assets/down.wav").unwrap();
-//     let (player, _stream, _stream_handle) = RodioPlayer::new(up, down);
-
-//     let player = Arc::new(Mutex::new(player));
-
-//     let pusher = Mutex::new(Pusher::new(player.clone()));
-
-//     let state = Arc::new(State { player, pusher });
-
-//     let Some(port) = free_local_port_in_range(20000..=60000) else {
-//         panic!(
-//             "Couldn't find an open port, you shouldn't realistically be seeing this, exiting..."
-//         );
-//     };
-
-//     let api_service = OpenApiService::new(Api, "Racoon Metronome", "0.1")
-//         .server(&format!("http://localhost:{port}/api"));
-
-//     #[cfg(debug_assertions)]
-//     {
-//         let mut file = File::create("openapi.yml")?;
-//         file.write_all(api_service.spec_yaml().as_bytes())?;
-//     }
-
-//     let ui = api_service.swagger_ui();
-//     let app = Route::new()
-//         .nest("/api", api_service)
-//         .nest("/doc", ui)
-//         .with(AddData::new(state));
-
-//     let _discovery_server = DiscoveryServer::new(port.into());
-//     let network_interfaces = list_afinet_netifas().unwrap();
-//     let mut loopback: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-//     let likely_local = local_ip();
-//     let mut locals = Vec::with_capacity(10);
-
-//     let likely_local_qr = if let Ok(ip) = likely_local {
-//         let code = QrCode::new(&format!("{ip}:{port}")).unwrap();
-//         code.render::<unicode::Dense1x2>()
-//             .quiet_zone(true)
-//             .dark_color(unicode::Dense1x2::Light)
-//             .light_color(unicode::Dense1x2::Dark)
-//             .build()
-//     } else {
-//         String::from("")
-//     };
-
-//     for (_, ip) in network_interfaces.iter() {
-//         if ip.is_loopback() {
-//             loopback = *ip;
-//         }
-//         if let IpAddr::V4(ipv4) = ip.to_canonical() {
-//             if ipv4.is_private() {
-//                 locals.push(ip);
-//             }
-//         }
-//     }
-
-//     let mut qr_str = locals
-//         .iter()
-//         .map(|e| e.to_string())
-//         .collect::<Vec<String>>()
-//         .join(",");
-//     qr_str.push_str(&format!(":{port}"));
-
-//     let code = QrCode::new(&qr_str).unwrap();
-//     let code = code
-//         .render::<unicode::Dense1x2>()
-//         .quiet_zone(true)
-//         .dark_color(unicode::Dense1x2::Light)
-//         .light_color(unicode::Dense1x2::Dark)
-//         .build();
-
-//     println!("{loopback}",);
-//     println!("{likely_local_qr}",);
-//     println!("{code}",);
-
-//     Server::new(TcpListener::bind(&format!("0.0.0.0:{port}")))
-//         .name("racoon")
-//         .run(app)
-//         .await
-// }
-
-fn main() -> eframe::Result {
-    let native_options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default()
-            .with_inner_size([400.0, 300.0])
-            .with_min_inner_size([300.0, 220.0])
-            .with_icon(
-                // NOTE: Adding an icon is optional
-                eframe::icon_data::from_png_bytes(&include_bytes!("../assets/icon-256.png")[..])
-                    .expect("Failed to load icon"),
-            ),
-        ..Default::default()
+use racoon::{
+    calibration,
+    device::{self, DeviceOutput},
+    discovery_server::DiscoveryServer,
+    measure::{Measure, MAX_DURATION, NUMERATOR_RANGE},
+    mixer::Routing,
+    pairing::{Pairing, Role},
+    player::{Player, SampleSelection, BPM_RANGE},
+    presets::Presets,
+    render::{self, Section},
+    server, smf,
+    tls::Tls,
+    State,
+};
+use serde::Deserialize;
+
+/// Headless Racoon Metronome server, controlled through its REST API
+#[derive(Parser, Debug)]
+#[command(name = "racoon-server", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Read the settings from a TOML file, command line arguments take precedence
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Port to listen on [default: a free port between 20000 and 60000]
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Address to bind to [default: 0.0.0.0]
+    #[arg(short, long, value_name = "ADDRESS")]
+    bind: Option<IpAddr>,
+
+    /// Sample played on accented beats [default: the bundled sample]
+    #[arg(long, value_name = "FILE")]
+    up: Option<PathBuf>,
+
+    /// Sample played on the other beats [default: the bundled sample]
+    #[arg(long, value_name = "FILE")]
+    down: Option<PathBuf>,
+
+    /// Initial tempo [default: 120]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(BPM_RANGE))]
+    bpm: Option<u64>,
+
+    /// Initial number of beats per measure [default: 4]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(BEATS_RANGE))]
+    beats: Option<u64>,
+
+    /// Initial measure in the text notation, e.g. "4/4: A m m m | sub=eights"
+    #[arg(long, value_name = "NOTATION", conflicts_with = "beats")]
+    measure: Option<String>,
+
+    /// Presets library [default: presets.json in the user's config directory]
+    #[arg(long, value_name = "FILE")]
+    presets: Option<PathBuf>,
+
+    /// Load this preset on startup, it takes precedence over the other initial settings
+    #[arg(long, value_name = "NAME")]
+    preset: Option<String>,
+
+    /// Output device to play on, as listed by the `devices` command [default: the system's
+    /// default device]
+    #[arg(long, value_name = "NAME")]
+    device: Option<String>,
+
+    /// Follow the tempo map of this Standard MIDI File instead of the measure
+    #[arg(long, value_name = "FILE")]
+    midi: Option<PathBuf>,
+
+    /// `split` plays the accents on the left and the other beats on the right, `left` and
+    /// `right` the whole click on one channel [default: stereo]
+    #[arg(long, value_name = "MODE", value_parser = ["stereo", "split", "left", "right"])]
+    routing: Option<String>,
+
+    /// Time between playing and hearing the click in milliseconds, e.g. of a Bluetooth speaker,
+    /// see `POST /api/calibrate` [default: 0]
+    #[arg(long, value_name = "MS")]
+    latency: Option<f64>,
+
+    /// Mute the click, the beats can still be followed with `GET /api/cue`
+    #[arg(long)]
+    silent: bool,
+
+    /// Only clients paired with one of the printed codes can use the REST API, see
+    /// `POST /api/pair`. Clients paired with the viewer code can't change anything.
+    #[arg(long)]
+    pairing: bool,
+
+    /// With `--pairing`, the `GET` endpoints don't need pairing
+    #[arg(long, requires = "pairing")]
+    open_reads: bool,
+
+    /// With `--pairing`, requests from this machine don't need pairing and can change
+    /// everything. Not for use behind a reverse proxy on this machine, every request would
+    /// seem to come from it.
+    #[arg(long, requires = "pairing")]
+    trust_localhost: bool,
+
+    /// Serve HTTPS with a self-signed certificate, generated on the first start. Its fingerprint
+    /// is printed and put in the QR code.
+    #[arg(long)]
+    tls: bool,
+
+    /// Serve HTTPS with this PEM certificate instead, followed by its chain if any
+    #[arg(long, value_name = "FILE", requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// The PEM private key of `--cert`
+    #[arg(long, value_name = "FILE", requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// Don't answer discovery requests
+    #[arg(long)]
+    no_discovery: bool,
+
+    /// Don't print the QR codes of the LAN addresses
+    #[arg(long)]
+    no_qr: bool,
+
+    /// Write the OpenAPI spec to this file on startup
+    #[arg(long, value_name = "FILE")]
+    openapi: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Render a click track to a WAV file instead of starting the server
+    Render(RenderArgs),
+    /// List the output devices
+    Devices,
+}
+
+#[derive(Args, Debug)]
+struct RenderArgs {
+    /// WAV file to write
+    output: PathBuf,
+
+    /// Tempo
+    #[arg(long, default_value_t = 120, value_parser = clap::value_parser!(u64).range(BPM_RANGE))]
+    bpm: u64,
+
+    /// Measure in the text notation [default: "4/4: A m m m"]
+    #[arg(long, value_name = "NOTATION")]
+    measure: Option<String>,
+
+    /// Number of measures
+    #[arg(long, default_value_t = 4)]
+    bars: u64,
+
+    /// TOML file with a `[[sections]]` table for every tempo change, each with a `bpm`, a
+    /// `measure` and a number of `bars`, and optionally the `ramp_to` tempo
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["bpm", "measure", "bars"])]
+    sections: Option<PathBuf>,
+
+    /// Standard MIDI File whose tempo map is rendered
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["bpm", "measure", "bars", "sections"])]
+    midi: Option<PathBuf>,
+
+    #[arg(long, default_value_t = 48_000, value_parser = clap::value_parser!(u32).range(8_000..=192_000))]
+    sample_rate: u32,
+
+    /// Sample played on accented beats [default: the bundled sample]
+    #[arg(long, value_name = "FILE")]
+    up: Option<PathBuf>,
+
+    /// Sample played on the other beats [default: the bundled sample]
+    #[arg(long, value_name = "FILE")]
+    down: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct SectionsFile {
+    sections: Vec<SectionConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct SectionConfig {
+    bpm: u64,
+    ramp_to: Option<u64>,
+    measure: Option<String>,
+    bars: u64,
+}
+
+/// Same settings as the command line, e.g. `port = 20000` or `discovery = false`
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    port: Option<u16>,
+    bind: Option<IpAddr>,
+    up: Option<PathBuf>,
+    down: Option<PathBuf>,
+    bpm: Option<u64>,
+    beats: Option<u64>,
+    measure: Option<String>,
+    presets: Option<PathBuf>,
+    preset: Option<String>,
+    device: Option<String>,
+    midi: Option<PathBuf>,
+    routing: Option<String>,
+    latency: Option<f64>,
+    silent: Option<bool>,
+    pairing: Option<bool>,
+    open_reads: Option<bool>,
+    trust_localhost: Option<bool>,
+    tls: Option<bool>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    discovery: Option<bool>,
+    qr: Option<bool>,
+    openapi: Option<PathBuf>,
+}
+
+const BEATS_RANGE: std::ops::RangeInclusive<u64> =
+    *NUMERATOR_RANGE.start() as u64..=*NUMERATOR_RANGE.end() as u64;
+
+#[derive(Debug)]
+struct Config {
+    port: Option<u16>,
+    bind: IpAddr,
+    up: Option<PathBuf>,
+    down: Option<PathBuf>,
+    bpm: u64,
+    measure: Measure,
+    presets: Option<PathBuf>,
+    preset: Option<String>,
+    device: Option<String>,
+    midi: Option<PathBuf>,
+    routing: Routing,
+    latency: Duration,
+    silent: bool,
+    pairing: bool,
+    open_reads: bool,
+    trust_localhost: bool,
+    tls: bool,
+    /// The certificate and its key, a self-signed certificate is used without them
+    cert: Option<(PathBuf, PathBuf)>,
+    discovery: bool,
+    qr: bool,
+    openapi: Option<PathBuf>,
+}
+
+impl Config {
+    fn load(cli: Cli) -> Result<Self, String> {
+        let file = match &cli.config {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| format!("couldn't read {}: {e}", path.display()))?;
+                toml::from_str::<ConfigFile>(&text)
+                    .map_err(|e| format!("invalid config {}: {e}", path.display()))?
+            }
+            None => ConfigFile::default(),
+        };
+
+        let bpm = cli.bpm.or(file.bpm).unwrap_or(120);
+        if !BPM_RANGE.contains(&bpm) {
+            return Err(format!("bpm {bpm} is not in {BPM_RANGE:?}"));
+        }
+        let measure = match cli.measure.or(file.measure) {
+            // Only the command line conflicts on both being set
+            Some(notation) if cli.beats.is_none() => parse_measure(&notation)?,
+            _ => {
+                let beats = cli.beats.or(file.beats).unwrap_or(4);
+                if !BEATS_RANGE.contains(&beats) {
+                    return Err(format!("beats {beats} is not in {BEATS_RANGE:?}"));
+                }
+                Measure::new(beats as usize)
+            }
+        };
+        if measure.too_long(bpm) {
+            return Err(format!(
+                "the measure lasts longer than {} minutes at {bpm} BPM, it needs at least {} BPM",
+                MAX_DURATION / 60_000_000_000,
+                measure.slowest_bpm()
+            ));
+        }
+
+        let routing = match cli.routing.or(file.routing) {
+            Some(routing) => {
+                Routing::from_str(&routing).map_err(|()| format!("unknown routing {routing}"))?
+            }
+            None => Routing::default(),
+        };
+
+        let latency = cli.latency.or(file.latency).unwrap_or(0.0);
+        let max = calibration::MAX_LATENCY.as_millis();
+        if !(0.0..=max as f64).contains(&latency) {
+            return Err(format!("latency {latency} is not in 0..={max} ms"));
+        }
+
+        let cert = match (cli.cert.or(file.cert), cli.key.or(file.key)) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => return Err(String::from("cert and key go together")),
+        };
+
+        Ok(Config {
+            port: cli.port.or(file.port),
+            bind: cli
+                .bind
+                .or(file.bind)
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            up: cli.up.or(file.up),
+            down: cli.down.or(file.down),
+            bpm,
+            measure,
+            presets: cli.presets.or(file.presets),
+            preset: cli.preset.or(file.preset),
+            device: cli.device.or(file.device),
+            midi: cli.midi.or(file.midi),
+            routing,
+            latency: Duration::from_secs_f64(latency / 1000.0),
+            silent: cli.silent || file.silent.unwrap_or(false),
+            pairing: cli.pairing || file.pairing.unwrap_or(false),
+            open_reads: cli.open_reads || file.open_reads.unwrap_or(false),
+            trust_localhost: cli.trust_localhost || file.trust_localhost.unwrap_or(false),
+            tls: cli.tls || file.tls.unwrap_or(false) || cert.is_some(),
+            cert,
+            discovery: !cli.no_discovery && file.discovery.unwrap_or(true),
+            qr: !cli.no_qr && file.qr.unwrap_or(true),
+            openapi: cli.openapi.or(file.openapi),
+        })
+    }
+}
+
+fn parse_measure(notation: &str) -> Result<Measure, String> {
+    notation.parse::<Measure>().map_err(|e| {
+        format!(
+            "invalid measure {e}\n  {notation}\n  {:>width$}",
+            "^",
+            width = e.position + 1
+        )
+    })
+}
+
+/// Prints what couldn't be imported exactly
+fn import_midi(path: &Path) -> Result<Vec<Section>, String> {
+    let data = fs::read(path).map_err(|e| format!("couldn't read {}: {e}", path.display()))?;
+    let map = smf::import(&data).map_err(|e| format!("couldn't import {}: {e}", path.display()))?;
+    for warning in &map.warnings {
+        eprintln!("warning: {warning}");
+    }
+    Ok(map.sections)
+}
+
+fn render(args: RenderArgs) -> Result<(), String> {
+    let sections = match (&args.midi, &args.sections) {
+        (Some(midi), _) => import_midi(midi)?,
+        (None, Some(path)) => {
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("couldn't read {}: {e}", path.display()))?;
+            let file = toml::from_str::<SectionsFile>(&text)
+                .map_err(|e| format!("invalid sections {}: {e}", path.display()))?;
+            let mut sections = Vec::with_capacity(file.sections.len());
+            for section in file.sections {
+                for bpm in [Some(section.bpm), section.ramp_to].into_iter().flatten() {
+                    if !BPM_RANGE.contains(&bpm) {
+                        return Err(format!("bpm {bpm} is not in {BPM_RANGE:?}"));
+                    }
+                }
+                let measure = match &section.measure {
+                    Some(notation) => parse_measure(notation)?,
+                    None => Measure::default(),
+                };
+                sections.push(Section {
+                    ramp_to: section.ramp_to,
+                    ..Section::new(section.bpm, measure, section.bars)
+                });
+            }
+            sections
+        }
+        (None, None) => {
+            let measure = match &args.measure {
+                Some(notation) => parse_measure(notation)?,
+                None => Measure::default(),
+            };
+            vec![Section::new(args.bpm, measure, args.bars)]
+        }
     };
-    eframe::run_native(
-        "eframe template",
-        native_options,
-        Box::new(|cc| Ok(Box::new(eframe_template::TemplateApp::new(cc)))),
+    let samples = SampleSelection {
+        up: args.up,
+        down: args.down,
+    };
+    let file = File::create(&args.output)
+        .map_err(|e| format!("couldn't create {}: {e}", args.output.display()))?;
+    render::write_wav(
+        std::io::BufWriter::new(file),
+        &sections,
+        args.sample_rate,
+        &samples,
     )
+    .map_err(|e| format!("couldn't render {}: {e}", args.output.display()))
 }
 
-/*
+#[tokio::main]
+async fn main() -> ExitCode {
+    let mut cli = Cli::parse();
+    match cli.command.take() {
+        Some(Command::Render(args)) => {
+            return match render(args) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("error: {e}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        Some(Command::Devices) => {
+            let default = device::default_device();
+            for name in device::output_devices() {
+                if default.as_ref() == Some(&name) {
+                    println!("{name} (default)");
+                } else {
+                    println!("{name}");
+                }
+            }
+            return ExitCode::SUCCESS;
+        }
+        None => {}
+    }
+
+    let config = match Config::load(cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let (output, sink) = match DeviceOutput::open(config.device.as_deref()) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("error: couldn't open the output device: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut player = Player::new(sink);
+    let samples = SampleSelection {
+        up: config.up,
+        down: config.down,
+    };
+    if let Err(e) = player.set_samples(samples) {
+        eprintln!("error: couldn't read the samples: {e}");
+        return ExitCode::from(2);
+    }
+    player.set_bpm(config.bpm);
+    player.set_measure(config.measure);
+    player.set_routing(config.routing);
+    player.set_latency(config.latency);
+    player.set_silent(config.silent);
+
+    let presets = match config.presets.or_else(Presets::default_path) {
+        Some(path) => match Presets::load(path) {
+            Ok(presets) => presets,
+            Err(e) => {
+                eprintln!("error: couldn't read the presets: {e}");
+                return ExitCode::from(2);
+            }
+        },
+        None => Presets::default(),
+    };
 
-*/
+    let state = State::new(player, presets, Default::default());
+    state.set_output(output);
+    if config.pairing {
+        state.set_pairing(Pairing::new(config.open_reads, config.trust_localhost));
+    }
+    if let Some(name) = &config.preset {
+        if let Err(e) = state.load_preset(name) {
+            eprintln!("error: couldn't load preset {name}: {e}");
+            return ExitCode::from(2);
+        }
+    }
+    if let Some(path) = &config.midi {
+        match import_midi(path) {
+            Ok(sections) => state.set_arrangement(sections),
+            Err(e) => {
+                eprintln!("error: {e}");
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    let port = match config.port {
+        Some(port) => port,
+        None => {
+            let Some(port) = free_local_port_in_range(20000..=60000) else {
+                eprintln!("error: couldn't find an open port");
+                return ExitCode::FAILURE;
+            };
+            port
+        }
+    };
+
+    let tls = if config.tls {
+        let tls = match &config.cert {
+            Some((cert, key)) => Tls::load(cert, key),
+            None => {
+                let Some(dir) = Tls::default_dir() else {
+                    eprintln!("error: couldn't find where to keep the self-signed certificate");
+                    return ExitCode::FAILURE;
+                };
+                Tls::self_signed(&dir)
+            }
+        };
+        match tls {
+            Ok(tls) => {
+                println!("Certificate fingerprint (SHA-256): {}", tls.fingerprint());
+                Some(tls)
+            }
+            Err(e) => {
+                eprintln!("error: couldn't set up TLS: {e}");
+                return ExitCode::from(2);
+            }
+        }
+    } else {
+        None
+    };
+
+    if let Some(path) = &config.openapi {
+        let spec = server::api_service(port, tls.is_some()).spec_yaml();
+        if let Err(e) = File::create(path).and_then(|mut file| file.write_all(spec.as_bytes())) {
+            eprintln!("error: couldn't write {}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let _discovery_server = config
+        .discovery
+        .then(|| DiscoveryServer::new(port.into(), tls.is_some()))
+        .flatten();
+
+    // The remote pairs by itself when opened with the code, anyone scanning it is a viewer
+    let code = state.pairing.get().map(|pairing| {
+        println!("Admin pairing code: {}", pairing.code(Role::Admin));
+        println!("Viewer pairing code: {}", pairing.code(Role::Viewer));
+        pairing.code(Role::Viewer)
+    });
+
+    // Only a self-signed certificate needs pinning
+    let fingerprint = tls
+        .as_ref()
+        .filter(|_| config.cert.is_none())
+        .map(Tls::fingerprint);
+
+    if config.qr {
+        if let Some(likely_local_qr) = server::lan_url(port, tls.is_some())
+            .map(|url| server::qr_url(&url, code, fingerprint))
+            .and_then(|url| server::terminal_qr(&url))
+        {
+            println!("{likely_local_qr}",);
+        }
+        if let Some(code) = server::terminal_qr(&server::lan_addresses(port)) {
+            println!("{code}",);
+        }
+    }
+
+    match server::serve(state, SocketAddr::new(config.bind, port), tls.as_ref()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,37 +1,848 @@
 #![feature(async_closure)]
+use backend::{ProcessOutput, ProcessSink};
+use calibration::{CalibrationError, DeviceLoopback};
+use device::{DeviceError, DeviceOutput};
 use discovery_server::DiscoveryServer;
-use local_ip_address::{list_afinet_netifas, local_ip};
+use events::{Event, Events};
+use measure::Measure;
+use midi::{MidiAction, MidiInput, MidiMapping};
+use mixer::{Layer, Levels, Routing};
 use nih_plug::prelude::*;
 use nih_plug_egui::{create_egui_editor, egui, EguiState};
+use pairing::{Pairing, PairingError, Role};
 use port_check::free_local_port_in_range;
-use qrcode::QrCode;
+use presets::{Preset, PresetError, Presets};
+use render::Section;
+use rhythm::Rhythm;
 use std::{
-    fs,
-    net::{IpAddr, Ipv4Addr},
-    sync::{Arc, Mutex},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex, OnceLock, RwLock},
     thread,
+    time::{Duration, Instant, SystemTime},
 };
-use tokio::runtime::Runtime;
+use tap::TapTempo;
+use tls::Tls;
+use tokio::{runtime::Runtime, sync::broadcast::error::RecvError};
 
-use player::RodioPlayer;
-use poem::{listener::TcpListener, middleware::AddData, web::Data, EndpointExt, Route, Server};
-use poem_openapi::{param::Path, OpenApi, OpenApiService};
+use futures_util::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use player::{Player, StartOptions, BPM_RANGE};
+use poem::web::{sse::Event as SseEvent, Data, RemoteAddr};
+use poem_openapi::{
+    param::{Path, Query},
+    payload::{Binary, EventStream, Json, Payload, PlainText},
+    registry::{MetaMediaType, MetaRequest, Registry},
+    types::ParseFromJSON,
+    ApiExtractor, ApiExtractorType, ApiResponse, ExtractParamOptions, Object, OpenApi,
+};
 use pusher::Pusher;
 
+pub mod backend;
+pub mod calibration;
+pub mod cue;
+pub mod device;
 pub mod discovery_server;
+pub mod events;
 pub mod measure;
+pub mod midi;
+pub mod mixer;
+pub mod notation;
+pub mod pairing;
 pub mod player;
+pub mod presets;
 pub mod pusher;
+pub mod remote;
+pub mod render;
 pub mod rhythm;
+pub mod server;
+pub mod smf;
+pub mod tap;
+pub mod tls;
 
 pub struct Api;
 
 pub struct State {
-    pub player: Arc<Mutex<RodioPlayer>>,
+    pub player: Arc<Mutex<Player>>,
     pub pusher: Mutex<Pusher>,
+    pub presets: Mutex<Presets>,
+    pub taps: Mutex<TapTempo>,
+    /// Unset in the plugin, which plays through the host
+    pub output: OnceLock<DeviceOutput>,
+    /// Mirrors the player's settings, the plugin saves it with the project
+    pub current: Arc<RwLock<Preset>>,
+    /// Every change made through the state is published here
+    pub events: Events,
+    /// Unset unless the clients have to pair
+    pub pairing: OnceLock<Pairing>,
+}
+
+pub type AppState = Arc<State>;
+
+impl State {
+    pub fn new(player: Player, presets: Presets, current: Arc<RwLock<Preset>>) -> AppState {
+        let player = Arc::new(Mutex::new(player));
+        let pusher = Mutex::new(Pusher::new(player.clone()));
+        Arc::new(State {
+            player,
+            pusher,
+            presets: Mutex::new(presets),
+            taps: Mutex::new(TapTempo::default()),
+            output: OnceLock::new(),
+            current,
+            events: Events::default(),
+            pairing: OnceLock::new(),
+        })
+    }
+
+    /// Plays on the output's device from now on
+    pub fn set_output(&self, output: DeviceOutput) {
+        output.attach(self.player.clone());
+        let _ = self.output.set(output);
+    }
+
+    /// Only paired clients can use the REST API from now on, and the host itself if the pairing
+    /// trusts it
+    pub fn set_pairing(&self, pairing: Pairing) {
+        let _ = self.pairing.set(pairing);
+    }
+
+    fn restart(&self, player: &mut Player) {
+        if player.playing() {
+            player.restart();
+        }
+        self.pusher.lock().unwrap().unpark();
+    }
+
+    fn remember(&self, player: &Player) {
+        let mut current = self.current.write().unwrap();
+        let name = std::mem::take(&mut current.name);
+        *current = Preset::from_player(name, player);
+    }
+
+    /// After a change of the measure, the subdivision, the swing or the humanize
+    fn measure_changed(&self, player: &Player) {
+        self.events.publish(Event::Measure {
+            measure: player.measure().to_string(),
+            beats: player.measure().beat_names(),
+        });
+    }
+
+    /// Only moves the times of the beats, e.g. in the status
+    pub fn set_latency(&self, latency: Duration) {
+        self.player.lock().unwrap().set_latency(latency);
+        self.events.publish(Event::Latency {
+            latency: latency.as_secs_f64() * 1000.0,
+        });
+    }
+
+    /// Stops the click, plays a few clicks on the output device and sets the latency to how long
+    /// they take to be heard on `input`, the default input device if `None`
+    pub fn calibrate(&self, input: Option<String>) -> Result<Duration, CalibrationError> {
+        if self.output.get().is_none() {
+            return Err(io::Error::other("the plugin plays through the host").into());
+        }
+        self.events.publish(Event::Stop);
+        let mut loopback = DeviceLoopback::new(self.player.clone(), input);
+        let latency = calibration::calibrate(&mut loopback)?;
+        self.set_latency(latency);
+        Ok(latency)
+    }
+
+    /// Plays silence instead of the click, the cues of the beats carry on
+    pub fn set_silent(&self, silent: bool) {
+        self.player.lock().unwrap().set_silent(silent);
+        self.events.publish(Event::Silent { silent });
+    }
+
+    /// Of the output, after the limiter
+    pub fn set_volume(&self, volume: f32) {
+        self.player.lock().unwrap().set_volume(volume);
+        self.events.publish(Event::Volume { volume });
+    }
+
+    /// Switches to the output device named `name`, or to the default device
+    pub fn set_device(&self, name: Option<&str>) -> Result<String, DeviceError> {
+        let output = self
+            .output
+            .get()
+            .ok_or_else(|| io::Error::other("the plugin plays through the host"))?;
+        let name = output.set_device(name)?;
+        self.events.publish(Event::Device { name: name.clone() });
+        Ok(name)
+    }
+
+    /// Keeps the beat phase instead of restarting the measure
+    pub fn set_bpm(&self, bpm: u64) {
+        let mut player = self.player.lock().unwrap();
+        player.set_bpm(bpm);
+        self.pusher.lock().unwrap().unpark();
+        self.remember(&player);
+        self.events.publish(Event::Bpm { bpm: player.bpm() });
+    }
+
+    /// Sets the tempo of the last taps, `None` until there are enough of them
+    pub fn tap(&self) -> Option<u64> {
+        let bpm = self.taps.lock().unwrap().tap(Instant::now())?;
+        self.set_bpm(bpm);
+        Some(bpm)
+    }
+
+    pub fn set_measure(&self, measure: Measure) {
+        let mut player = self.player.lock().unwrap();
+        player.set_measure(measure);
+        self.restart(&mut player);
+        self.remember(&player);
+        self.measure_changed(&player);
+    }
+
+    pub fn set_subdivision(&self, subdivision: Rhythm) {
+        let mut player = self.player.lock().unwrap();
+        player.set_subdivision(subdivision);
+        self.restart(&mut player);
+        self.remember(&player);
+        self.measure_changed(&player);
+    }
+
+    /// Keeps playing, the clicks already queued keep their routing
+    pub fn set_routing(&self, routing: Routing) {
+        let mut player = self.player.lock().unwrap();
+        player.set_routing(routing);
+        self.remember(&player);
+        self.events.publish(Event::Routing {
+            routing: routing.name(),
+        });
+    }
+
+    /// Keeps playing, the clicks already queued keep their levels
+    pub fn set_levels(&self, levels: Levels) {
+        self.update_levels(|_| levels);
+    }
+
+    /// Sets the levels `update` makes of the current ones, with the player locked throughout so
+    /// concurrent updates of different levels all apply
+    pub fn update_levels(&self, update: impl FnOnce(Levels) -> Levels) -> Levels {
+        let mut player = self.player.lock().unwrap();
+        let levels = update(player.levels());
+        player.set_levels(levels);
+        self.remember(&player);
+        self.events.publish(Event::Levels { levels });
+        levels
+    }
+
+    pub fn set_swing(&self, swing: f64) {
+        let mut player = self.player.lock().unwrap();
+        player.set_swing(swing);
+        self.restart(&mut player);
+        self.remember(&player);
+        self.measure_changed(&player);
+    }
+
+    pub fn set_humanize(&self, humanize: f64) {
+        let mut player = self.player.lock().unwrap();
+        player.set_humanize(humanize);
+        self.restart(&mut player);
+        self.remember(&player);
+        self.measure_changed(&player);
+    }
+
+    pub fn add_layer(&self, layer: Layer) {
+        let mut player = self.player.lock().unwrap();
+        let mut layers = player.layers().to_vec();
+        layers.push(layer);
+        player.set_layers(layers);
+        self.restart(&mut player);
+        self.remember(&player);
+        self.events.publish(Event::Layers {
+            count: player.layers().len(),
+        });
+    }
+
+    /// `false` if there is no layer at `index`
+    pub fn remove_layer(&self, index: usize) -> bool {
+        let mut player = self.player.lock().unwrap();
+        if index >= player.layers().len() {
+            return false;
+        }
+        let mut layers = player.layers().to_vec();
+        layers.remove(index);
+        player.set_layers(layers);
+        self.restart(&mut player);
+        self.remember(&player);
+        self.events.publish(Event::Layers {
+            count: player.layers().len(),
+        });
+        true
+    }
+
+    /// Follows the sections instead of the measure, an empty arrangement goes back to it
+    pub fn set_arrangement(&self, arrangement: Vec<Section>) {
+        let mut player = self.player.lock().unwrap();
+        player.set_arrangement(arrangement);
+        self.restart(&mut player);
+        self.events.publish(Event::Arrangement {
+            sections: player.arrangement().len(),
+        });
+    }
+
+    pub fn apply_preset(&self, preset: &Preset) -> io::Result<()> {
+        let mut player = self.player.lock().unwrap();
+        preset.apply(&mut player)?;
+        self.restart(&mut player);
+        self.taps.lock().unwrap().reset();
+        *self.current.write().unwrap() = preset.clone();
+        self.events.publish(Event::Preset {
+            name: preset.name.clone(),
+        });
+        Ok(())
+    }
+
+    pub fn load_preset(&self, name: &str) -> Result<(), PresetError> {
+        let preset = self.presets.lock().unwrap().get(name).cloned();
+        Ok(self.apply_preset(&preset.ok_or(PresetError::NotFound)?)?)
+    }
+
+    pub fn load_preset_at(&self, index: usize) -> Result<(), PresetError> {
+        let preset = self.presets.lock().unwrap().get_index(index).cloned();
+        Ok(self.apply_preset(&preset.ok_or(PresetError::NotFound)?)?)
+    }
+
+    /// Saves the player's current settings, replacing the preset with the same name
+    pub fn save_preset(&self, name: &str) -> Result<(), PresetError> {
+        let preset = Preset::from_player(name.to_string(), &self.player.lock().unwrap());
+        self.presets.lock().unwrap().insert(preset)?;
+        self.current.write().unwrap().name = name.to_string();
+        self.events.publish(Event::Presets);
+        Ok(())
+    }
+
+    pub fn rename_preset(&self, from: &str, to: &str) -> Result<(), PresetError> {
+        self.presets.lock().unwrap().rename(from, to)?;
+        let mut current = self.current.write().unwrap();
+        if current.name == from {
+            current.name = to.to_string();
+        }
+        self.events.publish(Event::Presets);
+        Ok(())
+    }
+
+    pub fn delete_preset(&self, name: &str) -> Result<(), PresetError> {
+        self.presets.lock().unwrap().remove(name)?;
+        self.events.publish(Event::Presets);
+        Ok(())
+    }
+
+    pub fn start(&self, options: StartOptions) {
+        self.player.lock().unwrap().start(options);
+        self.pusher.lock().unwrap().unpark();
+        self.events.publish(Event::Start);
+    }
+
+    pub fn play(&self) {
+        self.player.lock().unwrap().play();
+        self.pusher.lock().unwrap().unpark();
+        self.events.publish(Event::Play);
+    }
+
+    pub fn pause(&self) {
+        self.player.lock().unwrap().pause();
+        self.events.publish(Event::Pause);
+    }
+
+    /// The taps so far are forgotten, so they can't count towards the next tempo
+    pub fn stop(&self) {
+        self.player.lock().unwrap().stop();
+        self.taps.lock().unwrap().reset();
+        self.events.publish(Event::Stop);
+    }
+
+    pub fn toggle(&self) {
+        let mut player = self.player.lock().unwrap();
+        let event = if player.playing() {
+            player.stop();
+            self.taps.lock().unwrap().reset();
+            Event::Stop
+        } else {
+            player.start(StartOptions::default());
+            Event::Start
+        };
+        self.pusher.lock().unwrap().unpark();
+        self.events.publish(event);
+    }
+}
+
+#[derive(Object)]
+struct PresetSummary {
+    name: String,
+    bpm: u64,
+    /// e.g. `7/8`
+    time_signature: String,
+    subdivision: String,
+}
+
+impl From<&Preset> for PresetSummary {
+    fn from(preset: &Preset) -> Self {
+        PresetSummary {
+            name: preset.name.clone(),
+            bpm: preset.bpm,
+            time_signature: preset.measure.time_signature.to_string(),
+            subdivision: preset.measure.subdivision.name().to_string(),
+        }
+    }
+}
+
+#[derive(Object)]
+struct LayerSummary {
+    /// The measure in the text notation
+    measure: String,
+    volume: f32,
+    fit: bool,
+}
+
+impl From<&Layer> for LayerSummary {
+    fn from(layer: &Layer) -> Self {
+        LayerSummary {
+            measure: layer.measure.to_string(),
+            volume: layer.volume,
+            fit: layer.fit,
+        }
+    }
+}
+
+/// Gains in dB, the limiter comes after them and the volume after the limiter
+#[derive(Object)]
+struct LevelsSummary {
+    master: f32,
+    up: f32,
+    mid: f32,
+    /// Also of the subdivisions
+    down: f32,
+    limiter: bool,
+}
+
+impl From<Levels> for LevelsSummary {
+    fn from(levels: Levels) -> Self {
+        LevelsSummary {
+            master: levels.master,
+            up: levels.up,
+            mid: levels.mid,
+            down: levels.down,
+            limiter: levels.limiter,
+        }
+    }
+}
+
+/// Gains in dB, the fields left out keep their level
+#[derive(Object)]
+struct LevelsRequest {
+    #[oai(validator(minimum(value = "-60"), maximum(value = "24")))]
+    master: Option<f32>,
+    #[oai(validator(minimum(value = "-60"), maximum(value = "24")))]
+    up: Option<f32>,
+    #[oai(validator(minimum(value = "-60"), maximum(value = "24")))]
+    mid: Option<f32>,
+    #[oai(validator(minimum(value = "-60"), maximum(value = "24")))]
+    down: Option<f32>,
+    limiter: Option<bool>,
+}
+
+impl LevelsRequest {
+    fn apply(&self, levels: Levels) -> Levels {
+        Levels {
+            master: self.master.unwrap_or(levels.master),
+            up: self.up.unwrap_or(levels.up),
+            mid: self.mid.unwrap_or(levels.mid),
+            down: self.down.unwrap_or(levels.down),
+            limiter: self.limiter.unwrap_or(levels.limiter),
+        }
+    }
+}
+
+#[derive(Object)]
+struct BeatSummary {
+    /// Index in the measure
+    beat: usize,
+    /// `up`, `mid` or `down`, none for the count-in
+    sound_type: Option<String>,
+    /// When it's heard, in milliseconds since the Unix epoch, the output latency included
+    at: u64,
+}
+
+#[derive(Object)]
+struct StatusSummary {
+    playing: bool,
+    bpm: u64,
+    /// The measure in the text notation
+    measure: String,
+    /// `up`, `mid`, `down` or `rest` for every beat of the measure
+    beats: Vec<String>,
+    routing: String,
+    /// Of the output, from 0.0 to 1.0
+    volume: f32,
+    levels: LevelsSummary,
+    /// Output latency in milliseconds
+    latency: f64,
+    next_beat: Option<BeatSummary>,
+    /// Only the cues of the beats are given, the click is muted
+    silent: bool,
+    /// Of the client asking, `admin` or `viewer`. Viewers can't change anything.
+    role: String,
+}
+
+/// A beat with how to flash a screen and vibrate a phone on it
+#[derive(Object)]
+struct CueSummary {
+    /// Index in the measure
+    beat: usize,
+    /// `up`, `mid` or `down`, none for the count-in
+    sound_type: Option<String>,
+    /// When it's heard, in milliseconds since the Unix epoch, the output latency included
+    at: u64,
+    /// Milliseconds from the answer to `at`, for clients whose clock differs from the server's
+    in_ms: u64,
+    /// CSS color of the flash
+    color: String,
+    /// Of the flash, from 0.0 to 1.0
+    intensity: f32,
+    /// Length of the flash in milliseconds
+    flash: u64,
+    /// Alternating vibration and pause lengths in milliseconds, for `navigator.vibrate()`
+    vibration: Vec<u64>,
+}
+
+/// Comments sent on `GET /events` when nothing happens, so proxies keep the connection open
+const EVENTS_KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// How long `GET /cue` waits for a beat
+const CUE_TIMEOUT: Duration = Duration::from_secs(30);
+/// The time of a beat moves by a few milliseconds between calls, a beat this close to `after`
+/// was already given
+const CUE_MARGIN_MILLIS: u64 = 20;
+
+/// The endpoints changing anything answer viewers with it
+fn admin(role: &Role) -> Result<(), Forbidden> {
+    match role {
+        Role::Admin => Ok(()),
+        Role::Viewer => Err(Forbidden::Forbidden(PlainText(String::from(
+            "viewers can't change anything, pair with the admin code",
+        )))),
+    }
+}
+
+/// Why a measure can't be played at `bpm`, `None` unless it lasts longer than
+/// `measure::MAX_DURATION`
+fn too_long(measure: &Measure, bpm: u64) -> Option<PlainText<String>> {
+    measure.too_long(bpm).then(|| {
+        PlainText(format!(
+            "a measure can't last longer than {} minutes, this one needs at least {} BPM",
+            measure::MAX_DURATION / 60_000_000_000,
+            measure.slowest_bpm()
+        ))
+    })
+}
+
+/// Milliseconds since the Unix epoch
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+#[derive(Object)]
+struct PairRequest {
+    /// Shown by the host
+    code: String,
+    /// To tell the clients apart, e.g. `Drummer's phone`
+    #[oai(default)]
+    name: String,
+}
+
+#[derive(Object)]
+struct PairSummary {
+    /// To revoke the client
+    id: u64,
+    /// Presented as a bearer token, or as the `token` query parameter
+    token: String,
+    /// `admin` or `viewer`, depending on the code
+    role: String,
+}
+
+#[derive(Object)]
+struct ClientSummary {
+    id: u64,
+    name: String,
+    role: String,
+    /// When it paired, in milliseconds since the Unix epoch
+    paired: u64,
+}
+
+#[derive(Object)]
+struct SectionSummary {
+    bpm: u64,
+    ramp_to: Option<u64>,
+    /// The measure in the text notation
+    measure: String,
+    bars: u64,
+}
+
+impl From<&Section> for SectionSummary {
+    fn from(section: &Section) -> Self {
+        SectionSummary {
+            bpm: section.bpm,
+            ramp_to: section.ramp_to,
+            measure: section.measure.to_string(),
+            bars: section.bars,
+        }
+    }
+}
+
+#[derive(Object)]
+struct DeviceSummary {
+    name: String,
+    /// The system's default output device
+    default: bool,
+    /// The click is played on this device
+    current: bool,
+}
+
+#[derive(Object)]
+struct ImportSummary {
+    sections: Vec<SectionSummary>,
+    /// What couldn't be imported exactly, e.g. tempo changes in the middle of a measure
+    warnings: Vec<String>,
+}
+
+/// How to start, every field can be left out
+#[derive(Object)]
+struct StartRequest {
+    /// Measures of count-in, up to 16
+    #[oai(default, validator(maximum(value = "16")))]
+    count_in: usize,
+    /// Stops after this many measures, not counting the count-in
+    #[oai(validator(minimum(value = "1")))]
+    stop_after_bars: Option<u64>,
+    /// Stops after this many minutes
+    #[oai(validator(minimum(value = "0", exclusive)))]
+    stop_after_minutes: Option<f64>,
+    /// Plays the end sound when stopping on its own
+    #[oai(default)]
+    end_sound: bool,
+}
+
+impl From<StartRequest> for StartOptions {
+    fn from(request: StartRequest) -> Self {
+        StartOptions {
+            count_in: request.count_in,
+            stop_after_bars: request.stop_after_bars,
+            stop_after_minutes: request.stop_after_minutes,
+            end_sound: request.end_sound,
+        }
+    }
+}
+
+/// A JSON body that can be left out entirely, `Json<Option<T>>` still needs a content type
+struct OptionalJson<T>(Option<T>);
+
+impl<'a, T: ParseFromJSON> ApiExtractor<'a> for OptionalJson<T> {
+    const TYPES: &'static [ApiExtractorType] = &[ApiExtractorType::RequestObject];
+
+    type ParamType = ();
+    type ParamRawType = ();
+
+    fn register(registry: &mut Registry) {
+        T::register(registry);
+    }
+
+    fn request_meta() -> Option<MetaRequest> {
+        Some(MetaRequest {
+            description: None,
+            content: vec![MetaMediaType {
+                content_type: <Json<T> as Payload>::CONTENT_TYPE,
+                schema: T::schema_ref(),
+            }],
+            required: false,
+        })
+    }
+
+    async fn from_request(
+        request: &'a poem::Request,
+        body: &mut poem::RequestBody,
+        param_opts: ExtractParamOptions<Self::ParamType>,
+    ) -> poem::Result<Self> {
+        if request.content_type().is_none() {
+            return Ok(OptionalJson(None));
+        }
+        let Json(value) = Json::<Option<T>>::from_request(request, body, param_opts).await?;
+        Ok(OptionalJson(value))
+    }
+}
+
+fn default_bars() -> u64 {
+    4
+}
+
+fn default_sample_rate() -> u32 {
+    48_000
+}
+
+/// A click track, played with the current samples
+#[derive(Object)]
+struct RenderRequest {
+    /// Played one after the other, the current settings if left out
+    sections: Option<Vec<SectionRequest>>,
+    /// Measures to render with the current settings, ignored with `sections`
+    #[oai(default = "default_bars")]
+    bars: u64,
+    #[oai(
+        default = "default_sample_rate",
+        validator(minimum(value = "8000"), maximum(value = "192000"))
+    )]
+    sample_rate: u32,
+}
+
+#[derive(Object)]
+struct SectionRequest {
+    #[oai(validator(minimum(value = "1"), maximum(value = "1000")))]
+    bpm: u64,
+    /// Tempo of the last measure, for a ramp
+    #[oai(validator(minimum(value = "1"), maximum(value = "1000")))]
+    ramp_to: Option<u64>,
+    /// The measure in the text notation
+    measure: String,
+    /// Each has its own tempo in a ramp, hence the limit
+    #[oai(validator(maximum(value = "100000")))]
+    bars: u64,
+}
+
+#[derive(ApiResponse)]
+enum RenderResponse {
+    /// A 16 bit stereo WAV file
+    #[oai(status = 200, content_type = "audio/wav")]
+    Ok(
+        Binary<Vec<u8>>,
+        #[oai(header = "Content-Disposition")] String,
+    ),
+    #[oai(status = 400)]
+    BadRequest(PlainText<String>),
+    /// The samples couldn't be read
+    #[oai(status = 500)]
+    Io(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+enum DeviceResponse {
+    /// The name of the device being played
+    #[oai(status = 200)]
+    Ok(PlainText<String>),
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+    /// The plugin plays through the host
+    #[oai(status = 409)]
+    Conflict(PlainText<String>),
+    /// The device couldn't be opened
+    #[oai(status = 500)]
+    Io(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+enum PairResponse {
+    #[oai(status = 200)]
+    Ok(Json<PairSummary>),
+    #[oai(status = 403)]
+    WrongCode(PlainText<String>),
+    /// Pairing is off, no token is needed
+    #[oai(status = 409)]
+    Conflict(PlainText<String>),
+    /// Too many wrong codes were tried from this address, it's refused for a minute
+    #[oai(status = 429)]
+    TooManyRequests(PlainText<String>),
+}
+
+/// Merged into the responses of the endpoints viewers can't use
+#[derive(ApiResponse)]
+enum Forbidden {
+    /// Only admins can change anything
+    #[oai(status = 403)]
+    Forbidden(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+enum ClientResponse {
+    #[oai(status = 200)]
+    Ok,
+    /// There is no client with this id
+    #[oai(status = 404)]
+    NotFound,
+}
+
+#[derive(ApiResponse)]
+enum CueResponse {
+    #[oai(status = 200)]
+    Ok(Json<CueSummary>),
+    /// No beat within 30 seconds, e.g. when stopped
+    #[oai(status = 204)]
+    Timeout,
+}
+
+#[derive(ApiResponse)]
+enum CalibrationResponse {
+    /// The latency in milliseconds
+    #[oai(status = 200)]
+    Ok(Json<f64>),
+    /// The plugin plays through the host
+    #[oai(status = 409)]
+    Conflict(PlainText<String>),
+    /// The clicks weren't heard, e.g. the microphone is too far from the speaker
+    #[oai(status = 422)]
+    NotHeard(PlainText<String>),
+    /// The input device couldn't be recorded
+    #[oai(status = 500)]
+    Io(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+enum ImportResponse {
+    #[oai(status = 200)]
+    Ok(Json<ImportSummary>),
+    /// Not a MIDI file, or one whose timing isn't supported
+    #[oai(status = 400)]
+    BadRequest(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+enum PresetResponse {
+    #[oai(status = 200)]
+    Ok,
+    /// There is no preset with this name
+    #[oai(status = 404)]
+    NotFound,
+    /// A preset with the new name already exists
+    #[oai(status = 409)]
+    Conflict,
+    /// The presets file or the preset's samples couldn't be accessed
+    #[oai(status = 500)]
+    Io(PlainText<String>),
+}
+
+impl From<Result<(), PresetError>> for PresetResponse {
+    fn from(result: Result<(), PresetError>) -> Self {
+        match result {
+            Ok(()) => PresetResponse::Ok,
+            Err(PresetError::NotFound) => PresetResponse::NotFound,
+            Err(PresetError::AlreadyExists) => PresetResponse::Conflict,
+            Err(PresetError::Io(e)) => PresetResponse::Io(PlainText(e.to_string())),
+        }
+    }
 }
 
-type AppState = Arc<State>;
+#[derive(ApiResponse)]
+enum ValueResponse {
+    #[oai(status = 200)]
+    Ok,
+    #[oai(status = 400)]
+    BadRequest(PlainText<String>),
+}
 
 #[OpenApi]
 impl Api {
@@ -41,74 +852,827 @@ impl Api {
         println!("->> /health - ");
     }
 
+    #[oai(path = "/status", method = "get")]
+    async fn status(&self, role: Data<&Role>, state: Data<&AppState>) -> Json<StatusSummary> {
+        #[cfg(debug_assertions)]
+        println!("->> /status - ");
+
+        let player = state.player.lock().unwrap();
+        Json(StatusSummary {
+            playing: player.playing(),
+            bpm: player.bpm(),
+            measure: player.measure().to_string(),
+            beats: player
+                .measure()
+                .beat_names()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            routing: player.routing().name().to_string(),
+            volume: player.volume(),
+            levels: player.levels().into(),
+            latency: player.latency().as_secs_f64() * 1000.0,
+            next_beat: player.next_beat().map(|(tick, at)| BeatSummary {
+                beat: tick.beat,
+                sound_type: tick
+                    .sound_type
+                    .map(|sound_type| sound_type.name().to_string()),
+                at: unix_millis(at),
+            }),
+            silent: player.silent(),
+            role: role.name().to_string(),
+        })
+    }
+
+    /// Waits for the first beat heard after `after`, in milliseconds since the Unix epoch, and
+    /// returns it ahead of time. It's the next beat to be heard if left out. Passing the `at` of
+    /// the last cue gives every beat once.
+    #[oai(path = "/cue", method = "get")]
+    async fn cue(&self, after: Query<Option<u64>>, state: Data<&AppState>) -> CueResponse {
+        #[cfg(debug_assertions)]
+        println!("->> /cue - after:{:?} ", *after);
+
+        let after = after.map_or(0, |after| after + CUE_MARGIN_MILLIS);
+        let deadline = tokio::time::Instant::now() + CUE_TIMEOUT;
+        let beats_queued = state.player.lock().unwrap().beats_queued();
+        loop {
+            // Listening before looking, so beats queued in between aren't missed
+            let queued = beats_queued.notified();
+            tokio::pin!(queued);
+            queued.as_mut().enable();
+            let beat = state
+                .player
+                .lock()
+                .unwrap()
+                .beats()
+                .find(|(_, at)| unix_millis(*at) > after);
+            if let Some((tick, at)) = beat {
+                let cue = cue::cue(tick.sound_type);
+                let in_ms = at.duration_since(SystemTime::now()).unwrap_or_default();
+                return CueResponse::Ok(Json(CueSummary {
+                    beat: tick.beat,
+                    sound_type: tick
+                        .sound_type
+                        .map(|sound_type| sound_type.name().to_string()),
+                    at: unix_millis(at),
+                    in_ms: in_ms.as_millis() as u64,
+                    color: cue.color.to_string(),
+                    intensity: cue.intensity,
+                    flash: cue.flash,
+                    vibration: cue.vibration.to_vec(),
+                }));
+            }
+            if tokio::time::timeout_at(deadline, queued).await.is_err() {
+                return CueResponse::Timeout;
+            }
+        }
+    }
+
+    /// Mutes the click from the next measure on, the beats can still be followed with
+    /// `GET /cue`
+    #[oai(path = "/set_silent/:silent", method = "post")]
+    async fn set_silent(
+        &self,
+        silent: Path<bool>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<(), Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /set_silent - silent:{} ", *silent);
+
+        admin(&role)?;
+        state.set_silent(*silent);
+        Ok(())
+    }
+
+    /// Server-Sent Events of every change, e.g. `{"type":"bpm","bpm":140}` named `bpm`. The
+    /// `type` is one of `start`, `play`, `pause`, `stop`, `bpm`, `measure`, `layers`, `routing`,
+    /// `levels`, `volume`, `latency`, `silent`, `device`, `arrangement`, `preset` (loaded) and
+    /// `presets` (saved, renamed or deleted). A client too slow to keep up misses events.
+    #[oai(path = "/events", method = "get")]
+    async fn events(
+        &self,
+        state: Data<&AppState>,
+    ) -> EventStream<BoxStream<'static, serde_json::Value>> {
+        #[cfg(debug_assertions)]
+        println!("->> /events - ");
+
+        let events = stream::unfold(state.events.subscribe(), |mut events| async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => return Some((serde_json::to_value(event).unwrap(), events)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        EventStream::new(events.boxed())
+            .keep_alive(EVENTS_KEEP_ALIVE)
+            .to_event(|event| {
+                let name = event["type"].as_str().unwrap_or_default().to_string();
+                SseEvent::message(event.to_string()).event_type(name)
+            })
+    }
+
+    /// Starts from the downbeat, the body is optional
     #[oai(path = "/start", method = "post")]
-    async fn start(&self, state: Data<&AppState>) {
+    async fn start(
+        &self,
+        options: OptionalJson<StartRequest>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<(), Forbidden> {
         #[cfg(debug_assertions)]
         println!("->> /start - ");
 
-        let mut player = state.player.lock().unwrap();
-        player.start();
+        admin(&role)?;
+        state.start(options.0.map(StartOptions::from).unwrap_or_default());
+        Ok(())
     }
 
     #[oai(path = "/play", method = "post")]
-    async fn play(&self, state: Data<&AppState>) {
+    async fn play(&self, role: Data<&Role>, state: Data<&AppState>) -> Result<(), Forbidden> {
         #[cfg(debug_assertions)]
         println!("->> /play - ");
 
-        let mut player = state.player.lock().unwrap();
-        player.play();
+        admin(&role)?;
+        state.play();
+        Ok(())
     }
 
     #[oai(path = "/pause", method = "post")]
-    async fn pause(&self, state: Data<&AppState>) {
+    async fn pause(&self, role: Data<&Role>, state: Data<&AppState>) -> Result<(), Forbidden> {
         #[cfg(debug_assertions)]
         println!("->> /pause - ");
 
-        let mut player = state.player.lock().unwrap();
-        player.pause();
+        admin(&role)?;
+        state.pause();
+        Ok(())
     }
 
     #[oai(path = "/stop", method = "post")]
-    async fn stop(&self, state: Data<&AppState>) {
+    async fn stop(&self, role: Data<&Role>, state: Data<&AppState>) -> Result<(), Forbidden> {
         #[cfg(debug_assertions)]
         println!("->> /stop - ");
 
-        let mut player = state.player.lock().unwrap();
-        player.stop();
+        admin(&role)?;
+        state.stop();
+        Ok(())
     }
 
     #[oai(path = "/push", method = "post")]
-    async fn push(&self, state: Data<&AppState>) {
+    async fn push(&self, role: Data<&Role>, state: Data<&AppState>) -> Result<(), Forbidden> {
         #[cfg(debug_assertions)]
         println!("->> /push - ");
 
+        admin(&role)?;
         let mut player = state.player.lock().unwrap();
         player.push();
+        Ok(())
     }
 
+    /// Refused if the measure would last longer than four minutes at this tempo
     #[oai(path = "/set_bpm/:bpm", method = "post")]
-    async fn set_bpm(&self, bpm: Path<u64>, state: Data<&AppState>) {
+    async fn set_bpm(
+        &self,
+        #[oai(validator(minimum(value = "1"), maximum(value = "1000")))] bpm: Path<u64>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<ValueResponse, Forbidden> {
         #[cfg(debug_assertions)]
         println!("->> /set_bpm - bpm:{} ", *bpm);
 
-        let mut player = state.player.lock().unwrap();
-        player.set_bpm(*bpm);
-        if player.playing() {
-            player.stop();
-            player.start();
+        admin(&role)?;
+        let error = too_long(state.player.lock().unwrap().measure(), *bpm);
+        if let Some(e) = error {
+            return Ok(ValueResponse::BadRequest(e));
+        }
+        state.set_bpm(*bpm);
+        Ok(ValueResponse::Ok)
+    }
+
+    /// Sets the tempo from the last taps and returns it, `null` on the first tap. Taps more than
+    /// two seconds apart start over.
+    #[oai(path = "/tap", method = "post")]
+    async fn tap(
+        &self,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<Json<Option<u64>>, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /tap - ");
+
+        admin(&role)?;
+        Ok(Json(state.tap()))
+    }
+
+    /// The current measure in the text notation, e.g. `4/4: A m m m | sub=eights`
+    #[oai(path = "/measure", method = "get")]
+    async fn measure(&self, state: Data<&AppState>) -> PlainText<String> {
+        #[cfg(debug_assertions)]
+        println!("->> /measure - ");
+
+        PlainText(state.player.lock().unwrap().measure().to_string())
+    }
+
+    /// Replaces the measure with one in the text notation, refused if it would last longer than
+    /// four minutes at the current tempo
+    #[oai(path = "/measure", method = "post")]
+    async fn set_measure(
+        &self,
+        notation: PlainText<String>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<ValueResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /set_measure - notation:{} ", notation.0);
+
+        admin(&role)?;
+        Ok(match notation.0.parse::<Measure>() {
+            Ok(measure) => {
+                let bpm = state.player.lock().unwrap().bpm();
+                match too_long(&measure, bpm) {
+                    Some(e) => ValueResponse::BadRequest(e),
+                    None => {
+                        state.set_measure(measure);
+                        ValueResponse::Ok
+                    }
+                }
+            }
+            Err(e) => ValueResponse::BadRequest(PlainText(e.to_string())),
+        })
+    }
+
+    /// Sets the subdivision of every beat, e.g. `eights` or `triplet_eights`
+    #[oai(path = "/set_rhythm/:rhythm", method = "post")]
+    async fn set_rhythm(
+        &self,
+        rhythm: Path<String>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<ValueResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /set_rhythm - rhythm:{} ", *rhythm);
+
+        admin(&role)?;
+        Ok(match Rhythm::from_str(&rhythm) {
+            Ok(r) => {
+                state.set_subdivision(r);
+                ValueResponse::Ok
+            }
+            Err(()) => ValueResponse::BadRequest(PlainText(format!("unknown rhythm {}", *rhythm))),
+        })
+    }
+
+    /// `stereo` plays every beat at its pan position, `split` the accents on the left and the
+    /// other beats and subdivisions on the right, `left` and `right` everything on one channel
+    #[oai(path = "/set_routing/:routing", method = "post")]
+    async fn set_routing(
+        &self,
+        routing: Path<String>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<ValueResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /set_routing - routing:{} ", *routing);
+
+        admin(&role)?;
+        Ok(match Routing::from_str(&routing) {
+            Ok(r) => {
+                state.set_routing(r);
+                ValueResponse::Ok
+            }
+            Err(()) => {
+                ValueResponse::BadRequest(PlainText(format!("unknown routing {}", *routing)))
+            }
+        })
+    }
+
+    #[oai(path = "/routing", method = "get")]
+    async fn routing(&self, state: Data<&AppState>) -> PlainText<String> {
+        #[cfg(debug_assertions)]
+        println!("->> /routing - ");
+
+        PlainText(state.player.lock().unwrap().routing().name().to_string())
+    }
+
+    /// Changes the levels given in the body and returns every level
+    #[oai(path = "/levels", method = "post")]
+    async fn set_levels(
+        &self,
+        levels: Json<LevelsRequest>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<Json<LevelsSummary>, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /levels - ");
+
+        admin(&role)?;
+        let levels = state.update_levels(|current| levels.apply(current));
+        Ok(Json(levels.into()))
+    }
+
+    #[oai(path = "/levels", method = "get")]
+    async fn levels(&self, state: Data<&AppState>) -> Json<LevelsSummary> {
+        #[cfg(debug_assertions)]
+        println!("->> /levels - ");
+
+        Json(state.player.lock().unwrap().levels().into())
+    }
+
+    /// Swings eighth and sixteenth subdivisions, 50 is straight, 66 triplet and 75 dotted
+    #[oai(path = "/set_swing/:percent", method = "post")]
+    async fn set_swing(
+        &self,
+        percent: Path<f64>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<ValueResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /set_swing - percent:{} ", *percent);
+
+        admin(&role)?;
+        let swing = *percent / 100.0;
+        if !measure::SWING_RANGE.contains(&swing) {
+            return Ok(ValueResponse::BadRequest(PlainText(String::from(
+                "swing must be between 50 and 75 percent",
+            ))));
+        }
+        state.set_swing(swing);
+        Ok(ValueResponse::Ok)
+    }
+
+    /// Moves every click randomly by up to this many milliseconds, 0 turns it off
+    #[oai(path = "/set_humanize/:ms", method = "post")]
+    async fn set_humanize(
+        &self,
+        ms: Path<f64>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<ValueResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /set_humanize - ms:{} ", *ms);
+
+        admin(&role)?;
+        if !measure::HUMANIZE_RANGE.contains(&*ms) {
+            return Ok(ValueResponse::BadRequest(PlainText(format!(
+                "humanize must be in {:?} ms",
+                measure::HUMANIZE_RANGE
+            ))));
+        }
+        state.set_humanize(*ms);
+        Ok(ValueResponse::Ok)
+    }
+
+    /// Measures played along with the main one, in the order they were added
+    #[oai(path = "/layers", method = "get")]
+    async fn layers(&self, state: Data<&AppState>) -> Json<Vec<LayerSummary>> {
+        #[cfg(debug_assertions)]
+        println!("->> /layers - ");
+
+        let player = state.player.lock().unwrap();
+        Json(player.layers().iter().map(LayerSummary::from).collect())
+    }
+
+    /// Adds a layer from a measure in the text notation. With `fit` the measure is squeezed into
+    /// the main measure, e.g. `3/4: A m m` plays 3 against 4, otherwise it keeps the tempo and
+    /// realigns with the main measure every few measures. The volume is a gain from 0 to 2, 1 by
+    /// default.
+    #[oai(path = "/layers", method = "post")]
+    async fn add_layer(
+        &self,
+        notation: PlainText<String>,
+        #[oai(validator(minimum(value = "0"), maximum(value = "2")))] volume: Query<Option<f32>>,
+        fit: Query<Option<bool>>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<ValueResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /add_layer - notation:{} ", notation.0);
+
+        admin(&role)?;
+        Ok(match notation.0.parse::<Measure>() {
+            Ok(measure) => {
+                state.add_layer(Layer {
+                    measure,
+                    volume: volume.unwrap_or(1.0),
+                    fit: fit.unwrap_or(false),
+                });
+                ValueResponse::Ok
+            }
+            Err(e) => ValueResponse::BadRequest(PlainText(e.to_string())),
+        })
+    }
+
+    #[oai(path = "/layers/:index", method = "delete")]
+    async fn remove_layer(
+        &self,
+        index: Path<usize>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<ValueResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /remove_layer - index:{} ", *index);
+
+        admin(&role)?;
+        Ok(if state.remove_layer(*index) {
+            ValueResponse::Ok
+        } else {
+            ValueResponse::BadRequest(PlainText(format!("no layer at {}", *index)))
+        })
+    }
+
+    /// Renders a click track to a WAV file, without playing it
+    #[oai(path = "/render", method = "post")]
+    async fn render(
+        &self,
+        request: Json<RenderRequest>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<RenderResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /render - sample_rate:{} ", request.sample_rate);
+
+        admin(&role)?;
+        let (sections, samples) = {
+            let player = state.player.lock().unwrap();
+            let sections = match &request.sections {
+                Some(sections) => {
+                    let mut parsed = Vec::with_capacity(sections.len());
+                    for section in sections {
+                        let slowest = section.bpm.min(section.ramp_to.unwrap_or(section.bpm));
+                        match section.measure.parse::<Measure>() {
+                            Ok(measure) => {
+                                if let Some(e) = too_long(&measure, slowest) {
+                                    return Ok(RenderResponse::BadRequest(e));
+                                }
+                                parsed.push(Section {
+                                    ramp_to: section.ramp_to,
+                                    ..Section::new(section.bpm, measure, section.bars)
+                                })
+                            }
+                            Err(e) => {
+                                return Ok(RenderResponse::BadRequest(PlainText(e.to_string())))
+                            }
+                        }
+                    }
+                    parsed
+                }
+                None => vec![Section {
+                    bpm: player.bpm(),
+                    ramp_to: None,
+                    measure: player.measure().clone(),
+                    layers: player.layers().to_vec(),
+                    bars: request.bars,
+                }],
+            };
+            (sections, player.samples().clone())
+        };
+        let sample_rate = request.sample_rate;
+        if render::samples(&sections, sample_rate) > render::MAX_SAMPLES {
+            let minutes = render::MAX_SAMPLES / mixer::CHANNELS as u64 / sample_rate as u64 / 60;
+            return Ok(RenderResponse::BadRequest(PlainText(format!(
+                "click tracks can't be longer than {minutes} minutes at {sample_rate} Hz"
+            ))));
+        }
+
+        let wav = tokio::task::spawn_blocking(move || {
+            let mut wav = io::Cursor::new(Vec::new());
+            render::write_wav(&mut wav, &sections, sample_rate, &samples).map(|()| wav.into_inner())
+        })
+        .await
+        .unwrap();
+        Ok(match wav {
+            Ok(wav) => RenderResponse::Ok(
+                Binary(wav),
+                String::from("attachment; filename=\"click.wav\""),
+            ),
+            Err(e) => RenderResponse::Io(PlainText(e.to_string())),
+        })
+    }
+
+    /// Output devices, the plugin plays through the host instead
+    #[oai(path = "/devices", method = "get")]
+    async fn devices(&self, state: Data<&AppState>) -> Json<Vec<DeviceSummary>> {
+        #[cfg(debug_assertions)]
+        println!("->> /devices - ");
+
+        let Some(output) = state.output.get() else {
+            return Json(Vec::new());
+        };
+        let current = output.device();
+        let default = device::default_device();
+        Json(
+            device::output_devices()
+                .into_iter()
+                .map(|name| DeviceSummary {
+                    default: default.as_ref() == Some(&name),
+                    current: name == current,
+                    name,
+                })
+                .collect(),
+        )
+    }
+
+    /// Plays on the device with this name, or on the default device if the name is empty. The
+    /// player carries on from the downbeat, and goes back to the default device if this one is
+    /// unplugged.
+    #[oai(path = "/device", method = "post")]
+    async fn set_device(
+        &self,
+        name: PlainText<String>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<DeviceResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /set_device - name:{} ", name.0);
+
+        admin(&role)?;
+        if state.output.get().is_none() {
+            return Ok(DeviceResponse::Conflict(PlainText(String::from(
+                "the plugin plays through the host",
+            ))));
+        }
+        let state = state.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let name = Some(name.0.trim()).filter(|name| !name.is_empty());
+            state.set_device(name)
+        })
+        .await
+        .unwrap();
+        Ok(match result {
+            Ok(name) => DeviceResponse::Ok(PlainText(name)),
+            Err(e @ DeviceError::NotFound) => DeviceResponse::NotFound(PlainText(e.to_string())),
+            Err(e) => DeviceResponse::Io(PlainText(e.to_string())),
+        })
+    }
+
+    /// Time between playing and hearing the click, e.g. of a Bluetooth speaker. It moves the
+    /// beats' times in the status, the click itself isn't delayed.
+    #[oai(path = "/set_latency/:ms", method = "post")]
+    async fn set_latency(
+        &self,
+        ms: Path<f64>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<ValueResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /set_latency - ms:{} ", *ms);
+
+        admin(&role)?;
+        if !(0.0..=calibration::MAX_LATENCY.as_millis() as f64).contains(&*ms) {
+            return Ok(ValueResponse::BadRequest(PlainText(format!(
+                "latency {} is not in 0..={} ms",
+                *ms,
+                calibration::MAX_LATENCY.as_millis()
+            ))));
+        }
+        state.set_latency(Duration::from_secs_f64(*ms / 1000.0));
+        Ok(ValueResponse::Ok)
+    }
+
+    /// Stops the click and plays a few clicks to measure the latency with the input device of
+    /// this name, the default input device if left out. Put the microphone next to the speaker,
+    /// or connect the output to the input. The latency is set and returned in milliseconds.
+    #[oai(path = "/calibrate", method = "post")]
+    async fn calibrate(
+        &self,
+        input: Query<Option<String>>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<CalibrationResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /calibrate - input:{:?} ", *input);
+
+        admin(&role)?;
+        if state.output.get().is_none() {
+            return Ok(CalibrationResponse::Conflict(PlainText(String::from(
+                "the plugin plays through the host",
+            ))));
+        }
+        let state = state.clone();
+        let result = tokio::task::spawn_blocking(move || state.calibrate(input.0))
+            .await
+            .unwrap();
+        Ok(match result {
+            Ok(latency) => CalibrationResponse::Ok(Json(latency.as_secs_f64() * 1000.0)),
+            Err(e @ CalibrationError::NotHeard) => {
+                CalibrationResponse::NotHeard(PlainText(e.to_string()))
+            }
+            Err(e) => CalibrationResponse::Io(PlainText(e.to_string())),
+        })
+    }
+
+    /// Trades a pairing code shown by the host for a token, which the other endpoints need
+    /// when pairing is on. It's sent as `Authorization: Bearer <token>`, or as the `token` query
+    /// parameter when headers can't be set. Requests from the host itself don't need one when
+    /// it's trusted, see `--trust-localhost`. The admin code gives an admin, who can change
+    /// everything, the viewer code a viewer, who can only follow the status, the events and the
+    /// cues.
+    #[oai(path = "/pair", method = "post")]
+    async fn pair(
+        &self,
+        request: Json<PairRequest>,
+        remote: &RemoteAddr,
+        state: Data<&AppState>,
+    ) -> PairResponse {
+        #[cfg(debug_assertions)]
+        println!("->> /pair - name:{} ", request.name);
+
+        let Some(pairing) = state.pairing.get() else {
+            return PairResponse::Conflict(PlainText(String::from("pairing is off")));
+        };
+        // Clients without an IP address, e.g. in the tests, share their failures
+        let from = remote
+            .as_socket_addr()
+            .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |addr| addr.ip());
+        match pairing.pair(&request.code, &request.name, from) {
+            Ok((id, token, role)) => PairResponse::Ok(Json(PairSummary {
+                id,
+                token,
+                role: role.name().to_string(),
+            })),
+            Err(e @ PairingError::WrongCode) => PairResponse::WrongCode(PlainText(e.to_string())),
+            Err(e @ PairingError::LockedOut) => {
+                PairResponse::TooManyRequests(PlainText(e.to_string()))
+            }
         }
-        state.pusher.lock().unwrap().unpark();
-        // player.play();
     }
 
-    // #[oai(path = "/set_rhythm/:rhythm", method = "post")]
-    // async fn set_rhythm(&self, rhythm: Path<String>, state: Data<&Player>) {
-    //     Rhythm::from_str(&rhythm).and_then(|r| {
-    //         let mut player = state.0.lock().unwrap();
-    //         player.set_rhythm(r);
-    //         Ok(())
-    //     });
-    // }
+    /// The paired clients, empty when pairing is off
+    #[oai(path = "/clients", method = "get")]
+    async fn clients(
+        &self,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<Json<Vec<ClientSummary>>, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /clients - ");
+
+        admin(&role)?;
+        let clients = state
+            .pairing
+            .get()
+            .map(Pairing::clients)
+            .unwrap_or_default();
+        Ok(Json(
+            clients
+                .into_iter()
+                .map(|client| ClientSummary {
+                    id: client.id,
+                    name: client.name,
+                    role: client.role.name().to_string(),
+                    paired: unix_millis(client.paired),
+                })
+                .collect(),
+        ))
+    }
+
+    /// Revokes the token of a client, it has to pair again
+    #[oai(path = "/clients/:id", method = "delete")]
+    async fn revoke_client(
+        &self,
+        id: Path<u64>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<ClientResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /revoke_client - id:{} ", *id);
+
+        admin(&role)?;
+        Ok(match state.pairing.get() {
+            Some(pairing) if pairing.revoke(*id) => ClientResponse::Ok,
+            _ => ClientResponse::NotFound,
+        })
+    }
+
+    /// Sections played instead of the measure, empty unless an arrangement is loaded
+    #[oai(path = "/arrangement", method = "get")]
+    async fn arrangement(&self, state: Data<&AppState>) -> Json<Vec<SectionSummary>> {
+        #[cfg(debug_assertions)]
+        println!("->> /arrangement - ");
+
+        let player = state.player.lock().unwrap();
+        Json(
+            player
+                .arrangement()
+                .iter()
+                .map(SectionSummary::from)
+                .collect(),
+        )
+    }
+
+    /// Follows the tempo and time signature changes of a Standard MIDI File, the notes are left
+    /// out. Every measure gets its average tempo, the warnings list what couldn't be imported
+    /// exactly.
+    #[oai(path = "/arrangement/midi", method = "post")]
+    async fn import_midi(
+        &self,
+        file: Binary<Vec<u8>>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<ImportResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /import_midi - bytes:{} ", file.len());
+
+        admin(&role)?;
+        Ok(match smf::import(&file) {
+            Ok(map) => {
+                let summary = ImportSummary {
+                    sections: map.sections.iter().map(SectionSummary::from).collect(),
+                    warnings: map.warnings,
+                };
+                state.set_arrangement(map.sections);
+                ImportResponse::Ok(Json(summary))
+            }
+            Err(e) => ImportResponse::BadRequest(PlainText(e.to_string())),
+        })
+    }
+
+    /// Goes back to playing the measure
+    #[oai(path = "/arrangement", method = "delete")]
+    async fn clear_arrangement(
+        &self,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<(), Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /clear_arrangement - ");
+
+        admin(&role)?;
+        state.set_arrangement(Vec::new());
+        Ok(())
+    }
+
+    #[oai(path = "/presets", method = "get")]
+    async fn presets(&self, state: Data<&AppState>) -> Json<Vec<PresetSummary>> {
+        #[cfg(debug_assertions)]
+        println!("->> /presets - ");
+
+        let presets = state.presets.lock().unwrap();
+        Json(presets.list().iter().map(PresetSummary::from).collect())
+    }
+
+    /// Saves the current settings, replacing the preset with the same name
+    #[oai(path = "/presets/:name", method = "post")]
+    async fn save_preset(
+        &self,
+        name: Path<String>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<PresetResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /presets/save - name:{} ", *name);
+
+        admin(&role)?;
+        Ok(state.save_preset(&name).into())
+    }
+
+    #[oai(path = "/presets/:name/load", method = "post")]
+    async fn load_preset(
+        &self,
+        name: Path<String>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<PresetResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /presets/load - name:{} ", *name);
+
+        admin(&role)?;
+        Ok(state.load_preset(&name).into())
+    }
+
+    #[oai(path = "/presets/:name/rename/:new_name", method = "post")]
+    async fn rename_preset(
+        &self,
+        name: Path<String>,
+        new_name: Path<String>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<PresetResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!(
+            "->> /presets/rename - name:{} new_name:{} ",
+            *name, *new_name
+        );
+
+        admin(&role)?;
+        Ok(state.rename_preset(&name, &new_name).into())
+    }
+
+    #[oai(path = "/presets/:name", method = "delete")]
+    async fn delete_preset(
+        &self,
+        name: Path<String>,
+        role: Data<&Role>,
+        state: Data<&AppState>,
+    ) -> Result<PresetResponse, Forbidden> {
+        #[cfg(debug_assertions)]
+        println!("->> /presets/delete - name:{} ", *name);
+
+        admin(&role)?;
+        Ok(state.delete_preset(&name).into())
+    }
 }
 
 // This is a shortened version of the gain example with most comments removed, check out
@@ -117,18 +1681,40 @@ impl Api {
 
 struct Racoon {
     params: Arc<RacoonParams>,
+    state: Arc<OnceLock<AppState>>,
+    // Of the self-signed certificate, shown in the editor for the clients to pin
+    fingerprint: Arc<OnceLock<String>>,
+    // The player's idle sink, converted to the host's sample rate and channel count
+    output: Option<ProcessOutput>,
+    pass_through: bool,
 }
 
 #[derive(Params)]
 struct RacoonParams {
     #[persist = "editor-state"]
     editor_state: Arc<EguiState>,
+    #[persist = "midi-mapping"]
+    midi_mapping: Arc<RwLock<MidiMapping>>,
+    #[persist = "preset"]
+    preset: Arc<RwLock<Preset>>,
+    // Serve HTTPS with a self-signed certificate, read when the server starts
+    #[persist = "tls"]
+    tls: Arc<RwLock<bool>>,
+
+    // Only used by the pass-through layout, 0 is only the incoming audio and 1 only the click.
+    // Both stay at unity in the middle, so inserting the plugin doesn't change the incoming audio.
+    #[id = "mix"]
+    mix: FloatParam,
 }
 
 impl Default for Racoon {
     fn default() -> Self {
         Self {
             params: Arc::new(RacoonParams::default()),
+            state: Arc::new(OnceLock::new()),
+            fingerprint: Arc::new(OnceLock::new()),
+            output: None,
+            pass_through: false,
         }
     }
 }
@@ -136,11 +1722,56 @@ impl Default for Racoon {
 impl Default for RacoonParams {
     fn default() -> Self {
         Self {
-            editor_state: EguiState::from_size(300, 180),
+            editor_state: EguiState::from_size(300, 290),
+            midi_mapping: Arc::new(RwLock::new(MidiMapping::default())),
+            preset: Arc::new(RwLock::new(Preset::default())),
+            tls: Arc::new(RwLock::new(false)),
+            mix: FloatParam::new("Mix", 0.5, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(SmoothingStyle::Linear(10.0))
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+        }
+    }
+}
+
+/// Gains of the incoming audio and of the click, both at unity at 0.5 and fading out towards
+/// their end of the mix
+fn mix_gains(mix: f32) -> (f32, f32) {
+    ((2.0 * (1.0 - mix)).min(1.0), (2.0 * mix).min(1.0))
+}
+
+fn apply_midi_action(state: &State, action: MidiAction) {
+    #[cfg(debug_assertions)]
+    println!("->> midi - {action:?}");
+
+    match action {
+        MidiAction::StartStop => state.toggle(),
+        MidiAction::Tap => {
+            state.tap();
+        }
+        MidiAction::SetBpm(bpm) => state.set_bpm(bpm),
+        MidiAction::SetVolume(volume) => state.set_volume(volume),
+        MidiAction::SelectProgram(program) => {
+            if let Err(e) = state.load_preset_at(program as usize) {
+                nih_log!("Couldn't load preset {program}: {e}");
+            }
         }
     }
 }
 
+fn midi_slot(ui: &mut egui::Ui, label: &str, slot: &mut Option<u8>) {
+    ui.horizontal(|ui| {
+        let mut enabled = slot.is_some();
+        if ui.checkbox(&mut enabled, label).changed() {
+            *slot = enabled.then_some(0);
+        }
+        if let Some(value) = slot {
+            ui.add(egui::DragValue::new(value).range(0..=127));
+        }
+    });
+}
+
 impl Plugin for Racoon {
     const NAME: &'static str = "Racoon Metronome";
     const VENDOR: &'static str = "Asayake";
@@ -151,20 +1782,31 @@ impl Plugin for Racoon {
 
     // The first audio IO layout is used as the default. The other layouts may be selected either
     // explicitly or automatically by the host or the user depending on the plugin API/backend.
-    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
-        main_input_channels: NonZeroU32::new(2),
-        main_output_channels: NonZeroU32::new(2),
-
-        aux_input_ports: &[],
-        aux_output_ports: &[],
-
-        // Individual ports and the layout as a whole can be named here. By default these names
-        // are generated as needed. This layout will be called 'Stereo', while a layout with
-        // only one input and output channel would be called 'Mono'.
-        names: PortNames::const_default(),
-    }];
+    // The click is generated without any input, the pass-through layout mixes it onto the
+    // incoming audio instead.
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[
+        AudioIOLayout {
+            main_input_channels: None,
+            main_output_channels: NonZeroU32::new(2),
+            ..AudioIOLayout::const_default()
+        },
+        AudioIOLayout {
+            main_input_channels: None,
+            main_output_channels: NonZeroU32::new(1),
+            ..AudioIOLayout::const_default()
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+            names: PortNames {
+                layout: Some("Stereo pass-through"),
+                ..PortNames::const_default()
+            },
+            ..AudioIOLayout::const_default()
+        },
+    ];
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::None;
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
 
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
@@ -173,157 +1815,168 @@ impl Plugin for Racoon {
     // messages here. The type implements the `SysExMessage` trait, which allows conversion to and
     // from plain byte buffers.
     type SysExMessage = ();
-    // More advanced plugins can use this to run expensive background tasks. See the field's
-    // documentation for more information. `()` means that the plugin does not have any background
-    // tasks.
-    type BackgroundTask = ();
+    // MIDI is forwarded from the audio thread and applied to the player on a background thread,
+    // as the player can't be locked from `process()`
+    type BackgroundTask = MidiInput;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        let state = self.state.clone();
+        let mapping = self.params.midi_mapping.clone();
+        Box::new(move |input| {
+            let Some(state) = state.get() else {
+                return;
+            };
+            let Some(action) = mapping.read().unwrap().action(input) else {
+                return;
+            };
+            apply_midi_action(state, action);
+        })
+    }
+
     //FIXME use this instead ? https://nih-plug.robbertvanderhelm.nl/nih_plug/context/process/trait.ProcessContext.html#tymethod.execute_background
     //FIXME save thread state and stop server in reset? more testing needed
     // It seems that deleting the vst doesn't affect the server
-    // Also, the VST outputs aren't being used
     fn initialize(
         &mut self,
-        _audio_io_layout: &AudioIOLayout,
-        _buffer_config: &BufferConfig,
+        audio_io_layout: &AudioIOLayout,
+        buffer_config: &BufferConfig,
         _context: &mut impl InitContext<Self>,
     ) -> bool {
-        nih_dbg!(_audio_io_layout);
-        thread::spawn(move || {
-            // Resize buffers and perform other potentially expensive initialization operations here.
-            // The `reset()` function is always called right after this function. You can remove this
-            // function if you do not need it.
+        nih_dbg!(audio_io_layout);
+        // The player's sink isn't attached to an output device, `process()` pulls the click from it
+        let channels = audio_io_layout
+            .main_output_channels
+            .map(NonZeroU32::get)
+            .unwrap_or(2);
+        let (sink, output) = ProcessSink::new(channels as u16, buffer_config.sample_rate as u32);
+        self.output = Some(output);
+        self.pass_through = audio_io_layout.main_input_channels.is_some();
 
-            // let up = fs::read("assets/up.wav").unwrap();
-            // let down = fs::read("assets/down.wav").unwrap();
-            let up = include_bytes!("../assets/up.wav").to_vec();
-            let down = include_bytes!("../assets/down.wav").to_vec();
-            let (player, _stream, _stream_handle) = RodioPlayer::new(up, down);
+        // The host restores the saved preset before (re)initializing the plugin
+        let preset = self.params.preset.read().unwrap().clone();
 
-            let player = Arc::new(Mutex::new(player));
+        // The server keeps running when the plugin is reinitialized, only swap out the sink
+        if let Some(state) = self.state.get() {
+            state.player.lock().unwrap().set_sink(sink);
+            if let Err(e) = state.apply_preset(&preset) {
+                nih_log!("Couldn't restore the preset: {e}");
+            }
+            return true;
+        }
 
-            let pusher = Mutex::new(Pusher::new(player.clone()));
+        let state = State::new(
+            Player::new(sink),
+            Presets::load_default(),
+            self.params.preset.clone(),
+        );
+        if let Err(e) = state.apply_preset(&preset) {
+            nih_log!("Couldn't restore the preset: {e}");
+        }
+        let _ = self.state.set(state.clone());
 
-            let state = Arc::new(State { player, pusher });
+        let tls = if *self.params.tls.read().unwrap() {
+            // Falling back to plain HTTP would expose the API the user meant to protect
+            match Tls::default_dir().map(|dir| Tls::self_signed(&dir)) {
+                Some(Ok(tls)) => {
+                    let _ = self.fingerprint.set(tls.fingerprint().to_string());
+                    Some(tls)
+                }
+                Some(Err(e)) => {
+                    nih_log!("Couldn't set up TLS, the server isn't started: {e}");
+                    return true;
+                }
+                None => {
+                    nih_log!(
+                        "Couldn't find where to keep the certificate, the server isn't started"
+                    );
+                    return true;
+                }
+            }
+        } else {
+            None
+        };
 
+        thread::spawn(move || {
             let Some(port) = free_local_port_in_range(20000..=60000) else {
                 panic!(
             "Couldn't find an open port, you shouldn't realistically be seeing this, exiting..."
         );
             };
 
-            let api_service = OpenApiService::new(Api, "Racoon Metronome", "0.1")
-                .server(&format!("http://localhost:{port}/api"));
-
-            let ui = api_service.swagger_ui();
-            let app = Route::new()
-                .nest("/api", api_service)
-                .nest("/doc", ui)
-                .with(AddData::new(state));
-
-            let _discovery_server = DiscoveryServer::new(port.into());
-            let network_interfaces = list_afinet_netifas().unwrap();
-            let mut loopback: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-            let likely_local = local_ip();
-            // let mut locals = Vec::with_capacity(10);
-
-            // let likely_local_qr = if let Ok(ip) = likely_local {
-            //     let code = QrCode::new(&format!("{ip}:{port}")).unwrap();
-            //     code.render::<unicode::Dense1x2>()
-            //         .quiet_zone(true)
-            //         .dark_color(unicode::Dense1x2::Light)
-            //         .light_color(unicode::Dense1x2::Dark)
-            //         .build()
-            // } else {
-            //     String::from("")
-            // };
-
-            // for (_, ip) in network_interfaces.iter() {
-            //     if ip.is_loopback() {
-            //         loopback = *ip;
-            //     }
-            //     if let IpAddr::V4(ipv4) = ip.to_canonical() {
-            //         if ipv4.is_private() {
-            //             locals.push(ip);
-            //         }
-            //     }
-            // }
-
-            // let mut qr_str = locals
-            //     .iter()
-            //     .map(|e| e.to_string())
-            //     .collect::<Vec<String>>()
-            //     .join(",");
-            // qr_str.push_str(&format!(":{port}"));
-
-            // let code = QrCode::new(&qr_str).unwrap();
-            // let code = code
-            //     .render::<unicode::Dense1x2>()
-            //     .quiet_zone(true)
-            //     .dark_color(unicode::Dense1x2::Light)
-            //     .light_color(unicode::Dense1x2::Dark)
-            //     .build();
-
-            // println!("{loopback}",);
-            // println!("{likely_local_qr}",);
-            // println!("{code}",);
+            let _discovery_server = DiscoveryServer::new(port.into(), tls.is_some());
 
             let rt = Runtime::new().unwrap();
-            rt.block_on(async move {
-                Server::new(TcpListener::bind(&format!("0.0.0.0:{port}")))
-                    .name("racoon")
-                    .run(app)
-                    .await
-            });
+            rt.block_on(server::serve(
+                state,
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+                tls.as_ref(),
+            ))
         });
 
         true
     }
 
     fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+        let params = self.params.clone();
+        let state = self.state.clone();
+        let fingerprint = self.fingerprint.clone();
         create_egui_editor(
             self.params.editor_state.clone(),
             (),
             |_, _| {},
-            move |egui_ctx, setter, _state| {
+            move |egui_ctx, _setter, _state| {
                 egui::CentralPanel::default().show(egui_ctx, |ui| {
                     // NOTE: See `plugins/diopser/src/editor.rs` for an example using the generic UI widget
 
-                    // This is a fancy widget that can get all the information it needs to properly
-                    // display and modify the parameter from the parametr itself
-                    // It's not yet fully implemented, as the text is missing.
-                    ui.label("Some random integer");
-                    // ui.add(widgets::ParamSlider::for_param(&params.some_int, setter));
+                    if let Some(state) = state.get() {
+                        ui.horizontal(|ui| {
+                            if ui.button("Tap").clicked() {
+                                state.tap();
+                            }
+                            ui.label(format!("{} BPM", state.player.lock().unwrap().bpm()));
+                        });
+                    }
 
-                    // ui.label("Gain");
-                    // ui.add(widgets::ParamSlider::for_param(&params.gain, setter));
+                    ui.heading("MIDI");
+                    let mut mapping = params.midi_mapping.write().unwrap();
+                    ui.horizontal(|ui| {
+                        let mut omni = mapping.channel.is_none();
+                        if ui.checkbox(&mut omni, "All channels").changed() {
+                            mapping.channel = if omni { None } else { Some(0) };
+                        }
+                        if let Some(channel) = &mut mapping.channel {
+                            ui.add(egui::DragValue::new(channel).range(0..=15));
+                        }
+                    });
+                    midi_slot(ui, "Start/stop note", &mut mapping.start_stop_note);
+                    midi_slot(ui, "Tap tempo note", &mut mapping.tap_note);
+                    midi_slot(ui, "BPM CC", &mut mapping.bpm_cc);
+                    ui.horizontal(|ui| {
+                        let max = mapping.bpm_max;
+                        ui.add(egui::DragValue::new(&mut mapping.bpm_min).range(1..=max));
+                        ui.label("to");
+                        let min = mapping.bpm_min;
+                        ui.add(
+                            egui::DragValue::new(&mut mapping.bpm_max)
+                                .range(min..=*BPM_RANGE.end()),
+                        );
+                        ui.label("BPM");
+                    });
+                    midi_slot(ui, "Volume CC", &mut mapping.volume_cc);
+                    ui.checkbox(&mut mapping.program_change, "Program change loads preset");
 
-                    ui.label(
-                        "Also gain, but with a lame widget. Can't even render the value correctly!",
+                    ui.heading("Remote");
+                    ui.checkbox(
+                        &mut params.tls.write().unwrap(),
+                        "HTTPS, from the next time the plugin is loaded",
                     );
-                    // This is a simple naieve version of a parameter slider that's not aware of how
-                    // the parameters work
-                    // ui.add(
-                    //     egui::widgets::Slider::from_get_set(-30.0..=30.0, |new_value| {
-                    //         match new_value {
-                    //             Some(new_value_db) => {
-                    //                 let new_value = util::gain_to_db(new_value_db as f32);
-
-                    //                 setter.begin_set_parameter(&params.gain);
-                    //                 setter.set_parameter(&params.gain, new_value);
-                    //                 setter.end_set_parameter(&params.gain);
-
-                    //                 new_value_db
-                    //             }
-                    //             None => util::gain_to_db(params.gain.value()) as f64,
-                    //         }
-                    //     })
-                    //     .suffix(" dB"),
-                    // );
+                    if let Some(fingerprint) = fingerprint.get() {
+                        ui.label(format!("Certificate fingerprint (SHA-256): {fingerprint}"));
+                    }
                 });
             },
         )
@@ -338,8 +1991,39 @@ impl Plugin for Racoon {
         &mut self,
         buffer: &mut Buffer,
         _aux: &mut AuxiliaryBuffers,
-        _context: &mut impl ProcessContext<Self>,
+        context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
+        while let Some(event) = context.next_event() {
+            let input = match event {
+                NoteEvent::NoteOn { channel, note, .. } => MidiInput::NoteOn { channel, note },
+                NoteEvent::MidiCC {
+                    channel, cc, value, ..
+                } => MidiInput::Cc { channel, cc, value },
+                NoteEvent::MidiProgramChange {
+                    channel, program, ..
+                } => MidiInput::Program { channel, program },
+                _ => continue,
+            };
+            context.execute_background(input);
+        }
+
+        let Some(output) = &mut self.output else {
+            return ProcessStatus::Normal;
+        };
+        for channel_samples in buffer.iter_samples() {
+            if !self.pass_through {
+                for sample in channel_samples {
+                    *sample = output.next().unwrap_or(0.0);
+                }
+                continue;
+            }
+            let mix = self.params.mix.smoothed.next();
+            let (input, click) = mix_gains(mix);
+            for sample in channel_samples {
+                *sample = *sample * input + output.next().unwrap_or(0.0) * click;
+            }
+        }
+
         ProcessStatus::Normal
     }
 }
@@ -350,16 +2034,19 @@ impl ClapPlugin for Racoon {
     const CLAP_MANUAL_URL: Option<&'static str> = Some(Self::URL);
     const CLAP_SUPPORT_URL: Option<&'static str> = None;
 
-    // Don't forget to change these features
-    const CLAP_FEATURES: &'static [ClapFeature] = &[ClapFeature::AudioEffect, ClapFeature::Stereo];
+    const CLAP_FEATURES: &'static [ClapFeature] = &[
+        ClapFeature::Instrument,
+        ClapFeature::Utility,
+        ClapFeature::Stereo,
+        ClapFeature::Mono,
+    ];
 }
 
 impl Vst3Plugin for Racoon {
     const VST3_CLASS_ID: [u8; 16] = *b"RacoonMetronomee";
 
-    // And also don't forget to change these categories
     const VST3_SUBCATEGORIES: &'static [Vst3SubCategory] =
-        &[Vst3SubCategory::Fx, Vst3SubCategory::Dynamics];
+        &[Vst3SubCategory::Instrument, Vst3SubCategory::Generator];
 }
 
 nih_export_clap!(Racoon);
@@ -1,61 +1,515 @@
+use std::{fmt::Display, ops::RangeInclusive};
+
+use serde::{Deserialize, Serialize};
+
 use crate::rhythm::Rhythm;
 
-#[derive(Debug, Clone)]
+/// Of the downbeat, the loudest a click gets by default. The accented beats also have their own
+/// sounds and levels, so this stays clear of clipping.
+pub const ACCENT_VOLUME: f32 = 1.5;
+/// Of the first beat of every other group
+pub const GROUP_VOLUME: f32 = 1.25;
+
+/// From straight to dotted, triplet swing is 2/3
+pub const SWING_RANGE: RangeInclusive<f64> = 0.5..=0.75;
+pub const STRAIGHT: f64 = 0.5;
+/// In milliseconds
+pub const HUMANIZE_RANGE: RangeInclusive<f64> = 0.0..=50.0;
+/// Beats per measure, bounded so a measure can't take up all the memory
+pub const NUMERATOR_RANGE: RangeInclusive<usize> = 1..=32;
+/// The longest a measure can last in nanoseconds, as long as 4/4 at the slowest tempo. The
+/// player renders a measure at a time, so this bounds its buffers.
+pub const MAX_DURATION: u64 = 4 * 60 * 1_000_000_000;
+
+/// The tempo always counts quarter notes, so a 6/8 measure at 120 BPM lasts 1.5 seconds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TimeSignature {
+    pub numerator: usize,
+    pub denominator: usize,
+}
+
+impl TimeSignature {
+    /// `None` unless the numerator is in `NUMERATOR_RANGE` and the denominator is 2, 4, 8 or 16
+    pub fn new(numerator: usize, denominator: usize) -> Option<Self> {
+        (NUMERATOR_RANGE.contains(&numerator) && Rhythm::from_denominator(denominator).is_some())
+            .then_some(TimeSignature {
+                numerator,
+                denominator,
+            })
+    }
+
+    /// The note value of one beat
+    pub fn beat(&self) -> Rhythm {
+        Rhythm::from_denominator(self.denominator).unwrap_or(Rhythm::Quarter)
+    }
+
+    /// Groups of three in compound meters like 6/8, twos followed by a three in odd meters like
+    /// 7/8, and a single group otherwise
+    pub fn default_grouping(&self) -> Vec<usize> {
+        let n = self.numerator;
+        if self.denominator < 8 || n < 5 {
+            vec![n]
+        } else if n.is_multiple_of(3) {
+            vec![3; n / 3]
+        } else if n.is_multiple_of(2) {
+            vec![2; n / 2]
+        } else {
+            let mut grouping = vec![2; (n - 3) / 2];
+            grouping.push(3);
+            grouping
+        }
+    }
+
+    /// Whether every group has beats and they add up to the numerator
+    pub fn fits(&self, grouping: &[usize]) -> bool {
+        !grouping.contains(&0)
+            && grouping
+                .iter()
+                .try_fold(0usize, |sum, &len| sum.checked_add(len))
+                == Some(self.numerator)
+    }
+}
+
+impl Display for TimeSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.numerator, self.denominator)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredMeasure")]
 pub struct Measure {
-    pub beats_per_measure: usize,
+    pub time_signature: TimeSignature,
+    /// Beats per group, e.g. `[2, 2, 3]` for 7/8, adding up to the numerator
+    pub grouping: Vec<usize>,
     pub data: Vec<Beat>,
+    /// Every beat is split into these, only the first one plays the beat's sound
+    pub subdivision: Rhythm,
+    /// Where the second note of every pair of eighth or sixteenth subdivisions starts, as a
+    /// fraction of the pair, in `SWING_RANGE`
+    pub swing: f64,
+    /// Every click is moved randomly by up to this many milliseconds either way
+    pub humanize: f64,
+}
+
+/// A measure as saved by any version, older ones only have `beats_per_measure`
+#[derive(Deserialize)]
+struct StoredMeasure {
+    time_signature: Option<TimeSignature>,
+    beats_per_measure: Option<usize>,
+    grouping: Option<Vec<usize>>,
+    data: Vec<Beat>,
+    #[serde(default = "no_subdivision")]
+    subdivision: Rhythm,
+    #[serde(default = "straight")]
+    swing: f64,
+    #[serde(default)]
+    humanize: f64,
+}
+
+impl From<StoredMeasure> for Measure {
+    fn from(stored: StoredMeasure) -> Self {
+        // Older measures counted quarter notes, their beats keep the durations they had
+        let time_signature = stored
+            .time_signature
+            .and_then(|signature| TimeSignature::new(signature.numerator, signature.denominator))
+            .unwrap_or(TimeSignature {
+                numerator: stored
+                    .beats_per_measure
+                    .unwrap_or(stored.data.len())
+                    .clamp(*NUMERATOR_RANGE.start(), *NUMERATOR_RANGE.end()),
+                denominator: 4,
+            });
+        let grouping = stored
+            .grouping
+            .filter(|grouping| time_signature.fits(grouping))
+            .unwrap_or_else(|| time_signature.default_grouping());
+        Measure {
+            time_signature,
+            grouping,
+            data: stored.data,
+            subdivision: stored.subdivision,
+            swing: stored.swing,
+            humanize: stored.humanize,
+        }
+    }
+}
+
+fn no_subdivision() -> Rhythm {
+    Rhythm::Quarter
+}
+
+fn straight() -> f64 {
+    STRAIGHT
+}
+
+/// Where a beat or one of its subdivisions starts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Onset {
+    /// Nanoseconds since the start of the measure
+    pub time: f64,
+    pub beat: usize,
+    /// 0 is the beat itself
+    pub sub: usize,
 }
 
 impl Default for Measure {
     fn default() -> Self {
         Measure {
-            beats_per_measure: 4,
+            time_signature: TimeSignature {
+                numerator: 4,
+                denominator: 4,
+            },
+            grouping: vec![4],
             data: vec![
                 Beat(vec![Sound {
                     sound_type: SoundType::Up,
                     duration: Rhythm::Quarter,
-                    volume_modifier: 3.0,
+                    volume_modifier: ACCENT_VOLUME,
                     hidden: false,
+                    pan: 0.0,
                 }]),
                 Beat(vec![Sound {
                     sound_type: SoundType::Down,
                     duration: Rhythm::Quarter,
                     volume_modifier: 1.0,
                     hidden: false,
+                    pan: 0.0,
                 }]),
                 Beat(vec![Sound {
                     sound_type: SoundType::Down,
                     duration: Rhythm::Quarter,
                     volume_modifier: 1.0,
                     hidden: false,
+                    pan: 0.0,
                 }]),
                 Beat(vec![Sound {
                     sound_type: SoundType::Down,
                     duration: Rhythm::Quarter,
                     volume_modifier: 1.0,
                     hidden: false,
+                    pan: 0.0,
                 }]),
             ],
+            subdivision: Rhythm::Quarter,
+            swing: STRAIGHT,
+            humanize: 0.0,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+impl Measure {
+    /// A measure of quarter notes with an accent on the downbeat
+    pub fn new(beats_per_measure: usize) -> Self {
+        let time_signature = TimeSignature {
+            numerator: beats_per_measure,
+            denominator: 4,
+        };
+        Self::with_grouping(time_signature, vec![beats_per_measure]).unwrap()
+    }
+
+    /// A measure grouped the usual way for its time signature
+    pub fn with_time_signature(time_signature: TimeSignature) -> Self {
+        Self::with_grouping(time_signature, time_signature.default_grouping()).unwrap()
+    }
+
+    /// Accents the downbeat and the first beat of every other group, `None` if the grouping
+    /// doesn't add up to the numerator
+    pub fn with_grouping(time_signature: TimeSignature, grouping: Vec<usize>) -> Option<Self> {
+        if !time_signature.fits(&grouping) {
+            return None;
+        }
+        let data = grouping
+            .iter()
+            .enumerate()
+            .flat_map(|(group, &len)| {
+                (0..len).map(move |n| {
+                    let (sound_type, volume_modifier) = match (group, n) {
+                        (0, 0) => (SoundType::Up, ACCENT_VOLUME),
+                        (_, 0) => (SoundType::Mid, GROUP_VOLUME),
+                        _ => (SoundType::Down, 1.0),
+                    };
+                    Beat(vec![Sound {
+                        sound_type,
+                        duration: time_signature.beat(),
+                        volume_modifier,
+                        hidden: false,
+                        pan: 0.0,
+                    }])
+                })
+            })
+            .collect();
+        Some(Measure {
+            time_signature,
+            grouping,
+            data,
+            subdivision: Rhythm::Quarter,
+            swing: STRAIGHT,
+            humanize: 0.0,
+        })
+    }
+
+    pub fn beats_per_measure(&self) -> usize {
+        self.time_signature.numerator
+    }
+
+    /// The sound type of every beat, `rest` for the silent ones
+    pub fn beat_names(&self) -> Vec<&'static str> {
+        self.data
+            .iter()
+            .map(|beat| match beat.0.first() {
+                Some(sound) if !sound.hidden => sound.sound_type.name(),
+                _ => "rest",
+            })
+            .collect()
+    }
+
+    /// Length of the measure in nanoseconds
+    pub fn duration(&self, bpm: u64) -> u64 {
+        self.data
+            .iter()
+            .map(|beat| beat.0[0].duration.make_duration(bpm))
+            .fold(0, u64::saturating_add)
+    }
+
+    /// Lasts longer than `MAX_DURATION` at `bpm`
+    pub fn too_long(&self, bpm: u64) -> bool {
+        self.duration(bpm) > MAX_DURATION
+    }
+
+    /// The slowest tempo at which it isn't `too_long()`
+    pub fn slowest_bpm(&self) -> u64 {
+        self.duration(1).div_ceil(MAX_DURATION).max(1)
+    }
+
+    /// Start of every beat and subdivision, with the swing applied but not the humanize
+    pub fn onsets(&self, bpm: u64) -> Vec<Onset> {
+        let fractions = self.subdivision.fractions();
+        let swung = matches!(self.subdivision, Rhythm::Eighth | Rhythm::Sixteenth);
+        let mut onsets = Vec::with_capacity(self.data.len() * fractions.len());
+        let mut beat_start = 0.0;
+        for (beat, b) in self.data.iter().enumerate() {
+            let length = b.0[0].duration.make_duration(bpm) as f64;
+            let mut start = 0.0;
+            for (sub, fraction) in fractions.iter().enumerate() {
+                let offset = if swung && sub % 2 == 1 {
+                    // The pair starts on the previous subdivision and lasts two of them
+                    start - fraction + 2.0 * fraction * self.swing
+                } else {
+                    start
+                };
+                onsets.push(Onset {
+                    time: beat_start + offset * length,
+                    beat,
+                    sub,
+                });
+                start += fraction;
+            }
+            beat_start += length;
+        }
+        onsets
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Beat(pub Vec<Sound>);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum SoundType {
     Up,
     Mid,
     Down,
 }
 
-#[derive(Debug, Clone)]
+impl SoundType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SoundType::Up => "up",
+            SoundType::Mid => "mid",
+            SoundType::Down => "down",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Sound {
     pub sound_type: SoundType,
     pub duration: Rhythm,
     pub volume_modifier: f32,
     // pitch_modifier?
     pub hidden: bool,
+    /// From -1.0 (left) to 1.0 (right), see `mixer::Routing`
+    #[serde(default)]
+    pub pan: f32,
     // rhythm? and compute duration from?
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn times(measure: &Measure, bpm: u64) -> Vec<f64> {
+        measure
+            .onsets(bpm)
+            .iter()
+            .map(|onset| onset.time / 1_000_000.0)
+            .collect()
+    }
+
+    fn assert_close(actual: Vec<f64>, expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?}");
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn straight_eighths() {
+        let mut measure = Measure::new(2);
+        measure.subdivision = Rhythm::Eighth;
+        assert_close(times(&measure, 120), &[0.0, 250.0, 500.0, 750.0]);
+    }
+
+    #[test]
+    fn triplet_swing() {
+        let mut measure = Measure::new(2);
+        measure.subdivision = Rhythm::Eighth;
+        measure.swing = 2.0 / 3.0;
+        assert_close(
+            times(&measure, 120),
+            &[0.0, 1000.0 / 3.0, 500.0, 500.0 + 1000.0 / 3.0],
+        );
+    }
+
+    #[test]
+    fn dotted_sixteenths() {
+        let mut measure = Measure::new(1);
+        measure.subdivision = Rhythm::Sixteenth;
+        measure.swing = 0.75;
+        assert_close(times(&measure, 60), &[0.0, 375.0, 500.0, 875.0]);
+    }
+
+    #[test]
+    fn swing_ignores_other_subdivisions() {
+        let mut measure = Measure::new(1);
+        measure.subdivision = Rhythm::TripletEighth;
+        measure.swing = 0.75;
+        assert_close(times(&measure, 60), &[0.0, 1000.0 / 3.0, 2000.0 / 3.0]);
+    }
+
+    #[test]
+    fn swing_in_compound_meter() {
+        let mut measure = Measure::with_time_signature(TimeSignature::new(6, 8).unwrap());
+        measure.subdivision = Rhythm::Eighth;
+        measure.swing = 0.6;
+        let onsets = times(&measure, 120);
+        assert_eq!(onsets.len(), 12);
+        assert_close(onsets[..4].to_vec(), &[0.0, 150.0, 250.0, 400.0]);
+    }
+
+    fn grouping(numerator: usize, denominator: usize) -> Vec<usize> {
+        TimeSignature::new(numerator, denominator)
+            .unwrap()
+            .default_grouping()
+    }
+
+    #[test]
+    fn default_groupings() {
+        assert_eq!(grouping(4, 4), [4]);
+        assert_eq!(grouping(5, 4), [5]);
+        assert_eq!(grouping(4, 8), [4]);
+        assert_eq!(grouping(6, 8), [3, 3]);
+        assert_eq!(grouping(9, 8), [3, 3, 3]);
+        assert_eq!(grouping(12, 16), [3, 3, 3, 3]);
+        assert_eq!(grouping(8, 8), [2, 2, 2, 2]);
+        assert_eq!(grouping(5, 8), [2, 3]);
+        assert_eq!(grouping(7, 8), [2, 2, 3]);
+        assert_eq!(grouping(11, 16), [2, 2, 2, 2, 3]);
+    }
+
+    #[test]
+    fn accents_follow_the_grouping() {
+        let time_signature = TimeSignature::new(7, 8).unwrap();
+        let measure = Measure::with_grouping(time_signature, vec![3, 2, 2]).unwrap();
+        let accents = measure
+            .data
+            .iter()
+            .map(|beat| (beat.0[0].sound_type, beat.0[0].duration))
+            .collect::<Vec<_>>();
+        let (up, mid, down) = (SoundType::Up, SoundType::Mid, SoundType::Down);
+        let eighth = Rhythm::Eighth;
+        assert_eq!(
+            accents,
+            [
+                (up, eighth),
+                (down, eighth),
+                (down, eighth),
+                (mid, eighth),
+                (down, eighth),
+                (mid, eighth),
+                (down, eighth),
+            ]
+        );
+        assert_eq!(measure.beats_per_measure(), 7);
+    }
+
+    #[test]
+    fn groupings_add_up() {
+        let time_signature = TimeSignature::new(7, 8).unwrap();
+        assert!(Measure::with_grouping(time_signature, vec![3, 3]).is_none());
+        assert!(Measure::with_grouping(time_signature, vec![3, 2, 2, 1]).is_none());
+        assert!(Measure::with_grouping(time_signature, vec![7, 0]).is_none());
+        assert!(Measure::with_grouping(time_signature, vec![usize::MAX, 8]).is_none());
+        assert!(TimeSignature::new(0, 4).is_none());
+        assert!(TimeSignature::new(4, 3).is_none());
+        assert!(TimeSignature::new(32, 4).is_some());
+        assert!(TimeSignature::new(33, 4).is_none());
+        assert!(TimeSignature::new(usize::MAX, 4).is_none());
+    }
+
+    #[test]
+    fn loads_bad_time_signatures() {
+        let json = r#"{
+            "time_signature": {"numerator": 1000000000, "denominator": 4},
+            "grouping": [18446744073709551615, 1],
+            "data": []
+        }"#;
+        let measure: Measure = serde_json::from_str(json).unwrap();
+        assert_eq!(measure.time_signature, TimeSignature::new(1, 4).unwrap());
+        assert_eq!(measure.grouping, [1]);
+    }
+
+    #[test]
+    fn loads_old_measures() {
+        let json = r#"{
+            "beats_per_measure": 3,
+            "data": [
+                [{"sound_type": "Up", "duration": "quarter", "volume_modifier": 3.0, "hidden": false}],
+                [{"sound_type": "Down", "duration": "quarter", "volume_modifier": 1.0, "hidden": false}],
+                [{"sound_type": "Down", "duration": "quarter", "volume_modifier": 1.0, "hidden": true}]
+            ],
+            "subdivision": "eighth"
+        }"#;
+        let measure: Measure = serde_json::from_str(json).unwrap();
+        assert_eq!(measure.time_signature, TimeSignature::new(3, 4).unwrap());
+        assert_eq!(measure.grouping, [3]);
+        assert_eq!(measure.data.len(), 3);
+        assert!(measure.data[2].0[0].hidden);
+        assert_eq!(measure.subdivision, Rhythm::Eighth);
+        assert_eq!(measure.swing, STRAIGHT);
+        assert_eq!(measure.humanize, 0.0);
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let mut measure =
+            Measure::with_grouping(TimeSignature::new(7, 8).unwrap(), vec![3, 2, 2]).unwrap();
+        measure.swing = 0.6;
+        let json = serde_json::to_string(&measure).unwrap();
+        let loaded: Measure = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.time_signature, measure.time_signature);
+        assert_eq!(loaded.grouping, [3, 2, 2]);
+        assert_eq!(loaded.data.len(), 7);
+        assert_eq!(loaded.swing, 0.6);
+    }
+}
@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+
+use crate::player::BPM_RANGE;
+
+/// The subset of incoming MIDI the plugin forwards from the audio thread.
+#[derive(Debug, Clone, Copy)]
+pub enum MidiInput {
+    NoteOn { channel: u8, note: u8 },
+    Cc { channel: u8, cc: u8, value: f32 },
+    Program { channel: u8, program: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MidiAction {
+    StartStop,
+    Tap,
+    SetBpm(u64),
+    SetVolume(f32),
+    SelectProgram(u8),
+}
+
+/// User configurable mapping from MIDI messages to metronome actions, `None` disables a mapping.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MidiMapping {
+    /// Only listen to this channel (0-15), or to every channel if `None`
+    pub channel: Option<u8>,
+    pub start_stop_note: Option<u8>,
+    pub tap_note: Option<u8>,
+    pub bpm_cc: Option<u8>,
+    pub bpm_min: u64,
+    pub bpm_max: u64,
+    pub volume_cc: Option<u8>,
+    pub program_change: bool,
+}
+
+impl Default for MidiMapping {
+    fn default() -> Self {
+        MidiMapping {
+            channel: None,
+            start_stop_note: Some(60),
+            tap_note: Some(62),
+            bpm_cc: Some(20),
+            bpm_min: 30,
+            bpm_max: 300,
+            volume_cc: Some(7),
+            program_change: true,
+        }
+    }
+}
+
+impl MidiMapping {
+    pub fn action(&self, input: MidiInput) -> Option<MidiAction> {
+        let channel = match input {
+            MidiInput::NoteOn { channel, .. }
+            | MidiInput::Cc { channel, .. }
+            | MidiInput::Program { channel, .. } => channel,
+        };
+        if self.channel.is_some_and(|c| c != channel) {
+            return None;
+        }
+
+        match input {
+            MidiInput::NoteOn { note, .. } if self.start_stop_note == Some(note) => {
+                Some(MidiAction::StartStop)
+            }
+            MidiInput::NoteOn { note, .. } if self.tap_note == Some(note) => Some(MidiAction::Tap),
+            MidiInput::Cc { cc, value, .. } if self.bpm_cc == Some(cc) => {
+                let range = self.bpm_max.saturating_sub(self.bpm_min) as f32;
+                let bpm = self.bpm_min + (value.clamp(0.0, 1.0) * range).round() as u64;
+                Some(MidiAction::SetBpm(
+                    bpm.clamp(*BPM_RANGE.start(), *BPM_RANGE.end()),
+                ))
+            }
+            MidiInput::Cc { cc, value, .. } if self.volume_cc == Some(cc) => {
+                Some(MidiAction::SetVolume(value.clamp(0.0, 1.0)))
+            }
+            MidiInput::Program { program, .. } if self.program_change => {
+                Some(MidiAction::SelectProgram(program))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(channel: u8, note: u8) -> MidiInput {
+        MidiInput::NoteOn { channel, note }
+    }
+
+    fn cc(cc: u8, value: f32) -> MidiInput {
+        MidiInput::Cc {
+            channel: 0,
+            cc,
+            value,
+        }
+    }
+
+    #[test]
+    fn notes() {
+        let mapping = MidiMapping::default();
+        assert_eq!(mapping.action(note(0, 60)), Some(MidiAction::StartStop));
+        assert_eq!(mapping.action(note(9, 62)), Some(MidiAction::Tap));
+        assert_eq!(mapping.action(note(0, 61)), None);
+    }
+
+    #[test]
+    fn bpm_cc() {
+        let mapping = MidiMapping::default();
+        assert_eq!(mapping.action(cc(20, 0.0)), Some(MidiAction::SetBpm(30)));
+        assert_eq!(mapping.action(cc(20, 0.5)), Some(MidiAction::SetBpm(165)));
+        assert_eq!(mapping.action(cc(20, 1.0)), Some(MidiAction::SetBpm(300)));
+        assert_eq!(mapping.action(cc(20, 2.0)), Some(MidiAction::SetBpm(300)));
+        assert_eq!(mapping.action(cc(21, 1.0)), None);
+    }
+
+    #[test]
+    fn bpm_cc_stays_in_range() {
+        let mapping = MidiMapping {
+            bpm_min: 0,
+            bpm_max: 5000,
+            ..MidiMapping::default()
+        };
+        assert_eq!(mapping.action(cc(20, 0.0)), Some(MidiAction::SetBpm(1)));
+        assert_eq!(mapping.action(cc(20, 1.0)), Some(MidiAction::SetBpm(1000)));
+    }
+
+    #[test]
+    fn volume_cc() {
+        let mapping = MidiMapping::default();
+        assert_eq!(
+            mapping.action(cc(7, 0.25)),
+            Some(MidiAction::SetVolume(0.25))
+        );
+        assert_eq!(
+            mapping.action(cc(7, -1.0)),
+            Some(MidiAction::SetVolume(0.0))
+        );
+    }
+
+    #[test]
+    fn program_change() {
+        let program = MidiInput::Program {
+            channel: 0,
+            program: 3,
+        };
+        let mut mapping = MidiMapping::default();
+        assert_eq!(mapping.action(program), Some(MidiAction::SelectProgram(3)));
+        mapping.program_change = false;
+        assert_eq!(mapping.action(program), None);
+    }
+
+    #[test]
+    fn channel_filter() {
+        let mapping = MidiMapping {
+            channel: Some(2),
+            ..MidiMapping::default()
+        };
+        assert_eq!(mapping.action(note(2, 60)), Some(MidiAction::StartStop));
+        assert_eq!(mapping.action(note(0, 60)), None);
+        assert_eq!(mapping.action(cc(20, 1.0)), None);
+    }
+
+    #[test]
+    fn disabled_mappings() {
+        let mapping = MidiMapping {
+            start_stop_note: None,
+            bpm_cc: None,
+            ..MidiMapping::default()
+        };
+        assert_eq!(mapping.action(note(0, 60)), None);
+        assert_eq!(mapping.action(cc(20, 1.0)), None);
+    }
+}
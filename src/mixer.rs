@@ -0,0 +1,904 @@
+//! Renders measures into sample buffers so several layers can play against each other, e.g. 3
+//! against 4 or a 7/8 layer against a 4/4 one, without drifting apart
+
+use std::{io, str::FromStr, sync::Arc};
+
+use rodio::{source::UniformSourceIterator, Decoder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    measure::{Measure, SoundType, MAX_DURATION},
+    player::StartOptions,
+};
+
+/// Of the live output, offline renders can use another one
+pub const SAMPLE_RATE: u32 = 48_000;
+pub const CHANNELS: u16 = 2;
+
+// Subdivisions are played with the down sample at this volume
+const SUBDIVISION_VOLUME: f32 = 0.5;
+const COUNT_IN_VOLUME: f32 = 0.6;
+const END_VOLUME: f32 = 0.6;
+/// The limiter leaves the mix untouched below this level
+const LIMITER_THRESHOLD: f32 = 0.8;
+
+fn full_volume() -> f32 {
+    1.0
+}
+
+/// A measure played along with the main one, at the same tempo
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Layer {
+    pub measure: Measure,
+    #[serde(default = "full_volume")]
+    pub volume: f32,
+    /// Squeezes the measure into the length of the main measure, e.g. `3/4` against `4/4` plays
+    /// 3 against 4. Otherwise it's played at the tempo and realigns with the main measure every
+    /// few measures.
+    #[serde(default)]
+    pub fit: bool,
+}
+
+impl Layer {
+    pub fn new(measure: Measure) -> Self {
+        Layer {
+            measure,
+            volume: 1.0,
+            fit: false,
+        }
+    }
+}
+
+/// Gains in dB, the limiter comes after them and `Player::set_volume()` after the limiter
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Levels {
+    /// Of every sound, including the count-in and the end sound
+    pub master: f32,
+    pub up: f32,
+    pub mid: f32,
+    /// Also of the subdivisions
+    pub down: f32,
+    /// Keeps the mix below full scale, e.g. when an accent rings into the next beat
+    pub limiter: bool,
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        Levels {
+            master: 0.0,
+            up: 0.0,
+            mid: 0.0,
+            down: 0.0,
+            limiter: true,
+        }
+    }
+}
+
+impl Levels {
+    /// Linear gain of the sounds of `sound_type`, of the count-in and the end sound if `None`
+    fn gain(&self, sound_type: Option<SoundType>) -> f32 {
+        let db = match sound_type {
+            Some(SoundType::Up) => self.up,
+            Some(SoundType::Mid) => self.mid,
+            Some(SoundType::Down) => self.down,
+            None => 0.0,
+        };
+        decibels(self.master + db)
+    }
+}
+
+/// Linear gain of `db`
+pub fn decibels(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+/// Soft limiter, linear up to the threshold and then bending towards full scale without reaching
+/// it
+fn limit(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= LIMITER_THRESHOLD {
+        return sample;
+    }
+    let headroom = 1.0 - LIMITER_THRESHOLD;
+    (LIMITER_THRESHOLD + headroom * ((magnitude - LIMITER_THRESHOLD) / headroom).tanh())
+        .copysign(sample)
+}
+
+/// Where the clicks go in the stereo output, e.g. to feed a separate monitor mix
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Routing {
+    /// Every sound at its own pan position
+    #[default]
+    Stereo,
+    /// Accents on the left, the other beats and the subdivisions on the right
+    Split,
+    /// Everything on one channel
+    Left,
+    Right,
+}
+
+impl Routing {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Routing::Stereo => "stereo",
+            Routing::Split => "split",
+            Routing::Left => "left",
+            Routing::Right => "right",
+        }
+    }
+
+    /// Gains of the left and the right channel, the count-in and the end sound keep their pan
+    /// position with a split
+    fn gains(&self, sample: Sample, pan: f32) -> [f32; 2] {
+        let pan = match (self, sample) {
+            (Routing::Stereo, _) | (Routing::Split, Sample::CountIn | Sample::End) => pan,
+            (Routing::Split, Sample::Up) | (Routing::Left, _) => -1.0,
+            (Routing::Split, Sample::Down) | (Routing::Right, _) => 1.0,
+        };
+        let pan = pan.clamp(-1.0, 1.0);
+        [(1.0 - pan).min(1.0), (1.0 + pan).min(1.0)]
+    }
+}
+
+impl FromStr for Routing {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stereo" => Ok(Routing::Stereo),
+            "split" => Ok(Routing::Split),
+            "left" => Ok(Routing::Left),
+            "right" => Ok(Routing::Right),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A sine beep fading out, for the sounds that don't come from a sample
+pub(crate) fn tone(frequency: f32, millis: u32, sample_rate: u32) -> Arc<[f32]> {
+    let frames = (sample_rate as u64 * millis as u64 / 1000) as usize;
+    (0..frames)
+        .flat_map(|i| {
+            let t = i as f32 / sample_rate as f32;
+            let fade = 1.0 - i as f32 / frames as f32;
+            let sample = (t * frequency * std::f32::consts::TAU).sin() * fade * fade;
+            [sample; CHANNELS as usize]
+        })
+        .collect()
+}
+
+/// Decodes a sample into interleaved frames with `CHANNELS` channels
+pub fn decode(data: Vec<u8>, sample_rate: u32) -> io::Result<Arc<[f32]>> {
+    let source = Decoder::new(io::Cursor::new(data))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(
+        UniformSourceIterator::<_, f32>::new(source, CHANNELS, sample_rate)
+            .collect::<Vec<f32>>()
+            .into(),
+    )
+}
+
+/// Length of a measure in frames
+fn frames(nanos: u64, sample_rate: u32) -> f64 {
+    nanos as f64 * sample_rate as f64 / 1_000_000_000.0
+}
+
+#[derive(Clone, Copy)]
+enum Sample {
+    Up,
+    Down,
+    CountIn,
+    End,
+}
+
+/// A beat of the main measure or of the count-in, as scheduled by `Mixer::render()`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tick {
+    /// Of the mixer, at which the beat starts playing
+    pub frame: u64,
+    /// Index in the measure
+    pub beat: usize,
+    /// `None` for the count-in
+    pub sound_type: Option<SoundType>,
+}
+
+struct Click {
+    /// Frames since the start of the measure
+    offset: f64,
+    /// Index in the measure, `None` for the subdivisions
+    beat: Option<usize>,
+    sample: Sample,
+    /// Whose level applies, none for the count-in
+    sound_type: Option<SoundType>,
+    gain: f32,
+    pan: f32,
+}
+
+/// One layer's position in the timeline
+struct Track {
+    clicks: Vec<Click>,
+    length: f64,
+    /// Largest random offset of a click, in frames
+    humanize: f64,
+    /// Frame at which the current measure started
+    start: f64,
+    next: usize,
+    /// Measures left to play, forever if `None`
+    measures: Option<usize>,
+    /// Its beats are reported as ticks, the layers' aren't
+    ticks: bool,
+}
+
+impl Track {
+    /// Measures are at least a frame long, so rendering always moves on
+    fn new(measure: &Measure, bpm: u64, length: f64, volume: f32, sample_rate: u32) -> Self {
+        let length = length.max(1.0);
+        let scale = length / measure.duration(bpm).max(1) as f64;
+        let clicks = measure
+            .onsets(bpm)
+            .into_iter()
+            .filter_map(|onset| {
+                let sound = &measure.data[onset.beat].0[0];
+                if sound.hidden {
+                    return None;
+                }
+                let (sample, sound_type, gain) = match (onset.sub, sound.sound_type) {
+                    //TODO give mid its own sample
+                    (0, sound_type @ (SoundType::Up | SoundType::Mid)) => {
+                        (Sample::Up, sound_type, sound.volume_modifier)
+                    }
+                    (0, SoundType::Down) => (Sample::Down, SoundType::Down, sound.volume_modifier),
+                    _ => (Sample::Down, SoundType::Down, SUBDIVISION_VOLUME),
+                };
+                Some(Click {
+                    offset: onset.time * scale,
+                    beat: (onset.sub == 0).then_some(onset.beat),
+                    sample,
+                    sound_type: Some(sound_type),
+                    gain: gain * volume,
+                    pan: sound.pan,
+                })
+            })
+            .collect();
+        Track {
+            clicks,
+            length,
+            humanize: frames(
+                (measure.humanize.max(0.0) * 1_000_000.0) as u64,
+                sample_rate,
+            ),
+            start: 0.0,
+            next: 0,
+            measures: None,
+            ticks: false,
+        }
+    }
+
+    /// Every beat of `measure` with the count-in sound, without subdivisions or humanize
+    fn count_in(measure: &Measure, bpm: u64, measures: usize, sample_rate: u32) -> Self {
+        let length = frames(measure.duration(bpm), sample_rate);
+        let mut track = Track::new(measure, bpm, length, 1.0, sample_rate);
+        let beats = measure
+            .onsets(bpm)
+            .into_iter()
+            .filter(|onset| onset.sub == 0);
+        track.clicks = beats
+            .filter(|onset| !measure.data[onset.beat].0[0].hidden)
+            .map(|onset| Click {
+                offset: frames(onset.time as u64, sample_rate),
+                beat: Some(onset.beat),
+                sample: Sample::CountIn,
+                sound_type: None,
+                gain: COUNT_IN_VOLUME,
+                pan: measure.data[onset.beat].0[0].pan,
+            })
+            .collect();
+        track.humanize = 0.0;
+        track.measures = Some(measures);
+        track.ticks = true;
+        track
+    }
+
+    /// Carries on from `phase`, from 0.0 to 1.0, of the measure playing at frame `at`
+    fn seek(&mut self, at: f64, phase: f64) {
+        let position = phase * self.length;
+        self.start = at - position;
+        self.next = match self
+            .clicks
+            .iter()
+            .position(|click| click.offset >= position)
+        {
+            Some(next) => next,
+            None => {
+                self.start += self.length;
+                if let Some(measures) = &mut self.measures {
+                    *measures = measures.saturating_sub(1);
+                }
+                0
+            }
+        };
+    }
+}
+
+/// Uniformly distributed in `-1.0..1.0`, xorshift is plenty for moving clicks around
+fn random(seed: &mut u64) -> f64 {
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 7;
+    *seed ^= *seed << 17;
+    (*seed >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+}
+
+/// A sample that is still playing
+struct Voice {
+    sample: Arc<[f32]>,
+    position: usize,
+    /// Frames until it starts
+    delay: usize,
+    /// Of the left and the right channel
+    gains: [f32; 2],
+}
+
+/// Mixes the clicks of every layer, letting them ring across buffers
+pub struct Mixer {
+    sample_rate: u32,
+    up: Arc<[f32]>,
+    down: Arc<[f32]>,
+    count_in: Arc<[f32]>,
+    end: Arc<[f32]>,
+    /// The main measure first
+    tracks: Vec<Track>,
+    voices: Vec<Voice>,
+    /// Scheduled since the last `take_ticks()`
+    ticks: Vec<Tick>,
+    frame: u64,
+    /// Nothing is scheduled from these frames on, the first one follows the tempo and the second
+    /// one doesn't
+    stop_after_measures: Option<f64>,
+    stop_after_time: Option<f64>,
+    end_sound: bool,
+    routing: Routing,
+    levels: Levels,
+    /// State of the random number generator used by the humanize
+    seed: u64,
+}
+
+impl Mixer {
+    /// Mixes at `SAMPLE_RATE`
+    pub fn new(up: Arc<[f32]>, down: Arc<[f32]>) -> Self {
+        Self::with_sample_rate(up, down, SAMPLE_RATE)
+    }
+
+    /// The samples have to be decoded at `sample_rate` too
+    pub fn with_sample_rate(up: Arc<[f32]>, down: Arc<[f32]>, sample_rate: u32) -> Self {
+        Mixer {
+            sample_rate,
+            up,
+            down,
+            count_in: tone(1760.0, 40, sample_rate),
+            end: tone(880.0, 600, sample_rate),
+            tracks: Vec::new(),
+            voices: Vec::new(),
+            ticks: Vec::new(),
+            frame: 0,
+            stop_after_measures: None,
+            stop_after_time: None,
+            end_sound: false,
+            routing: Routing::default(),
+            levels: Levels::default(),
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    pub fn set_samples(&mut self, up: Arc<[f32]>, down: Arc<[f32]>) {
+        self.up = up;
+        self.down = down;
+    }
+
+    /// Applies to the clicks scheduled from now on
+    pub fn set_routing(&mut self, routing: Routing) {
+        self.routing = routing;
+    }
+
+    /// Applies to the clicks scheduled from now on
+    pub fn set_levels(&mut self, levels: Levels) {
+        self.levels = levels;
+    }
+
+    /// A measure longer than `MAX_DURATION` is squeezed into it, since `render_measure()` renders
+    /// all of it at once. The layers don't change the length of the renders.
+    fn tracks(&self, bpm: u64, measure: &Measure, layers: &[Layer]) -> Vec<Track> {
+        let sample_rate = self.sample_rate;
+        let length = frames(measure.duration(bpm).min(MAX_DURATION), sample_rate);
+        let mut main = Track::new(measure, bpm, length, 1.0, sample_rate);
+        main.ticks = true;
+        let mut tracks = vec![main];
+        tracks.extend(layers.iter().map(|layer| {
+            let layer_length = if layer.fit {
+                length
+            } else {
+                frames(layer.measure.duration(bpm), sample_rate)
+            };
+            Track::new(&layer.measure, bpm, layer_length, layer.volume, sample_rate)
+        }));
+        tracks
+    }
+
+    /// Starts every layer from its downbeat, after the count-in, and silences the clicks that are
+    /// still ringing
+    pub fn reset(&mut self, bpm: u64, measure: &Measure, layers: &[Layer], options: &StartOptions) {
+        self.tracks = self.tracks(bpm, measure, layers);
+        let count_in = self.tracks[0].length * options.count_in as f64;
+        for track in &mut self.tracks {
+            track.start = count_in;
+        }
+        if options.count_in > 0 {
+            self.tracks.push(Track::count_in(
+                measure,
+                bpm,
+                options.count_in,
+                self.sample_rate,
+            ));
+        }
+        self.stop_after_measures = options
+            .stop_after_bars
+            .filter(|bars| *bars > 0)
+            .map(|bars| count_in + self.tracks[0].length * bars as f64);
+        self.stop_after_time = options
+            .stop_after_minutes
+            .filter(|minutes| *minutes > 0.0)
+            .map(|minutes| count_in + minutes * 60.0 * self.sample_rate as f64);
+        self.end_sound = options.end_sound;
+        self.voices.clear();
+        self.ticks.clear();
+        self.frame = 0;
+    }
+
+    /// Continues rendering from `frame` with another tempo, every layer carries on from the same
+    /// position in its measure, or the count-in from the same position in what's left of it.
+    /// Whatever was rendered after `frame` has to be thrown away.
+    pub fn retime(&mut self, frame: u64, bpm: u64, measure: &Measure, layers: &[Layer]) {
+        let at = frame as f64;
+        let mut tracks = self.tracks(bpm, measure, layers);
+        let scale = tracks[0].length / self.tracks.first().map_or(1.0, |main| main.length);
+        // The end of the count-in and the length of its measures, if it isn't over yet
+        let count_in = self.tracks.iter().find_map(|track| {
+            let end = track.start + track.measures? as f64 * track.length;
+            (at < end).then_some((end, track.length))
+        });
+        for (track, old) in tracks.iter_mut().zip(&self.tracks) {
+            match count_in {
+                Some((end, _)) => track.start = at + (end - at) * scale,
+                None => track.seek(at, (at - old.start).rem_euclid(old.length) / old.length),
+            }
+        }
+        if let Some((end, length)) = count_in {
+            let left = (end - at) / length;
+            let mut track = Track::count_in(measure, bpm, left.ceil() as usize, self.sample_rate);
+            track.seek(at, left.ceil() - left);
+            tracks.push(track);
+        }
+        self.stop_after_measures = self
+            .stop_after_measures
+            .map(|stop| frame as f64 + (stop - frame as f64).max(0.0) * scale);
+        self.tracks = tracks;
+        self.voices.clear();
+        self.ticks.clear();
+        self.frame = frame;
+    }
+
+    /// Plays the measure from the current frame on, e.g. for the next section of a click track,
+    /// the clicks that are still ringing and the practice timer carry on
+    pub fn continue_with(&mut self, bpm: u64, measure: &Measure, layers: &[Layer]) {
+        let tracks = self.tracks(bpm, measure, layers);
+        let frame = self.frame as f64;
+        if let Some(main) = self.tracks.first() {
+            let scale = tracks[0].length / main.length;
+            self.stop_after_measures = self
+                .stop_after_measures
+                .map(|stop| frame + (stop - frame).max(0.0) * scale);
+        }
+        self.tracks = tracks;
+        for track in &mut self.tracks {
+            track.start = frame;
+        }
+    }
+
+    /// The beats scheduled by the renders since the last call, in the order they were scheduled
+    pub fn take_ticks(&mut self) -> Vec<Tick> {
+        std::mem::take(&mut self.ticks)
+    }
+
+    /// Frames rendered since the last reset
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// The count-in isn't over yet
+    pub fn counting_in(&self) -> bool {
+        self.tracks
+            .first()
+            .is_some_and(|main| (self.frame as f64) < main.start.round())
+    }
+
+    fn stop(&self) -> Option<f64> {
+        match (self.stop_after_measures, self.stop_after_time) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// The practice timer ran out and the last sound is done playing
+    pub fn finished(&self) -> bool {
+        self.stop()
+            .is_some_and(|stop| self.frame as f64 > stop && self.voices.is_empty())
+    }
+
+    /// Only the end sound, silencing everything else
+    pub fn render_end(&mut self) -> Vec<f32> {
+        self.voices.clear();
+        let gain = END_VOLUME * self.levels.gain(None);
+        let gains = self.routing.gains(Sample::End, 0.0);
+        let mut buffer: Vec<f32> = self
+            .end
+            .iter()
+            .enumerate()
+            .map(|(i, sample)| sample * gain * gains[i % CHANNELS as usize])
+            .collect();
+        self.limit(&mut buffer);
+        buffer
+    }
+
+    /// Renders up to the start of the next main measure, or of the next count-in measure
+    pub fn render_measure(&mut self) -> Vec<f32> {
+        let Some(main) = self.tracks.first() else {
+            return Vec::new();
+        };
+        let frame = self.frame as f64;
+        let measures = ((frame - main.start) / main.length).floor() + 1.0;
+        let mut end = (main.start + measures * main.length).round();
+        if end <= frame {
+            end += main.length.round();
+        }
+        self.render((end - frame) as usize)
+    }
+
+    /// Renders the next `frames` frames of every layer
+    pub fn render(&mut self, frames: usize) -> Vec<f32> {
+        let end = self.frame + frames as u64;
+        let stop = self.stop().unwrap_or(f64::INFINITY);
+        for track in &mut self.tracks {
+            if track.clicks.is_empty() {
+                while track.start + track.length <= end as f64 {
+                    track.start += track.length;
+                }
+                continue;
+            }
+            while track.measures != Some(0) {
+                let click = &track.clicks[track.next];
+                // Early enough that a click moved by the humanize is never scheduled too late
+                let at = track.start + click.offset;
+                if at - track.humanize >= end as f64 || at >= stop {
+                    break;
+                }
+                let at = if track.humanize > 0.0 {
+                    (at + track.humanize * random(&mut self.seed)).max(0.0)
+                } else {
+                    at
+                };
+                let at = at.round() as u64;
+                if let (true, Some(beat)) = (track.ticks, click.beat) {
+                    self.ticks.push(Tick {
+                        frame: at,
+                        beat,
+                        sound_type: click.sound_type,
+                    });
+                }
+                self.voices.push(Voice {
+                    sample: match click.sample {
+                        Sample::Up => self.up.clone(),
+                        Sample::Down => self.down.clone(),
+                        Sample::CountIn => self.count_in.clone(),
+                        Sample::End => self.end.clone(),
+                    },
+                    position: 0,
+                    delay: at.saturating_sub(self.frame) as usize,
+                    gains: self
+                        .routing
+                        .gains(click.sample, click.pan)
+                        .map(|gain| gain * click.gain * self.levels.gain(click.sound_type)),
+                });
+                track.next += 1;
+                if track.next == track.clicks.len() {
+                    track.next = 0;
+                    track.start += track.length;
+                    if let Some(measures) = &mut track.measures {
+                        *measures -= 1;
+                    }
+                }
+            }
+        }
+        if self.end_sound && (self.frame as f64..end as f64).contains(&stop) {
+            self.voices.push(Voice {
+                sample: self.end.clone(),
+                position: 0,
+                delay: (stop.round() as u64).saturating_sub(self.frame) as usize,
+                gains: self
+                    .routing
+                    .gains(Sample::End, 0.0)
+                    .map(|gain| gain * END_VOLUME * self.levels.gain(None)),
+            });
+        }
+        self.frame = end;
+
+        let channels = CHANNELS as usize;
+        let mut buffer = vec![0.0; frames * channels];
+        self.voices.retain_mut(|voice| {
+            let delay = voice.delay.min(frames);
+            voice.delay -= delay;
+            let out = &mut buffer[delay * channels..];
+            let remaining = &voice.sample[voice.position..];
+            let n = remaining.len().min(out.len());
+            for (i, (out, sample)) in out.iter_mut().zip(&remaining[..n]).enumerate() {
+                *out += sample * voice.gains[i % channels];
+            }
+            voice.position += n;
+            voice.position < voice.sample.len()
+        });
+        self.limit(&mut buffer);
+        buffer
+    }
+
+    fn limit(&self, buffer: &mut [f32]) {
+        if self.levels.limiter {
+            for sample in buffer {
+                *sample = limit(*sample);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Left and right of the frame at `frame`
+    fn frame(buffer: &[f32], frame: usize) -> [f32; 2] {
+        [buffer[frame * 2], buffer[frame * 2 + 1]]
+    }
+
+    #[test]
+    fn routing() {
+        // Beats 1000 frames apart, the second one panned to the right
+        let measure = "2/4: A m>".parse::<Measure>().unwrap();
+        let click: Arc<[f32]> = vec![1.0; 20].into();
+        for (routing, accent, beat) in [
+            (Routing::Stereo, [1.5, 1.5], [0.0, 1.0]),
+            (Routing::Split, [1.5, 0.0], [0.0, 1.0]),
+            (Routing::Left, [1.5, 0.0], [1.0, 0.0]),
+            (Routing::Right, [0.0, 1.5], [0.0, 1.0]),
+        ] {
+            let mut mixer = Mixer::with_sample_rate(click.clone(), click.clone(), 1000);
+            mixer.set_routing(routing);
+            mixer.set_levels(Levels {
+                limiter: false,
+                ..Levels::default()
+            });
+            mixer.reset(60, &measure, &[], &StartOptions::default());
+            let buffer = mixer.render_measure();
+            assert_eq!(frame(&buffer, 0), accent, "{routing:?}");
+            assert_eq!(frame(&buffer, 1000), beat, "{routing:?}");
+        }
+    }
+
+    #[test]
+    fn levels() {
+        let measure = "2/4: A m".parse::<Measure>().unwrap();
+        let click: Arc<[f32]> = vec![0.1; 20].into();
+        let mut mixer = Mixer::with_sample_rate(click.clone(), click, 1000);
+        mixer.set_levels(Levels {
+            master: -6.0,
+            down: 26.0,
+            limiter: false,
+            ..Levels::default()
+        });
+        mixer.reset(60, &measure, &[], &StartOptions::default());
+        let buffer = mixer.render_measure();
+        assert!((frame(&buffer, 0)[0] - 0.15 * decibels(-6.0)).abs() < 1e-6);
+        assert!((frame(&buffer, 1000)[0] - 1.0).abs() < 1e-3);
+
+        // Much louder than full scale, the limiter keeps it below while leaving quiet clicks be
+        mixer.set_levels(Levels {
+            up: -20.0,
+            down: 40.0,
+            ..Levels::default()
+        });
+        let buffer = mixer.render_measure();
+        assert!((frame(&buffer, 0)[0] - 0.015).abs() < 1e-6);
+        let beat = frame(&buffer, 1000)[0];
+        assert!(beat > LIMITER_THRESHOLD && beat <= 1.0, "{beat}");
+    }
+
+    /// Frames of the ticks scheduled since the last call, and whether they're from the count-in
+    fn ticks(mixer: &mut Mixer) -> Vec<(u64, bool)> {
+        let mut ticks = mixer
+            .take_ticks()
+            .iter()
+            .map(|tick| (tick.frame, tick.sound_type.is_none()))
+            .collect::<Vec<_>>();
+        ticks.sort();
+        ticks
+    }
+
+    fn mixer() -> Mixer {
+        let click: Arc<[f32]> = vec![1.0; 20].into();
+        Mixer::with_sample_rate(click.clone(), click, 1000)
+    }
+
+    #[test]
+    fn count_in() {
+        let measure = "2/4: A m".parse::<Measure>().unwrap();
+        let mut mixer = mixer();
+        let options = StartOptions {
+            count_in: 2,
+            ..StartOptions::default()
+        };
+        mixer.reset(60, &measure, &[], &options);
+        assert!(mixer.counting_in());
+        mixer.render(5000);
+        assert!(!mixer.counting_in());
+        assert_eq!(
+            ticks(&mut mixer),
+            [
+                (0, true),
+                (1000, true),
+                (2000, true),
+                (3000, true),
+                (4000, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn stop_after() {
+        let measure = "2/4: A m".parse::<Measure>().unwrap();
+        let mut mixer = mixer();
+        let options = StartOptions {
+            count_in: 1,
+            stop_after_bars: Some(2),
+            stop_after_minutes: Some(1.0),
+            ..StartOptions::default()
+        };
+        mixer.reset(60, &measure, &[], &options);
+        mixer.render(8000);
+        assert_eq!(
+            ticks(&mut mixer),
+            [
+                (0, true),
+                (1000, true),
+                (2000, false),
+                (3000, false),
+                (4000, false),
+                (5000, false)
+            ]
+        );
+        assert!(mixer.finished());
+
+        // 3 seconds, the end sound starts when the time is up
+        let options = StartOptions {
+            stop_after_minutes: Some(0.05),
+            end_sound: true,
+            ..StartOptions::default()
+        };
+        mixer.reset(60, &measure, &[], &options);
+        let buffer = mixer.render(4000);
+        assert_eq!(
+            ticks(&mut mixer),
+            [(0, false), (1000, false), (2000, false)]
+        );
+        assert!(buffer[2100 * 2..3000 * 2]
+            .iter()
+            .all(|sample| *sample == 0.0));
+        assert!(buffer[3000 * 2..3100 * 2]
+            .iter()
+            .any(|sample| *sample != 0.0));
+
+        // Neither is a limit
+        let options = StartOptions {
+            stop_after_bars: Some(0),
+            stop_after_minutes: Some(-1.0),
+            ..StartOptions::default()
+        };
+        mixer.reset(60, &measure, &[], &options);
+        mixer.render(3000);
+        assert_eq!(ticks(&mut mixer).len(), 3);
+        assert!(!mixer.finished());
+    }
+
+    #[test]
+    fn retime() {
+        let measure = "2/4: A m".parse::<Measure>().unwrap();
+        let mut mixer = mixer();
+        mixer.reset(60, &measure, &[], &StartOptions::default());
+        mixer.render(1500);
+        assert_eq!(ticks(&mut mixer), [(0, false), (1000, false)]);
+        // Three quarters into the measure at twice the tempo
+        mixer.retime(1500, 120, &measure, &[]);
+        mixer.render(1000);
+        assert_eq!(ticks(&mut mixer), [(1750, false), (2250, false)]);
+
+        // The measures left to play follow the tempo
+        let options = StartOptions {
+            stop_after_bars: Some(1),
+            ..StartOptions::default()
+        };
+        mixer.reset(60, &measure, &[], &options);
+        mixer.render(1000);
+        ticks(&mut mixer);
+        mixer.retime(1000, 120, &measure, &[]);
+        mixer.render(2000);
+        assert_eq!(ticks(&mut mixer), [(1000, false)]);
+    }
+
+    #[test]
+    fn retime_during_the_count_in() {
+        let measure = "2/4: A m".parse::<Measure>().unwrap();
+        let mut mixer = mixer();
+        let options = StartOptions {
+            count_in: 2,
+            ..StartOptions::default()
+        };
+        mixer.reset(60, &measure, &[], &options);
+        mixer.render(1500);
+        assert_eq!(ticks(&mut mixer), [(0, true), (1000, true)]);
+        // 1.25 measures of count-in left, at twice the tempo
+        mixer.retime(1500, 120, &measure, &[]);
+        assert!(mixer.counting_in());
+        mixer.render(2500);
+        assert_eq!(
+            ticks(&mut mixer),
+            [
+                (1750, true),
+                (2250, true),
+                (2750, false),
+                (3250, false),
+                (3750, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn measures_shorter_than_a_frame() {
+        // A sixteenth at 1000 BPM is 0.15 frames long, and a measure without beats none
+        let short = "1/16: A".parse::<Measure>().unwrap();
+        let empty = Measure {
+            data: Vec::new(),
+            ..Measure::default()
+        };
+        let click: Arc<[f32]> = vec![1.0; 2].into();
+        let mut mixer = Mixer::with_sample_rate(click.clone(), click, 10);
+        for (measure, layer) in [(&short, &empty), (&empty, &short)] {
+            let layers = [Layer::new(layer.clone())];
+            mixer.reset(1000, measure, &layers, &StartOptions::default());
+            assert_eq!(mixer.render_measure().len(), 2);
+            assert_eq!(mixer.render(100).len(), 200);
+            mixer.retime(50, 500, measure, &layers);
+            assert_eq!(mixer.render(100).len(), 200);
+        }
+    }
+
+    #[test]
+    fn measures_longer_than_the_maximum() {
+        // An hour long at 1 BPM
+        let long = "30/2:".parse::<Measure>().unwrap();
+        assert!(long.too_long(1));
+        let click: Arc<[f32]> = vec![1.0; 2].into();
+        let mut mixer = Mixer::with_sample_rate(click.clone(), click, 10);
+        mixer.reset(1, &long, &[], &StartOptions::default());
+        let frames = frames(MAX_DURATION, 10) as usize;
+        assert_eq!(mixer.render_measure().len(), frames * CHANNELS as usize);
+        assert_eq!(mixer.take_ticks().len(), 30);
+        assert!(!long.too_long(long.slowest_bpm()));
+        assert!(long.too_long(long.slowest_bpm() - 1));
+    }
+}
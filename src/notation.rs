@@ -0,0 +1,464 @@
+//! Compact text notation for measures, e.g. `4/4: A m m m | sub=eights` or `7/8: Amm Mm Mm`
+//!
+//! Every symbol is one beat: `A` accent, `M` medium accent, `m` normal beat, `g` ghost beat and
+//! `-` a silent beat. Beats written together form a group, so `Amm Mm Mm` is grouped 3+2+2, and
+//! when every beat stands alone the time signature's usual grouping applies. Options follow the
+//! beats after `|`, `sub` takes the names accepted by `Rhythm::from_str` and `group` the beat
+//! grouping, e.g. `group=3+2+2`, which wins over the written one. Without beats the accents
+//! follow the grouping, so `7/8: | group=3+2+2` works.
+//! `swing` is the swing in percent, from 50 (straight) to 75 (dotted), and `humanize` the
+//! largest random offset of every click in milliseconds.
+//!
+//! A beat followed by `<` or `>` is panned to the left or the right, e.g. `4/4: A< m> m> m>`.
+
+use std::{fmt::Display, str::FromStr};
+
+use crate::{
+    measure::{self, Beat, Measure, Sound, SoundType, TimeSignature, ACCENT_VOLUME, GROUP_VOLUME},
+    rhythm::Rhythm,
+};
+
+const NORMAL_VOLUME: f32 = 1.0;
+const GHOST_VOLUME: f32 = 0.4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotationError {
+    /// Character offset of the error in the notation
+    pub position: usize,
+    pub message: String,
+}
+
+impl NotationError {
+    fn new(position: usize, message: impl Into<String>) -> Self {
+        NotationError {
+            position,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for NotationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "at {}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for NotationError {}
+
+fn sound(symbol: char, duration: Rhythm) -> Option<Sound> {
+    let (sound_type, volume_modifier, hidden) = match symbol {
+        'A' => (SoundType::Up, ACCENT_VOLUME, false),
+        'M' => (SoundType::Mid, GROUP_VOLUME, false),
+        'm' => (SoundType::Down, NORMAL_VOLUME, false),
+        'g' => (SoundType::Down, GHOST_VOLUME, false),
+        '-' => (SoundType::Down, NORMAL_VOLUME, true),
+        _ => return None,
+    };
+    Some(Sound {
+        sound_type,
+        duration,
+        volume_modifier,
+        hidden,
+        pan: 0.0,
+    })
+}
+
+fn symbol(sound: &Sound) -> String {
+    let symbol = match sound.sound_type {
+        _ if sound.hidden => '-',
+        SoundType::Up => 'A',
+        SoundType::Mid => 'M',
+        SoundType::Down if sound.volume_modifier < NORMAL_VOLUME => 'g',
+        SoundType::Down => 'm',
+    };
+    // Only hard panning has a symbol
+    match sound.pan {
+        pan if pan <= -1.0 => format!("{symbol}<"),
+        pan if pan >= 1.0 => format!("{symbol}>"),
+        _ => symbol.to_string(),
+    }
+}
+
+/// Splits `s` on `separator`, keeping the character offset of every part
+fn split(s: &str, offset: usize, separator: char) -> Vec<(usize, &str)> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut position = offset;
+    for (i, c) in s.char_indices() {
+        if c == separator {
+            parts.push((position - s[start..i].chars().count(), &s[start..i]));
+            start = i + c.len_utf8();
+        }
+        position += 1;
+    }
+    parts.push((position - s[start..].chars().count(), &s[start..]));
+    parts
+}
+
+/// Offset and content of `s` without the surrounding whitespace
+fn trim(offset: usize, s: &str) -> (usize, &str) {
+    let leading = s.chars().take_while(|c| c.is_whitespace()).count();
+    (offset + leading, s.trim())
+}
+
+fn parse_number(offset: usize, s: &str, what: &str) -> Result<usize, NotationError> {
+    let (offset, s) = trim(offset, s);
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(NotationError::new(
+            offset,
+            format!("expected the {what}, found `{s}`"),
+        )),
+    }
+}
+
+fn parse_float(offset: usize, s: &str, what: &str) -> Result<f64, NotationError> {
+    let (offset, s) = trim(offset, s);
+    s.parse::<f64>()
+        .map_err(|_| NotationError::new(offset, format!("expected the {what}, found `{s}`")))
+}
+
+pub fn parse(notation: &str) -> Result<Measure, NotationError> {
+    let Some(colon) = notation.find(':') else {
+        return Err(NotationError::new(
+            notation.chars().count(),
+            "expected `:` after the time signature",
+        ));
+    };
+    let signature = &notation[..colon];
+    let Some(slash) = signature.find('/') else {
+        return Err(NotationError::new(
+            0,
+            "expected a time signature like `4/4`",
+        ));
+    };
+    let slash_position = signature[..slash].chars().count();
+    let beats_per_measure = parse_number(0, &signature[..slash], "number of beats")?;
+    let beat_value = parse_number(slash_position + 1, &signature[slash + 1..], "beat value")?;
+    if !measure::NUMERATOR_RANGE.contains(&beats_per_measure) {
+        return Err(NotationError::new(
+            0,
+            format!(
+                "the number of beats must be in {:?}",
+                measure::NUMERATOR_RANGE
+            ),
+        ));
+    }
+    let Some(time_signature) = TimeSignature::new(beats_per_measure, beat_value) else {
+        return Err(NotationError::new(
+            slash_position + 1,
+            "the beat value must be 2, 4, 8 or 16",
+        ));
+    };
+
+    let body_position = signature.chars().count() + 1;
+    let mut parts = split(&notation[colon + 1..], body_position, '|').into_iter();
+    let (beats_position, beats) = parts.next().unwrap_or((body_position, ""));
+
+    let mut data = Vec::with_capacity(beats_per_measure);
+    // Beats per word
+    let mut words: Vec<usize> = Vec::new();
+    let mut new_word = true;
+    for (i, c) in beats.chars().enumerate() {
+        if c.is_whitespace() {
+            new_word = true;
+            continue;
+        }
+        if c == '<' || c == '>' {
+            let Some(Beat(sounds)) = data.last_mut() else {
+                return Err(NotationError::new(
+                    beats_position + i,
+                    format!("`{c}` has to follow a beat"),
+                ));
+            };
+            sounds[0].pan = if c == '<' { -1.0 } else { 1.0 };
+            continue;
+        }
+        let Some(sound) = sound(c, time_signature.beat()) else {
+            return Err(NotationError::new(
+                beats_position + i,
+                format!("unknown beat `{c}`, expected one of `A`, `M`, `m`, `g` or `-`"),
+            ));
+        };
+        if data.len() == beats_per_measure {
+            return Err(NotationError::new(
+                beats_position + i,
+                format!("too many beats, the measure has {beats_per_measure}"),
+            ));
+        }
+        data.push(Beat(vec![sound]));
+        match words.last_mut() {
+            Some(len) if !new_word => *len += 1,
+            _ => words.push(1),
+        }
+        new_word = false;
+    }
+    if !data.is_empty() && data.len() != beats_per_measure {
+        return Err(NotationError::new(
+            beats_position + beats.chars().count(),
+            format!("expected {beats_per_measure} beats, found {}", data.len()),
+        ));
+    }
+
+    let mut subdivision = Rhythm::Quarter;
+    let mut swing = measure::STRAIGHT;
+    let mut humanize = 0.0;
+    let mut grouping = if words.iter().any(|&len| len > 1) {
+        words
+    } else {
+        time_signature.default_grouping()
+    };
+    for (option_position, option) in parts {
+        let (option_position, option) = trim(option_position, option);
+        let Some((key, value)) = option.split_once('=') else {
+            return Err(NotationError::new(
+                option_position,
+                format!("expected an option like `sub=eights`, found `{option}`"),
+            ));
+        };
+        let value_position = option_position + key.chars().count() + 1;
+        match key.trim() {
+            "sub" => {
+                let (value_position, value) = trim(value_position, value);
+                subdivision = Rhythm::from_str(value).map_err(|()| {
+                    NotationError::new(value_position, format!("unknown subdivision `{value}`"))
+                })?;
+            }
+            "swing" => {
+                swing = parse_float(value_position, value, "swing")? / 100.0;
+                if !measure::SWING_RANGE.contains(&swing) {
+                    return Err(NotationError::new(
+                        value_position,
+                        "the swing must be between 50 and 75",
+                    ));
+                }
+            }
+            "humanize" => {
+                humanize = parse_float(value_position, value, "humanize")?;
+                if !measure::HUMANIZE_RANGE.contains(&humanize) {
+                    return Err(NotationError::new(
+                        value_position,
+                        format!("the humanize must be in {:?}", measure::HUMANIZE_RANGE),
+                    ));
+                }
+            }
+            "group" => {
+                grouping = split(value, value_position, '+')
+                    .into_iter()
+                    .map(|(position, n)| parse_number(position, n, "group size"))
+                    .collect::<Result<_, _>>()?;
+                if !time_signature.fits(&grouping) {
+                    return Err(NotationError::new(
+                        value_position,
+                        format!("the groups must add up to {beats_per_measure} beats"),
+                    ));
+                }
+            }
+            key => {
+                return Err(NotationError::new(
+                    option_position,
+                    format!("unknown option `{key}`"),
+                ))
+            }
+        }
+    }
+
+    // The grouping was checked above
+    let mut measure = Measure::with_grouping(time_signature, grouping).unwrap();
+    if !data.is_empty() {
+        measure.data = data;
+    }
+    measure.subdivision = subdivision;
+    measure.swing = swing;
+    measure.humanize = humanize;
+    Ok(measure)
+}
+
+/// Beats in the same group are written together when the measure has more than one group
+pub fn format(measure: &Measure) -> String {
+    let symbols = measure
+        .data
+        .iter()
+        .map(|beat| beat.0.first().map_or(String::from("-"), symbol))
+        .collect::<Vec<String>>();
+    let beats = if measure.grouping.len() > 1 {
+        let mut groups = Vec::with_capacity(measure.grouping.len());
+        let mut start = 0;
+        for len in &measure.grouping {
+            let end = (start + len).min(symbols.len());
+            groups.push(symbols[start..end].concat());
+            start = end;
+        }
+        groups.join(" ")
+    } else {
+        symbols.join(" ")
+    };
+    let mut notation = format!("{}: {beats}", measure.time_signature);
+    if measure.grouping != measure.time_signature.default_grouping() {
+        let grouping = measure
+            .grouping
+            .iter()
+            .map(usize::to_string)
+            .collect::<Vec<String>>()
+            .join("+");
+        notation.push_str(&format!(" | group={grouping}"));
+    }
+    if measure.subdivision != Rhythm::Quarter {
+        notation.push_str(&format!(" | sub={}", measure.subdivision.name()));
+    }
+    if measure.swing != measure::STRAIGHT {
+        notation.push_str(&format!(
+            " | swing={}",
+            (measure.swing * 1000.0).round() / 10.0
+        ));
+    }
+    if measure.humanize != 0.0 {
+        notation.push_str(&format!(" | humanize={}", measure.humanize));
+    }
+    notation
+}
+
+impl FromStr for Measure {
+    type Err = NotationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s)
+    }
+}
+
+impl Display for Measure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUBDIVISIONS: [&str; 8] = [
+        "halves",
+        "eights",
+        "triplet_eights",
+        "triplet_quarter_eights",
+        "triplet_eighth_quarters",
+        "sixteenths",
+        "eighth_sixteenths",
+        "sixteen_eights",
+    ];
+
+    fn round_trip(notation: &str) {
+        let measure = parse(notation).unwrap();
+        assert_eq!(format(&measure), notation);
+        let again = parse(&format(&measure)).unwrap();
+        assert_eq!(again.time_signature, measure.time_signature);
+        assert_eq!(again.grouping, measure.grouping);
+        assert_eq!(again.subdivision, measure.subdivision);
+        assert_eq!(again.swing, measure.swing);
+        assert_eq!(again.humanize, measure.humanize);
+        assert_eq!(format(&again), notation);
+    }
+
+    fn position(notation: &str) -> usize {
+        parse(notation).unwrap_err().position
+    }
+
+    #[test]
+    fn every_symbol() {
+        round_trip("4/4: A M m g");
+        round_trip("3/4: A - m");
+        round_trip("4/4: A< m> -> g<");
+    }
+
+    #[test]
+    fn symbols_become_sounds() {
+        let measure = parse("5/4: A M m g -").unwrap();
+        let sounds = measure
+            .data
+            .iter()
+            .map(|beat| &beat.0[0])
+            .collect::<Vec<_>>();
+        assert_eq!(sounds[0].sound_type, SoundType::Up);
+        assert_eq!(sounds[1].sound_type, SoundType::Mid);
+        assert_eq!(sounds[2].volume_modifier, NORMAL_VOLUME);
+        assert_eq!(sounds[3].volume_modifier, GHOST_VOLUME);
+        assert!(sounds[4].hidden);
+        assert!(sounds.iter().all(|sound| sound.duration == Rhythm::Quarter));
+    }
+
+    #[test]
+    fn every_option() {
+        for sub in SUBDIVISIONS {
+            round_trip(&format!("4/4: A m m m | sub={sub}"));
+        }
+        round_trip("4/4: A m m m | swing=60");
+        round_trip("4/4: A m m m | swing=66.7");
+        round_trip("4/4: A m m m | humanize=12.5");
+        round_trip("7/8: Amm Mm Mm | group=3+2+2 | sub=eights | swing=60 | humanize=5");
+    }
+
+    #[test]
+    fn every_grouping() {
+        round_trip("7/8: Am Mm Mmm");
+        round_trip("6/8: Amm Mmm");
+        round_trip("7/8: Amm Mm Mm | group=3+2+2");
+        round_trip("9/8: Am Mm Mm Mmm | group=2+2+2+3");
+        round_trip("5/4: Amm Mm | group=3+2");
+    }
+
+    #[test]
+    fn written_groups() {
+        let measure = parse("7/8: Amm Mm Mm").unwrap();
+        assert_eq!(measure.grouping, [3, 2, 2]);
+        assert_eq!(format(&measure), "7/8: Amm Mm Mm | group=3+2+2");
+        assert_eq!(parse("7/8: A m m M m M m").unwrap().grouping, [2, 2, 3]);
+        assert_eq!(parse("6/8: AmmMmm").unwrap().grouping, [6]);
+        assert_eq!(
+            parse("7/8: Amm Mm Mm | group=2+2+3").unwrap().grouping,
+            [2, 2, 3]
+        );
+    }
+
+    #[test]
+    fn normalizes_whitespace() {
+        assert_eq!(format(&parse("4/4:AMmg").unwrap()), "4/4: A M m g");
+        assert_eq!(
+            format(&parse(" 7/8 :  | group = 3+2+2 ").unwrap()),
+            "7/8: Amm Mm Mm | group=3+2+2"
+        );
+    }
+
+    #[test]
+    fn bad_time_signatures() {
+        assert_eq!(position("4/4 A m m m"), 11);
+        assert_eq!(position("4 : A"), 0);
+        assert_eq!(position("x/4: A"), 0);
+        assert_eq!(position("4/y: A"), 2);
+        assert_eq!(position("12/0: A"), 3);
+        assert_eq!(position("4/3: A m m m"), 2);
+        assert_eq!(position("33/4: A"), 0);
+        assert_eq!(position("18446744073709551615/4: A"), 0);
+        assert_eq!(position("1000000000/4:"), 0);
+    }
+
+    #[test]
+    fn bad_beats() {
+        assert_eq!(position("4/4: A m x m"), 9);
+        assert_eq!(position("4/4: < A m m"), 5);
+        assert_eq!(position("4/4: A m m m m"), 13);
+        assert_eq!(position("4/4: A m m"), 10);
+    }
+
+    #[test]
+    fn bad_options() {
+        assert_eq!(position("4/4: A m m m | foo=1"), 15);
+        assert_eq!(position("4/4: A m m m | sub"), 15);
+        assert_eq!(position("4/4: A m m m | sub=nope"), 19);
+        assert_eq!(position("4/4: A m m m | swing=90"), 21);
+        assert_eq!(position("4/4: A m m m | swing=abc"), 21);
+        assert_eq!(position("4/4: A m m m | humanize=-1"), 24);
+        assert_eq!(position("7/8: | group=3+3"), 13);
+        assert_eq!(position("2/4: | group=18446744073709551615+3"), 13);
+        assert_eq!(position("7/8: | group=3+x+2"), 15);
+    }
+}
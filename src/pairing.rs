@@ -0,0 +1,308 @@
+//! Optional pairing of the clients allowed to use the REST API, e.g. on a shared venue Wi-Fi.
+//! The host shows a short code, which a client trades for a token to present from then on.
+//! There is a code per role, so only the clients given the admin code can change anything.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use rand::{distr::Alphanumeric, Rng};
+
+const CODE_DIGITS: usize = 6;
+const TOKEN_LENGTH: usize = 32;
+/// Wrong codes in a row from an address before it's refused for a while, so the code can't be
+/// guessed. The other addresses can still pair meanwhile.
+const MAX_FAILURES: u32 = 5;
+const LOCKOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum PairingError {
+    WrongCode,
+    /// Too many wrong codes were tried from this address
+    LockedOut,
+}
+
+impl Display for PairingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PairingError::WrongCode => write!(f, "wrong pairing code"),
+            PairingError::LockedOut => write!(f, "too many wrong pairing codes, try again later"),
+        }
+    }
+}
+
+impl std::error::Error for PairingError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Can change everything, e.g. the drummer or the musical director
+    Admin,
+    /// Can only follow the status and the beats
+    Viewer,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Viewer => "viewer",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub id: u64,
+    /// Given by the client, e.g. the drummer's phone
+    pub name: String,
+    pub role: Role,
+    pub paired: SystemTime,
+    token: String,
+}
+
+/// The wrong codes tried from an address
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct Clients {
+    clients: Vec<Client>,
+    next_id: u64,
+    failures: HashMap<IpAddr, Failures>,
+}
+
+/// The paired clients, they're forgotten when the server stops
+#[derive(Debug)]
+pub struct Pairing {
+    admin_code: String,
+    viewer_code: String,
+    /// The `GET` endpoints don't need a token
+    open_reads: bool,
+    /// Requests from this machine are admins without a token
+    trust_localhost: bool,
+    clients: Mutex<Clients>,
+}
+
+/// Compares every byte so the time taken doesn't tell how much of a token was right
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The failures are counted per IPv6 /64, which a single client usually gets whole
+fn source(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX))),
+        ip => ip,
+    }
+}
+
+impl Pairing {
+    /// With random codes. With `trust_localhost`, requests from this machine don't need a token,
+    /// which also lets in everyone behind a reverse proxy running on it.
+    pub fn new(open_reads: bool, trust_localhost: bool) -> Self {
+        let mut rng = rand::rng();
+        let mut code = || -> String {
+            (0..CODE_DIGITS)
+                .map(|_| char::from(b'0' + rng.random_range(0..10)))
+                .collect()
+        };
+        let admin_code = code();
+        let mut viewer_code = code();
+        while viewer_code == admin_code {
+            viewer_code = code();
+        }
+        Pairing {
+            admin_code,
+            viewer_code,
+            open_reads,
+            trust_localhost,
+            clients: Mutex::new(Clients::default()),
+        }
+    }
+
+    /// To show on the host, a client paired with it gets this role
+    pub fn code(&self, role: Role) -> &str {
+        match role {
+            Role::Admin => &self.admin_code,
+            Role::Viewer => &self.viewer_code,
+        }
+    }
+
+    pub fn open_reads(&self) -> bool {
+        self.open_reads
+    }
+
+    pub fn trust_localhost(&self) -> bool {
+        self.trust_localhost
+    }
+
+    /// Returns the new client's id, token and role. `from` is the client's address, which is
+    /// refused for a while after too many wrong codes.
+    pub fn pair(
+        &self,
+        code: &str,
+        name: &str,
+        from: IpAddr,
+    ) -> Result<(u64, String, Role), PairingError> {
+        let from = source(from);
+        let mut clients = self.clients.lock().unwrap();
+        let now = Instant::now();
+        // Forgets the addresses which stopped trying, so they can't fill the memory
+        clients
+            .failures
+            .retain(|_, failures| match failures.locked_until {
+                Some(until) => now < until,
+                None => now < failures.last + LOCKOUT,
+            });
+        if clients
+            .failures
+            .get(&from)
+            .is_some_and(|failures| failures.locked_until.is_some())
+        {
+            return Err(PairingError::LockedOut);
+        }
+        // Both are compared so the time taken doesn't tell which one was tried
+        let code = code.trim();
+        let (admin, viewer) = (same(code, &self.admin_code), same(code, &self.viewer_code));
+        let role = if admin {
+            Role::Admin
+        } else if viewer {
+            Role::Viewer
+        } else {
+            let failures = clients.failures.entry(from).or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+            failures.count += 1;
+            failures.last = now;
+            if failures.count >= MAX_FAILURES {
+                failures.locked_until = Some(now + LOCKOUT);
+            }
+            return Err(PairingError::WrongCode);
+        };
+        clients.failures.remove(&from);
+        let token: String = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LENGTH)
+            .map(char::from)
+            .collect();
+        let id = clients.next_id;
+        clients.next_id += 1;
+        clients.clients.push(Client {
+            id,
+            name: name.to_string(),
+            role,
+            paired: SystemTime::now(),
+            token: token.clone(),
+        });
+        Ok((id, token, role))
+    }
+
+    /// The client with this token, `None` if it was never paired or was revoked
+    pub fn client(&self, token: &str) -> Option<Client> {
+        let clients = self.clients.lock().unwrap();
+        clients
+            .clients
+            .iter()
+            .find(|client| same(&client.token, token))
+            .cloned()
+    }
+
+    pub fn clients(&self) -> Vec<Client> {
+        self.clients.lock().unwrap().clients.clone()
+    }
+
+    /// Its token isn't accepted anymore, `false` if there is no client with this id
+    pub fn revoke(&self, id: u64) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        let count = clients.clients.len();
+        clients.clients.retain(|client| client.id != id);
+        clients.clients.len() < count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHONE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 20));
+    const LAPTOP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 21));
+
+    /// Longer than the codes, so it's never right
+    const WRONG: &str = "0000000";
+
+    #[test]
+    fn roles() {
+        let pairing = Pairing::new(false, false);
+        let admin = pairing.code(Role::Admin).to_string();
+        let viewer = pairing.code(Role::Viewer).to_string();
+        let (_, token, role) = pairing.pair(&admin, "phone", PHONE).unwrap();
+        assert_eq!(role, Role::Admin);
+        assert_eq!(pairing.client(&token).unwrap().role, Role::Admin);
+        let (_, _, role) = pairing
+            .pair(&format!(" {viewer} "), "laptop", LAPTOP)
+            .unwrap();
+        assert_eq!(role, Role::Viewer);
+        assert!(pairing.client("nope").is_none());
+    }
+
+    #[test]
+    fn locks_out_the_guessing_address() {
+        let pairing = Pairing::new(false, false);
+        let admin = pairing.code(Role::Admin).to_string();
+        for _ in 0..MAX_FAILURES {
+            assert!(matches!(
+                pairing.pair(WRONG, "phone", PHONE),
+                Err(PairingError::WrongCode)
+            ));
+        }
+        // Even with the right code
+        assert!(matches!(
+            pairing.pair(&admin, "phone", PHONE),
+            Err(PairingError::LockedOut)
+        ));
+        assert!(pairing.pair(&admin, "laptop", LAPTOP).is_ok());
+    }
+
+    #[test]
+    fn right_codes_reset_the_failures() {
+        let pairing = Pairing::new(false, false);
+        let admin = pairing.code(Role::Admin).to_string();
+        for _ in 0..2 {
+            for _ in 1..MAX_FAILURES {
+                assert!(pairing.pair(WRONG, "phone", PHONE).is_err());
+            }
+            assert!(pairing.pair(&admin, "phone", PHONE).is_ok());
+        }
+    }
+
+    #[test]
+    fn ipv6_clients_are_counted_per_prefix() {
+        let pairing = Pairing::new(false, false);
+        let admin = pairing.code(Role::Admin).to_string();
+        for i in 0..MAX_FAILURES {
+            let from = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, i as u16));
+            assert!(pairing.pair(WRONG, "phone", from).is_err());
+        }
+        let other = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, 0xff));
+        assert!(matches!(
+            pairing.pair(&admin, "phone", other),
+            Err(PairingError::LockedOut)
+        ));
+        let elsewhere = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 2, 0, 0, 0, 1));
+        assert!(pairing.pair(&admin, "laptop", elsewhere).is_ok());
+    }
+}
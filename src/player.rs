@@ -1,57 +1,144 @@
-use crate::measure::{Beat, Measure, Sound, SoundType};
+use crate::backend::ClickSink;
+use crate::measure::Measure;
+use crate::mixer::{self, Layer, Levels, Mixer, Routing, Tick};
+use crate::render::Section;
 use crate::rhythm::Rhythm;
-use rodio::source::Zero;
-use rodio::{Decoder, Source};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::fmt::Debug;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+use std::{fs, io};
+use tokio::sync::Notify;
 
-pub struct RodioPlayer {
+pub const UP: &[u8] = include_bytes!("../assets/up.wav");
+pub const DOWN: &[u8] = include_bytes!("../assets/down.wav");
+/// Tempos accepted from the REST API, the command line, MIDI and tapping
+pub const BPM_RANGE: RangeInclusive<u64> = 1..=1000;
+/// Measures kept on the sink, the one being played and the next, so changes are heard from the
+/// next measure on
+pub const QUEUED_MEASURES: usize = 2;
+
+/// How to start playing, the default starts right away and plays until stopped
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StartOptions {
+    /// Measures of count-in, only the beats are played with their own sound
+    #[serde(default)]
+    pub count_in: usize,
+    /// Stops after this many measures, not counting the count-in, ignored if 0
+    #[serde(default)]
+    pub stop_after_bars: Option<u64>,
+    /// Stops after this many minutes, whichever comes first with `stop_after_bars`, ignored
+    /// unless positive
+    #[serde(default)]
+    pub stop_after_minutes: Option<f64>,
+    /// Plays the end sound when stopping on its own
+    #[serde(default)]
+    pub end_sound: bool,
+}
+
+/// Sample files to play, `None` is the bundled sample
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SampleSelection {
+    pub up: Option<PathBuf>,
+    pub down: Option<PathBuf>,
+}
+
+impl SampleSelection {
+    pub fn load(&self) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let read = |path: &Option<PathBuf>, default: &[u8]| match path {
+            Some(path) => fs::read(path),
+            None => Ok(default.to_vec()),
+        };
+        Ok((read(&self.up, UP)?, read(&self.down, DOWN)?))
+    }
+}
+
+pub struct Player {
     //TODO we probably don't need this to be arc/atomic anymore?
     bpm: Arc<AtomicU64>,
     // beats_per_measure: usize,
     measure: Measure,
     // stream: rodio::OutputStream,
     // stream_handle: rodio::OutputStreamHandle,
-    pub sink: rodio::Sink,
-    up: Vec<u8>,
-    down: Vec<u8>,
+    sink: Box<dyn ClickSink>,
+    layers: Vec<Layer>,
+    routing: Routing,
+    levels: Levels,
+    /// Played instead of the measure when it isn't empty, e.g. an imported tempo map
+    arrangement: Vec<Section>,
+    /// Section and measure of the arrangement to queue next
+    position: (usize, u64),
+    mixer: Mixer,
+    /// First frame of every buffer still in the sink
+    queued: VecDeque<u64>,
+    /// Beats queued on the sink that may not be heard yet
+    ticks: VecDeque<Tick>,
+    /// Notified whenever beats are queued
+    beats_queued: Arc<Notify>,
+    /// Between playing a frame and hearing it, e.g. of a Bluetooth speaker
+    latency: Duration,
+    options: StartOptions,
+    samples: SampleSelection,
     playing: bool,
+    /// Plays silence instead of the click, the beats still go by
+    silent: bool,
 }
 
-impl Debug for RodioPlayer {
+impl Debug for Player {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("RodioPlayer")
+        f.debug_struct("Player")
             .field("bpm", &self.bpm)
             .field("measure", &self.measure)
+            .field("layers", &self.layers)
+            .field("arrangement", &self.arrangement.len())
+            .field("samples", &self.samples)
             .field("playing", &self.playing)
             .finish()
     }
 }
 
-impl RodioPlayer {
-    pub fn new(
-        up: Vec<u8>,
-        down: Vec<u8>,
-    ) -> (Self, rodio::OutputStream, rodio::OutputStreamHandle) {
-        let (stream, stream_handle) = rodio::OutputStream::try_default().unwrap();
+impl Player {
+    /// Plays the bundled samples on `sink`, e.g. a `RodioSink` on the default output device
+    pub fn new(sink: impl ClickSink + 'static) -> Self {
         let bpm = Arc::new(AtomicU64::new(120));
         // let bpm2 = bpm.clone();
-        let sink = rodio::Sink::try_new(&stream_handle).unwrap();
-
-        (
-            Self {
-                bpm,
-                measure: Measure::default(),
-                sink,
-                up,
-                down,
-                playing: false,
-            },
-            stream,
-            stream_handle,
-        )
+
+        Self {
+            bpm,
+            measure: Measure::default(),
+            sink: Box::new(sink),
+            layers: Vec::new(),
+            routing: Routing::default(),
+            levels: Levels::default(),
+            arrangement: Vec::new(),
+            position: (0, 0),
+            // The bundled samples always decode
+            mixer: Mixer::new(
+                mixer::decode(UP.to_vec(), mixer::SAMPLE_RATE).unwrap(),
+                mixer::decode(DOWN.to_vec(), mixer::SAMPLE_RATE).unwrap(),
+            ),
+            queued: VecDeque::new(),
+            ticks: VecDeque::new(),
+            beats_queued: Arc::new(Notify::new()),
+            latency: Duration::ZERO,
+            options: StartOptions::default(),
+            samples: SampleSelection::default(),
+            playing: false,
+            silent: false,
+        }
+    }
+
+    /// Replaces the sink, dropping whatever was queued on the previous one
+    pub fn set_sink(&mut self, mut sink: impl ClickSink + 'static) {
+        sink.set_volume(self.sink.volume());
+        self.sink = Box::new(sink);
+        if self.playing {
+            self.restart();
+        }
     }
 
     pub fn play(&mut self) {
@@ -64,51 +151,184 @@ impl RodioPlayer {
         self.sink.pause()
     }
 
+    /// Queues the next measure, mixed with every layer, unless `QUEUED_MEASURES` are queued
+    /// already
     pub fn push(&mut self) {
         if !self.playing {
             return;
         }
-        let up = std::io::Cursor::new(self.up.clone());
-        let up_source = Decoder::new(up)
-            .unwrap()
-            // .take_duration(Duration::from_millis(Self::METRONOME_TIME))
-            .buffered();
-        let up_length = up_source.total_duration().unwrap().as_millis();
-
-        let down = std::io::Cursor::new(self.down.clone());
-        let down_source = Decoder::new(down)
-            .unwrap()
-            // .take_duration(Duration::from_millis(Self::METRONOME_TIME))
-            .buffered();
-        let down_length = down_source.total_duration().unwrap().as_millis();
-
-        self.measure.data.iter().enumerate().for_each(|(n, i)| {
-            let s = match i.0[0].sound_type {
-                SoundType::Up => up_source.clone(),
-                SoundType::Mid => up_source.clone(), //TODO change
-                SoundType::Down => down_source.clone(),
-            };
-            //     //TODO extract silence?
-            let silence: Zero<i16> = Zero::new(s.channels(), s.sample_rate());
-            let r = s.mix(silence).amplify(i.0[0].volume_modifier);
-            self.sink.append(r.take_duration(Duration::from_nanos(
-                i.0[0].duration.make_duration(self.bpm()),
-            )));
-        });
+        if self.mixer.finished() {
+            self.playing = false;
+            return;
+        }
+        while self.queued.len() > self.sink.queued() {
+            self.queued.pop_front();
+        }
+        if self.queued.len() >= QUEUED_MEASURES {
+            return;
+        }
+        if !self.arrangement.is_empty() && !self.mixer.counting_in() && !self.advance() {
+            self.playing = false;
+            if self.options.end_sound {
+                let end = self.mixer.render_end();
+                self.append(end);
+            }
+            return;
+        }
+        self.queued.push_back(self.mixer.frame());
+        let buffer = self.mixer.render_measure();
+        self.append(buffer);
+        let ticks = self.mixer.take_ticks();
+        if !ticks.is_empty() {
+            self.ticks.extend(ticks);
+            self.beats_queued.notify_waiters();
+        }
+        let heard = self.heard_frame();
+        while self.ticks.front().is_some_and(|tick| tick.frame < heard) {
+            self.ticks.pop_front();
+        }
+    }
+
+    /// Queues `buffer` on the sink, or as much silence in silent mode so the beats keep their time
+    fn append(&mut self, mut buffer: Vec<f32>) {
+        if self.silent {
+            buffer.fill(0.0);
+        }
+        self.sink.append(buffer);
+    }
+
+    /// Moves on to the next measure of the arrangement, `false` after the last one
+    fn advance(&mut self) -> bool {
+        let (index, bar) = self.position;
+        let Some(section) = self.arrangement.get(index) else {
+            return false;
+        };
+        if bar == 0 || section.ramp_to.is_some() {
+            let bpm = section.bpm_at(bar);
+            self.mixer
+                .continue_with(bpm, &section.measure, &self.layers);
+            self.bpm.store(bpm, Ordering::Relaxed);
+            self.measure = section.measure.clone();
+        }
+        self.position = if bar + 1 < section.bars {
+            (index, bar + 1)
+        } else {
+            (index + 1, 0)
+        };
+        true
+    }
+
+    /// The mixer's frame that is being played right now
+    fn playing_frame(&self) -> u64 {
+        let playing = self.queued.len().saturating_sub(self.sink.queued());
+        match self.queued.get(playing) {
+            Some(start) => {
+                start + (self.sink.position().as_secs_f64() * mixer::SAMPLE_RATE as f64) as u64
+            }
+            None => self.mixer.frame(),
+        }
     }
 
-    pub fn start(&mut self) {
+    /// The mixer's frame that is being heard right now, the output latency behind the one being
+    /// played
+    fn heard_frame(&self) -> u64 {
+        let latency = self.latency.as_secs_f64() * mixer::SAMPLE_RATE as f64;
+        self.playing_frame().saturating_sub(latency as u64)
+    }
+
+    /// How long until the last queued measure starts playing, when the next one can be queued.
+    /// `None` when not playing.
+    pub fn next_push(&self) -> Option<Duration> {
+        if !self.playing {
+            return None;
+        }
+        // Sinks which play as soon as they're given a measure are given one a measure
+        let Some(last) = self.queued.back() else {
+            return Some(Duration::from_nanos(self.measure.duration(self.bpm())));
+        };
+        let frames = last.saturating_sub(self.playing_frame());
+        Some(Duration::from_secs_f64(
+            frames as f64 / mixer::SAMPLE_RATE as f64,
+        ))
+    }
+
+    /// When the mixer's `frame` is heard, taking the output latency into account
+    pub fn heard_at(&self, frame: u64) -> SystemTime {
+        let heard = self.heard_frame();
+        let seconds =
+            |frames: u64| Duration::from_secs_f64(frames as f64 / mixer::SAMPLE_RATE as f64);
+        let now = SystemTime::now();
+        if frame >= heard {
+            now + seconds(frame - heard)
+        } else {
+            now - seconds(heard - frame)
+        }
+    }
+
+    /// The next beat to be heard and when it's heard
+    pub fn next_beat(&self) -> Option<(Tick, SystemTime)> {
+        self.beats().next()
+    }
+
+    /// Notified whenever `beats()` gets new beats, e.g. to wait for a beat without polling
+    pub fn beats_queued(&self) -> Arc<Notify> {
+        self.beats_queued.clone()
+    }
+
+    /// The beats queued that aren't heard yet, and when they're heard
+    pub fn beats(&self) -> impl Iterator<Item = (Tick, SystemTime)> + '_ {
+        let heard = self.heard_frame();
+        self.ticks
+            .iter()
+            .filter(move |tick| tick.frame >= heard)
+            .map(|tick| (*tick, self.heard_at(tick.frame)))
+    }
+
+    /// Starts from the downbeat of every layer, and from the top of the arrangement
+    pub fn start(&mut self, options: StartOptions) {
         self.playing = true;
+        self.position = (0, 0);
+        if let Some(first) = self.arrangement.first() {
+            self.bpm.store(first.bpm, Ordering::Relaxed);
+            self.measure = first.measure.clone();
+        }
+        self.mixer
+            .reset(self.bpm(), &self.measure, &self.layers, &options);
+        self.options = options;
+        self.queued.clear();
+        self.ticks.clear();
         self.push();
     }
 
+    /// Starts over without the count-in, the practice timer starts over too
+    pub fn restart(&mut self) {
+        self.stop();
+        self.start(StartOptions {
+            count_in: 0,
+            ..self.options.clone()
+        });
+    }
+
+    /// Drops the queued clicks, the end sound is only played when stopping on its own
     pub fn stop(&mut self) {
         self.playing = false;
         self.sink.stop();
+        self.ticks.clear();
     }
 
+    /// Keeps the beat phase while playing, the beat being played carries on at the new tempo.
+    /// Tempos outside `BPM_RANGE` are clamped.
     pub fn set_bpm(&mut self, bpm: u64) {
+        let bpm = bpm.clamp(*BPM_RANGE.start(), *BPM_RANGE.end());
         self.bpm.store(bpm, Ordering::Relaxed);
+        if self.playing {
+            let frame = self.playing_frame();
+            self.sink.stop();
+            self.mixer.retime(frame, bpm, &self.measure, &self.layers);
+            self.queued.clear();
+            self.ticks.retain(|tick| tick.frame < frame);
+            self.push();
+        }
     }
 
     pub fn bpm(&self) -> u64 {
@@ -118,4 +338,205 @@ impl RodioPlayer {
     pub fn playing(&self) -> bool {
         self.playing
     }
+
+    /// Takes effect from the next queued measure on
+    pub fn set_silent(&mut self, silent: bool) {
+        self.silent = silent;
+    }
+
+    pub fn silent(&self) -> bool {
+        self.silent
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.sink.set_volume(volume);
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.sink.volume()
+    }
+
+    /// Only moves the beats' times, the click itself is played as soon as possible
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = latency;
+    }
+
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    /// Stops the click and plays interleaved stereo `frames` at `mixer::SAMPLE_RATE` instead,
+    /// e.g. for the calibration
+    pub fn play_frames(&mut self, frames: Vec<f32>) {
+        self.stop();
+        self.queued.clear();
+        self.sink.append(frames);
+        self.sink.play();
+    }
+
+    pub fn set_measure(&mut self, measure: Measure) {
+        self.measure = measure;
+    }
+
+    pub fn measure(&self) -> &Measure {
+        &self.measure
+    }
+
+    pub fn set_subdivision(&mut self, subdivision: Rhythm) {
+        self.measure.subdivision = subdivision;
+    }
+
+    /// `swing` in `measure::SWING_RANGE`
+    pub fn set_swing(&mut self, swing: f64) {
+        self.measure.swing = swing;
+    }
+
+    /// Largest random offset of every click in milliseconds
+    pub fn set_humanize(&mut self, humanize: f64) {
+        self.measure.humanize = humanize;
+    }
+
+    /// Measures played along with the main one, they take effect on the next start
+    pub fn set_layers(&mut self, layers: Vec<Layer>) {
+        self.layers = layers;
+    }
+
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// Takes effect from the next queued measure on
+    pub fn set_routing(&mut self, routing: Routing) {
+        self.routing = routing;
+        self.mixer.set_routing(routing);
+    }
+
+    pub fn routing(&self) -> Routing {
+        self.routing
+    }
+
+    /// Takes effect from the next queued measure on
+    pub fn set_levels(&mut self, levels: Levels) {
+        self.levels = levels;
+        self.mixer.set_levels(levels);
+    }
+
+    pub fn levels(&self) -> Levels {
+        self.levels
+    }
+
+    /// Sections the player follows instead of the tempo and the measure, from the next start on.
+    /// An empty arrangement goes back to the measure.
+    pub fn set_arrangement(&mut self, arrangement: Vec<Section>) {
+        self.arrangement = arrangement
+            .into_iter()
+            .filter(|section| section.bars > 0)
+            .collect();
+    }
+
+    pub fn arrangement(&self) -> &[Section] {
+        &self.arrangement
+    }
+
+    /// Leaves the current samples in place if the new ones can't be read or decoded
+    pub fn set_samples(&mut self, samples: SampleSelection) -> io::Result<()> {
+        let (up, down) = samples.load()?;
+        self.mixer.set_samples(
+            mixer::decode(up, mixer::SAMPLE_RATE)?,
+            mixer::decode(down, mixer::SAMPLE_RATE)?,
+        );
+        self.samples = samples;
+        Ok(())
+    }
+
+    pub fn samples(&self) -> &SampleSelection {
+        &self.samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Counts the buffers queued, the test plays them by hand
+    #[derive(Clone, Default)]
+    struct QueueSink(Arc<Mutex<VecDeque<usize>>>);
+
+    impl QueueSink {
+        fn play_one(&self) {
+            self.0.lock().unwrap().pop_front();
+        }
+    }
+
+    impl ClickSink for QueueSink {
+        fn append(&mut self, frames: Vec<f32>) {
+            self.0.lock().unwrap().push_back(frames.len());
+        }
+
+        fn stop(&mut self) {
+            self.0.lock().unwrap().clear();
+        }
+
+        fn play(&mut self) {}
+
+        fn pause(&mut self) {}
+
+        fn queued(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+
+        fn position(&self) -> Duration {
+            Duration::ZERO
+        }
+
+        fn set_volume(&mut self, _volume: f32) {}
+
+        fn volume(&self) -> f32 {
+            1.0
+        }
+    }
+
+    #[test]
+    fn queue_stays_bounded() {
+        let sink = QueueSink::default();
+        let mut player = Player::new(sink.clone());
+        player.start(StartOptions::default());
+        for _ in 0..100 {
+            player.push();
+        }
+        assert_eq!(sink.queued(), QUEUED_MEASURES);
+
+        // Tempo changes and restarts queue the measures again instead of adding to them
+        for bpm in 100..150 {
+            player.set_bpm(bpm);
+            player.push();
+            player.restart();
+            player.push();
+        }
+        assert_eq!(sink.queued(), QUEUED_MEASURES);
+
+        for _ in 0..100 {
+            sink.play_one();
+            player.push();
+            player.push();
+            assert_eq!(sink.queued(), QUEUED_MEASURES);
+        }
+    }
+
+    #[test]
+    fn next_push_waits_for_the_last_measure() {
+        let sink = QueueSink::default();
+        let mut player = Player::new(sink.clone());
+        assert_eq!(player.next_push(), None);
+        player.start(StartOptions::default());
+        player.push();
+        // The first measure is being played from its start
+        let measure = Duration::from_nanos(player.measure().duration(player.bpm()));
+        let next = player.next_push().unwrap();
+        assert!(
+            next.abs_diff(measure) < Duration::from_millis(1),
+            "{next:?}"
+        );
+    }
 }
@@ -0,0 +1,285 @@
+use std::{
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    measure::Measure,
+    mixer::{Layer, Levels, Routing},
+    player::{Player, SampleSelection},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preset {
+    pub name: String,
+    pub bpm: u64,
+    /// Also holds the subdivision
+    pub measure: Measure,
+    #[serde(default)]
+    pub layers: Vec<Layer>,
+    #[serde(default)]
+    pub samples: SampleSelection,
+    #[serde(default)]
+    pub routing: Routing,
+    #[serde(default)]
+    pub levels: Levels,
+}
+
+impl Default for Preset {
+    fn default() -> Self {
+        Preset {
+            name: String::from("Default"),
+            bpm: 120,
+            measure: Measure::default(),
+            layers: Vec::new(),
+            samples: SampleSelection::default(),
+            routing: Routing::default(),
+            levels: Levels::default(),
+        }
+    }
+}
+
+impl Preset {
+    pub fn from_player(name: String, player: &Player) -> Self {
+        Preset {
+            name,
+            bpm: player.bpm(),
+            measure: player.measure().clone(),
+            layers: player.layers().to_vec(),
+            samples: player.samples().clone(),
+            routing: player.routing(),
+            levels: player.levels(),
+        }
+    }
+
+    /// Loads the samples first so the player is left untouched if they can't be read
+    pub fn apply(&self, player: &mut Player) -> io::Result<()> {
+        player.set_samples(self.samples.clone())?;
+        player.set_bpm(self.bpm);
+        player.set_measure(self.measure.clone());
+        player.set_layers(self.layers.clone());
+        player.set_routing(self.routing);
+        player.set_levels(self.levels);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    NotFound,
+    AlreadyExists,
+    Io(io::Error),
+}
+
+impl Display for PresetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PresetError::NotFound => write!(f, "no preset with this name"),
+            PresetError::AlreadyExists => write!(f, "a preset with this name already exists"),
+            PresetError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+impl From<io::Error> for PresetError {
+    fn from(e: io::Error) -> Self {
+        PresetError::Io(e)
+    }
+}
+
+/// Named presets, written back to a JSON file on every change. The order is kept so MIDI program
+/// changes can select them by index.
+#[derive(Debug, Default)]
+pub struct Presets {
+    path: Option<PathBuf>,
+    presets: Vec<Preset>,
+}
+
+impl Presets {
+    /// `presets.json` in the user's config directory
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("racoon").join("presets.json"))
+    }
+
+    /// Reads the library at `path`, it's created on the first change if it doesn't exist
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let presets = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Presets {
+            path: Some(path),
+            presets,
+        })
+    }
+
+    /// The library at the default path, or one that's only kept in memory if it can't be read
+    pub fn load_default() -> Self {
+        let Some(path) = Self::default_path() else {
+            return Presets::default();
+        };
+        Self::load(path).unwrap_or_else(|e| {
+            eprintln!("Couldn't load the presets, they won't be saved: {e}");
+            Presets::default()
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_vec_pretty(&self.presets)?)
+    }
+
+    pub fn list(&self) -> &[Preset] {
+        &self.presets
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Preset> {
+        self.presets.iter().find(|p| p.name == name)
+    }
+
+    pub fn get_index(&self, index: usize) -> Option<&Preset> {
+        self.presets.get(index)
+    }
+
+    /// Replaces the preset with the same name, if any
+    pub fn insert(&mut self, preset: Preset) -> Result<(), PresetError> {
+        match self.presets.iter_mut().find(|p| p.name == preset.name) {
+            Some(existing) => *existing = preset,
+            None => self.presets.push(preset),
+        }
+        Ok(self.save()?)
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), PresetError> {
+        if from != to && self.get(to).is_some() {
+            return Err(PresetError::AlreadyExists);
+        }
+        let preset = self
+            .presets
+            .iter_mut()
+            .find(|p| p.name == from)
+            .ok_or(PresetError::NotFound)?;
+        preset.name = to.to_string();
+        Ok(self.save()?)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<Preset, PresetError> {
+        let index = self
+            .presets
+            .iter()
+            .position(|p| p.name == name)
+            .ok_or(PresetError::NotFound)?;
+        let preset = self.presets.remove(index);
+        self.save()?;
+        Ok(preset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A library file of its own for every test
+    fn path(test: &str) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("racoon-presets-{}", std::process::id()))
+            .join(format!("{test}.json"));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn preset(name: &str, bpm: u64) -> Preset {
+        Preset {
+            name: name.to_string(),
+            bpm,
+            ..Preset::default()
+        }
+    }
+
+    fn names(presets: &Presets) -> Vec<&str> {
+        presets.list().iter().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn insert_replaces_by_name() {
+        let mut presets = Presets::default();
+        presets.insert(preset("Verse", 90)).unwrap();
+        presets.insert(preset("Chorus", 120)).unwrap();
+        presets.insert(preset("Verse", 95)).unwrap();
+        assert_eq!(names(&presets), ["Verse", "Chorus"]);
+        assert_eq!(presets.get("Verse").unwrap().bpm, 95);
+        assert_eq!(presets.get_index(1).unwrap().name, "Chorus");
+    }
+
+    #[test]
+    fn rename() {
+        let mut presets = Presets::default();
+        presets.insert(preset("Verse", 90)).unwrap();
+        presets.insert(preset("Chorus", 120)).unwrap();
+
+        assert!(matches!(
+            presets.rename("Verse", "Chorus"),
+            Err(PresetError::AlreadyExists)
+        ));
+        assert!(matches!(
+            presets.rename("Bridge", "Outro"),
+            Err(PresetError::NotFound)
+        ));
+        presets.rename("Verse", "Verse").unwrap();
+        presets.rename("Verse", "Intro").unwrap();
+        assert_eq!(names(&presets), ["Intro", "Chorus"]);
+    }
+
+    #[test]
+    fn remove() {
+        let mut presets = Presets::default();
+        presets.insert(preset("Verse", 90)).unwrap();
+        assert_eq!(presets.remove("Verse").unwrap().bpm, 90);
+        assert!(matches!(
+            presets.remove("Verse"),
+            Err(PresetError::NotFound)
+        ));
+        assert!(presets.list().is_empty());
+    }
+
+    #[test]
+    fn saved_on_every_change() {
+        let path = path("saved_on_every_change");
+        let mut presets = Presets::load(path.clone()).unwrap();
+        assert!(presets.list().is_empty());
+
+        presets.insert(preset("Verse", 90)).unwrap();
+        presets.insert(preset("Chorus", 120)).unwrap();
+        presets.rename("Verse", "Intro").unwrap();
+        presets.remove("Chorus").unwrap();
+
+        let loaded = Presets::load(path.clone()).unwrap();
+        assert_eq!(names(&loaded), ["Intro"]);
+        assert_eq!(loaded.get("Intro").unwrap().bpm, 90);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_file() {
+        let path = path("invalid_file");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "not json").unwrap();
+        assert!(Presets::load(path.clone()).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
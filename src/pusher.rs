@@ -4,37 +4,29 @@ use std::{
     time::Duration,
 };
 
-use crate::player::RodioPlayer;
+use crate::player::Player;
+
+/// How often to check on a player that isn't playing, starting it unparks the pusher anyway
+const IDLE: Duration = Duration::from_millis(100);
+/// Slept past the start of the last queued measure, so the sink is done with the one before
+const MARGIN: Duration = Duration::from_millis(5);
 
 pub struct Pusher {
-    player: Arc<Mutex<RodioPlayer>>,
+    player: Arc<Mutex<Player>>,
     thread: JoinHandle<()>,
 }
 
-//TODO park forever when not playing, start etc unparks
 impl Pusher {
-    pub fn new(player: Arc<Mutex<RodioPlayer>>) -> Self {
+    pub fn new(player: Arc<Mutex<Player>>) -> Self {
         let player2 = player.clone();
         let thread = thread::Builder::new()
-            .spawn(move || {
-                let mut bpm;
-                // let mut bpm = 120;
-                let mut beats_per_measure = 4;
-                loop {
-                    let mut lock = player2.lock().unwrap();
-
-                    lock.push();
-                    bpm = lock.bpm();
-                    let pos = lock.sink.get_pos();
-
-                    drop(lock);
-
-                    let sleep_duration = (60_000 / bpm) * (beats_per_measure - 1);
-                    // println!("sleep {sleep_duration}",);
-                    // println!("pos {pos:?}",);
-                    //TODO spin sleep here?
-                    park_timeout(Duration::from_millis(sleep_duration));
-                }
+            .spawn(move || loop {
+                let mut lock = player2.lock().unwrap();
+                lock.push();
+                // Wakes up when the last queued measure starts, a whole measure ahead of time
+                let sleep_duration = lock.next_push().map_or(IDLE, |next| next + MARGIN);
+                drop(lock);
+                park_timeout(sleep_duration);
             })
             .unwrap();
 
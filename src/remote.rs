@@ -0,0 +1,44 @@
+//! The web remote served at `/`, baked into the binary so the QR code of the server's address
+//! opens a working remote right away
+
+use poem::{endpoint::make_sync, get, http::header, Response, Route};
+
+struct File {
+    path: &'static str,
+    content_type: &'static str,
+    contents: &'static str,
+}
+
+const FILES: [File; 3] = [
+    File {
+        path: "/",
+        content_type: "text/html; charset=utf-8",
+        contents: include_str!("../assets/remote/index.html"),
+    },
+    File {
+        path: "/remote.css",
+        content_type: "text/css; charset=utf-8",
+        contents: include_str!("../assets/remote/remote.css"),
+    },
+    File {
+        path: "/remote.js",
+        content_type: "text/javascript; charset=utf-8",
+        contents: include_str!("../assets/remote/remote.js"),
+    },
+];
+
+/// Adds the remote's files to `route`
+pub fn mount(route: Route) -> Route {
+    FILES.iter().fold(route, |route, file| {
+        route.at(
+            file.path,
+            get(make_sync(move |_| {
+                Response::builder()
+                    .content_type(file.content_type)
+                    // Served again after an update of the server
+                    .header(header::CACHE_CONTROL, "no-cache")
+                    .body(file.contents)
+            })),
+        )
+    })
+}
@@ -0,0 +1,222 @@
+//! Offline rendering of click tracks to WAV, without an audio device
+
+use std::io::{self, Seek, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    measure::Measure,
+    mixer::{self, Layer, Mixer, CHANNELS},
+    player::SampleSelection,
+};
+
+/// Longest click track the REST API renders, in samples of every channel. That's 20 minutes at
+/// 48 kHz, or about 230 MB of WAV.
+pub const MAX_SAMPLES: u64 = 20 * 60 * 48_000 * CHANNELS as u64;
+
+/// Measures played at one tempo, or ramping to another one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Section {
+    pub bpm: u64,
+    /// Tempo of the last measure, every measure in between changes it by the same amount
+    #[serde(default)]
+    pub ramp_to: Option<u64>,
+    pub measure: Measure,
+    #[serde(default)]
+    pub layers: Vec<Layer>,
+    pub bars: u64,
+}
+
+impl Section {
+    pub fn new(bpm: u64, measure: Measure, bars: u64) -> Self {
+        Section {
+            bpm,
+            ramp_to: None,
+            measure,
+            layers: Vec::new(),
+            bars,
+        }
+    }
+
+    /// Tempo of measure `bar`, counting from 0
+    pub fn bpm_at(&self, bar: u64) -> u64 {
+        match self.ramp_to {
+            Some(to) if self.bars > 1 => {
+                let progress = bar.min(self.bars - 1) as f64 / (self.bars - 1) as f64;
+                (self.bpm as f64 + (to as f64 - self.bpm as f64) * progress).round() as u64
+            }
+            _ => self.bpm,
+        }
+    }
+
+    /// Length in nanoseconds
+    pub fn duration(&self) -> u64 {
+        match self.ramp_to {
+            Some(_) => (0..self.bars)
+                .map(|bar| self.measure.duration(self.bpm_at(bar)))
+                .fold(0, u64::saturating_add),
+            None => self.measure.duration(self.bpm).saturating_mul(self.bars),
+        }
+    }
+}
+
+/// Total length in nanoseconds
+pub fn duration(sections: &[Section]) -> u64 {
+    sections
+        .iter()
+        .map(Section::duration)
+        .fold(0, u64::saturating_add)
+}
+
+/// Total length in samples of every channel at `sample_rate`
+pub fn samples(sections: &[Section], sample_rate: u32) -> u64 {
+    let frames = duration(sections) as u128 * sample_rate as u128 / 1_000_000_000;
+    (frames * CHANNELS as u128).try_into().unwrap_or(u64::MAX)
+}
+
+/// Calls `f` with every measure of the sections, as interleaved stereo frames. The result only
+/// depends on the arguments, the humanize always uses the same random numbers.
+fn render_measures(
+    sections: &[Section],
+    sample_rate: u32,
+    samples: &SampleSelection,
+    mut f: impl FnMut(Vec<f32>) -> io::Result<()>,
+) -> io::Result<()> {
+    let (up, down) = samples.load()?;
+    let mut mixer = Mixer::with_sample_rate(
+        mixer::decode(up, sample_rate)?,
+        mixer::decode(down, sample_rate)?,
+        sample_rate,
+    );
+    for section in sections {
+        mixer.continue_with(section.bpm, &section.measure, &section.layers);
+        for bar in 0..section.bars {
+            if section.ramp_to.is_some() && bar > 0 {
+                mixer.continue_with(section.bpm_at(bar), &section.measure, &section.layers);
+            }
+            f(mixer.render_measure())?;
+        }
+    }
+    Ok(())
+}
+
+/// Renders the sections one after the other into interleaved stereo frames
+pub fn render(
+    sections: &[Section],
+    sample_rate: u32,
+    samples: &SampleSelection,
+) -> io::Result<Vec<f32>> {
+    let mut buffer = Vec::new();
+    render_measures(sections, sample_rate, samples, |measure| {
+        buffer.extend(measure);
+        Ok(())
+    })?;
+    Ok(buffer)
+}
+
+/// Renders the sections as a 16 bit stereo WAV file
+pub fn write_wav<W: Write + Seek>(
+    writer: W,
+    sections: &[Section],
+    sample_rate: u32,
+    samples: &SampleSelection,
+) -> io::Result<()> {
+    let spec = hound::WavSpec {
+        channels: CHANNELS,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut wav = hound::WavWriter::new(writer, spec).map_err(io::Error::other)?;
+    render_measures(sections, sample_rate, samples, |measure| {
+        for sample in measure {
+            wav.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+                .map_err(io::Error::other)?;
+        }
+        Ok(())
+    })?;
+    wav.finalize().map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    /// Ten frames of half scale at `SAMPLE_RATE`, for the up and the down beat
+    fn click(test: &str) -> SampleSelection {
+        let path =
+            std::env::temp_dir().join(format!("racoon-render-{}-{test}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut wav = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..10 {
+            wav.write_sample(i16::MAX / 2).unwrap();
+        }
+        wav.finalize().unwrap();
+        SampleSelection {
+            up: Some(path.clone()),
+            down: Some(path),
+        }
+    }
+
+    /// Frames where a click starts
+    fn onsets(buffer: &[f32]) -> Vec<usize> {
+        let loud = |frame: usize| buffer[frame * 2..frame * 2 + 2] != [0.0, 0.0];
+        (0..buffer.len() / 2)
+            .filter(|&frame| loud(frame) && (frame == 0 || !loud(frame - 1)))
+            .collect()
+    }
+
+    #[test]
+    fn click_positions() {
+        let sections = [
+            Section::new(60, "2/4: A m".parse().unwrap(), 2),
+            Section::new(120, "3/8: A m m".parse().unwrap(), 1),
+        ];
+        let buffer = render(&sections, SAMPLE_RATE, &click("positions")).unwrap();
+        assert_eq!(buffer.len(), 38_000 * 2);
+        assert_eq!(
+            onsets(&buffer),
+            [0, 8000, 16_000, 24_000, 32_000, 34_000, 36_000]
+        );
+        assert_eq!(samples(&sections, SAMPLE_RATE), 38_000 * 2);
+    }
+
+    #[test]
+    fn deterministic() {
+        let mut measure = "4/4: A m m m".parse::<Measure>().unwrap();
+        measure.humanize = 20.0;
+        let sections = [Section::new(120, measure, 4)];
+        let samples = click("deterministic");
+        let first = render(&sections, SAMPLE_RATE, &samples).unwrap();
+        let second = render(&sections, SAMPLE_RATE, &samples).unwrap();
+        assert_eq!(first, second);
+
+        let onsets = onsets(&first);
+        assert_eq!(onsets.len(), 16);
+        assert!(onsets
+            .iter()
+            .enumerate()
+            .all(|(beat, onset)| onset.abs_diff(beat * 4000) <= 160));
+        assert!(onsets
+            .iter()
+            .enumerate()
+            .any(|(beat, onset)| *onset != beat * 4000));
+    }
+
+    #[test]
+    fn long_tracks() {
+        let section = |bpm, bars| Section::new(bpm, Measure::new(4), bars);
+        // 4 bars at 60 BPM are 16 seconds
+        assert_eq!(samples(&[section(60, 4)], 48_000), 16 * 48_000 * 2);
+        assert!(samples(&[section(1, u64::MAX)], 192_000) > MAX_SAMPLES);
+        assert!(samples(&[section(60, 300)], 48_000) <= MAX_SAMPLES);
+        assert!(samples(&[section(60, 76)], 192_000) > MAX_SAMPLES);
+    }
+}
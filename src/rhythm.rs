@@ -1,14 +1,36 @@
 use std::str::FromStr;
 
-#[derive(Clone, Copy, Debug)]
+use serde::{Deserialize, Serialize};
+
+/// Serialized with the names of `name()`, the singular names saved by older versions are still
+/// read
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Rhythm {
+    #[serde(rename = "halves", alias = "half")]
+    Half,
+    #[serde(rename = "quarters", alias = "quarter")]
     Quarter,
+    #[serde(rename = "eights", alias = "eighths", alias = "eighth")]
     Eighth,
+    #[serde(
+        rename = "triplet_eights",
+        alias = "triplet_eighths",
+        alias = "triplet_eighth"
+    )]
     TripletEighth,
+    #[serde(
+        rename = "triplet_quarter_eights",
+        alias = "triplet_quarter_eighths",
+        alias = "triplet_quarter_eighth"
+    )]
     TripletQuarterEighth,
+    #[serde(rename = "triplet_eighth_quarters", alias = "triplet_eighth_quarter")]
     TripletEighthQuarter,
+    #[serde(rename = "sixteenths", alias = "sixteenth")]
     Sixteenth,
+    #[serde(rename = "eighth_sixteenths")]
     EighthSixteenths,
+    #[serde(rename = "sixteen_eights")]
     SixteenEights,
 }
 
@@ -16,6 +38,9 @@ pub enum Rhythm {
 impl Rhythm {
     pub fn make_intervals(&self, bpm: u64, beats_per_measure: usize) -> Vec<u64> {
         match &self {
+            Rhythm::Half => {
+                vec![60_000_000_000 * 2 / bpm; beats_per_measure]
+            }
             Rhythm::Quarter => {
                 vec![60_000_000_000 / bpm; beats_per_measure]
             }
@@ -33,12 +58,53 @@ impl Rhythm {
 
     pub fn make_duration(&self, bpm: u64) -> u64 {
         match &self {
+            Rhythm::Half => 60_000_000_000 * 2 / bpm,
             Rhythm::Quarter => 60_000_000_000 / bpm,
             Rhythm::Sixteenth => 60_000_000_000 / bpm / 4,
             Rhythm::Eighth => 60_000_000_000 / bpm / 2,
             _ => 0,
         }
     }
+
+    /// The note value of one beat for a time signature's denominator
+    pub fn from_denominator(denominator: usize) -> Option<Self> {
+        match denominator {
+            2 => Some(Rhythm::Half),
+            4 => Some(Rhythm::Quarter),
+            8 => Some(Rhythm::Eighth),
+            16 => Some(Rhythm::Sixteenth),
+            _ => None,
+        }
+    }
+
+    /// How a beat is split when used as a subdivision, as fractions of the beat
+    pub fn fractions(&self) -> &'static [f64] {
+        match &self {
+            Rhythm::Half | Rhythm::Quarter => &[1.0],
+            Rhythm::Eighth => &[1.0 / 2.0; 2],
+            Rhythm::TripletEighth => &[1.0 / 3.0; 3],
+            Rhythm::TripletQuarterEighth => &[2.0 / 3.0, 1.0 / 3.0],
+            Rhythm::TripletEighthQuarter => &[1.0 / 3.0, 2.0 / 3.0],
+            Rhythm::Sixteenth => &[1.0 / 4.0; 4],
+            Rhythm::EighthSixteenths => &[1.0 / 2.0, 1.0 / 4.0, 1.0 / 4.0],
+            Rhythm::SixteenEights => &[1.0 / 4.0, 1.0 / 4.0, 1.0 / 2.0],
+        }
+    }
+
+    /// The name accepted by `from_str`
+    pub fn name(&self) -> &'static str {
+        match &self {
+            Rhythm::Half => "halves",
+            Rhythm::Quarter => "quarters",
+            Rhythm::Eighth => "eights",
+            Rhythm::TripletEighth => "triplet_eights",
+            Rhythm::TripletQuarterEighth => "triplet_quarter_eights",
+            Rhythm::TripletEighthQuarter => "triplet_eighth_quarters",
+            Rhythm::Sixteenth => "sixteenths",
+            Rhythm::EighthSixteenths => "eighth_sixteenths",
+            Rhythm::SixteenEights => "sixteen_eights",
+        }
+    }
 }
 
 impl FromStr for Rhythm {
@@ -55,10 +121,13 @@ impl FromStr for Rhythm {
         // SixteenEights,
 
         match s {
+            "halves" => Ok(Rhythm::Half),
             "quarters" => Ok(Rhythm::Quarter),
-            "eights" => Ok(Rhythm::Eighth),
-            "triplet_eights" => Ok(Rhythm::TripletEighth),
-            "triplet_quarter_eights" => Ok(Rhythm::TripletQuarterEighth),
+            "eights" | "eighths" => Ok(Rhythm::Eighth),
+            "triplet_eights" | "triplet_eighths" => Ok(Rhythm::TripletEighth),
+            "triplet_quarter_eights" | "triplet_quarter_eighths" => {
+                Ok(Rhythm::TripletQuarterEighth)
+            }
             "triplet_eighth_quarters" => Ok(Rhythm::TripletEighthQuarter),
             "sixteenths" => Ok(Rhythm::Sixteenth),
             "eighth_sixteenths" => Ok(Rhythm::EighthSixteenths),
@@ -67,3 +136,46 @@ impl FromStr for Rhythm {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Rhythm; 9] = [
+        Rhythm::Half,
+        Rhythm::Quarter,
+        Rhythm::Eighth,
+        Rhythm::TripletEighth,
+        Rhythm::TripletQuarterEighth,
+        Rhythm::TripletEighthQuarter,
+        Rhythm::Sixteenth,
+        Rhythm::EighthSixteenths,
+        Rhythm::SixteenEights,
+    ];
+
+    #[test]
+    fn one_vocabulary() {
+        for rhythm in ALL {
+            assert_eq!(Rhythm::from_str(rhythm.name()), Ok(rhythm));
+            let json = serde_json::to_string(&rhythm).unwrap();
+            assert_eq!(json, format!("\"{}\"", rhythm.name()));
+            assert_eq!(serde_json::from_str::<Rhythm>(&json).unwrap(), rhythm);
+        }
+    }
+
+    #[test]
+    fn reads_old_names() {
+        let old: Vec<Rhythm> =
+            serde_json::from_str(r#"["quarter", "eighth", "triplet_eighth", "sixteenth"]"#)
+                .unwrap();
+        assert_eq!(
+            old,
+            [
+                Rhythm::Quarter,
+                Rhythm::Eighth,
+                Rhythm::TripletEighth,
+                Rhythm::Sixteenth
+            ]
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use local_ip_address::{list_afinet_netifas, local_ip};
+use poem::{
+    http::{header, Method, StatusCode},
+    listener::{Listener, TcpListener},
+    middleware::AddData,
+    Addr, Endpoint, EndpointExt, IntoResponse, Request, Response, Route, Server,
+};
+use poem_openapi::OpenApiService;
+use qrcode::{render::unicode, QrCode};
+
+use crate::{pairing::Role, remote, tls::Tls, Api, AppState};
+
+fn scheme(tls: bool) -> &'static str {
+    if tls {
+        "https"
+    } else {
+        "http"
+    }
+}
+
+pub fn api_service(port: u16, tls: bool) -> OpenApiService<Api, ()> {
+    OpenApiService::new(Api, "Racoon Metronome", "0.1")
+        .server(format!("{}://localhost:{port}/api", scheme(tls)))
+}
+
+/// The routes served by the plugin, the desktop app and the headless server: the web remote at
+/// `/`, the REST API at `/api` and its documentation at `/doc`
+pub fn app(state: AppState, port: u16, tls: bool) -> impl Endpoint {
+    let api_service = api_service(port, tls);
+    let ui = api_service.swagger_ui();
+    remote::mount(Route::new())
+        .nest("/api", api_service.around(authorize))
+        .nest("/doc", ui)
+        .with(AddData::new(state))
+}
+
+/// The token of a request, as a bearer token or as the `token` query parameter for the clients
+/// that can't set headers, e.g. `EventSource` and WebSocket connections
+fn token(request: &Request) -> Option<&str> {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    bearer.or_else(|| {
+        request
+            .uri()
+            .query()?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+    })
+}
+
+/// With pairing on, lets through pairing requests and the requests of paired clients. Requests
+/// from the host itself only get through with `--trust-localhost`. Behind a reverse proxy on the
+/// host, every request seems to come from the host. The handlers check the role of the request.
+/// Everyone is an admin with pairing off.
+async fn authorize<E: Endpoint>(endpoint: Arc<E>, mut request: Request) -> poem::Result<Response> {
+    let state = request.data::<AppState>().unwrap().clone();
+    let mut role = Role::Admin;
+    if let Some(pairing) = state.pairing.get() {
+        let host = pairing.trust_localhost()
+            && matches!(request.remote_addr().0, Addr::SocketAddr(addr) if addr.ip().is_loopback());
+        let open = request.uri().path() == "/pair"
+            || (pairing.open_reads() && request.method() == Method::GET);
+        if !host {
+            role = match token(&request).and_then(|token| pairing.client(token)) {
+                Some(client) => client.role,
+                None if open => Role::Viewer,
+                None => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::UNAUTHORIZED)
+                        .header(header::WWW_AUTHENTICATE, "Bearer")
+                        .body("pair with the code shown by the host first, see POST /api/pair"));
+                }
+            };
+        }
+    }
+    request.extensions_mut().insert(role);
+    Ok(endpoint.call(request).await?.into_response())
+}
+
+/// Serves HTTPS with a certificate, plain HTTP otherwise
+pub async fn serve(
+    state: AppState,
+    addr: SocketAddr,
+    tls: Option<&Tls>,
+) -> Result<(), std::io::Error> {
+    let app = app(state, addr.port(), tls.is_some());
+    let listener = TcpListener::bind(addr);
+    match tls {
+        Some(tls) => {
+            Server::new(listener.rustls(tls.config()))
+                .name("racoon")
+                .run(app)
+                .await
+        }
+        None => Server::new(listener).name("racoon").run(app).await,
+    }
+}
+
+/// The url of the server on the most likely LAN address
+pub fn lan_url(port: u16, tls: bool) -> Option<String> {
+    local_ip()
+        .ok()
+        .map(|ip| format!("{}://{ip}:{port}", scheme(tls)))
+}
+
+/// The url to put in a QR code. The web remote pairs by itself with the code, and clients pin
+/// the self-signed certificate with the fingerprint.
+pub fn qr_url(url: &str, code: Option<&str>, fingerprint: Option<&str>) -> String {
+    let params: Vec<String> = [("code", code), ("sha256", fingerprint)]
+        .into_iter()
+        .filter_map(|(name, value)| Some(format!("{name}={}", value?)))
+        .collect();
+    if params.is_empty() {
+        url.to_string()
+    } else {
+        format!("{url}/?{}", params.join("&"))
+    }
+}
+
+/// Every private IPv4 address comma separated, followed by the port
+pub fn lan_addresses(port: u16) -> String {
+    let mut locals = Vec::with_capacity(10);
+    if let Ok(network_interfaces) = list_afinet_netifas() {
+        for (_, ip) in network_interfaces.iter() {
+            if let IpAddr::V4(ipv4) = ip.to_canonical() {
+                if ipv4.is_private() {
+                    locals.push(ip.to_string());
+                }
+            }
+        }
+    }
+
+    let mut addresses = locals.join(",");
+    addresses.push_str(&format!(":{port}"));
+    addresses
+}
+
+pub fn terminal_qr(data: &str) -> Option<String> {
+    let code = QrCode::new(data).ok()?;
+    Some(
+        code.render::<unicode::Dense1x2>()
+            .quiet_zone(true)
+            .dark_color(unicode::Dense1x2::Light)
+            .light_color(unicode::Dense1x2::Dark)
+            .build(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qr_urls() {
+        let url = "https://192.168.1.20:5000";
+        assert_eq!(qr_url(url, None, None), url);
+        assert_eq!(
+            qr_url(url, Some("123456"), None),
+            "https://192.168.1.20:5000/?code=123456"
+        );
+        assert_eq!(
+            qr_url(url, None, Some("3A:F1")),
+            "https://192.168.1.20:5000/?sha256=3A:F1"
+        );
+        assert_eq!(
+            qr_url(url, Some("123456"), Some("3A:F1")),
+            "https://192.168.1.20:5000/?code=123456&sha256=3A:F1"
+        );
+    }
+}
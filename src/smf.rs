@@ -0,0 +1,586 @@
+//! Standard MIDI File export of click tracks, with the tempo map and time signatures, and import
+//! of the tempo map of an existing arrangement
+
+use std::{fmt::Display, io};
+
+use midly::{
+    num::{u15, u24, u28, u4, u7},
+    Format, Header, MetaMessage, MidiMessage, Smf, Timing, Track, TrackEvent, TrackEventKind,
+};
+
+use crate::{
+    measure::{Measure, SoundType, TimeSignature, ACCENT_VOLUME},
+    player::BPM_RANGE,
+    render::Section,
+};
+
+pub const TICKS_PER_QUARTER: u16 = 480;
+/// General MIDI percussion
+const CHANNEL: u8 = 9;
+const HIGH_WOOD_BLOCK: u8 = 76;
+const LOW_WOOD_BLOCK: u8 = 77;
+/// Unless the next note on the same key comes sooner
+const NOTE_TICKS: u64 = 60;
+// Subdivisions are played at this velocity
+const SUBDIVISION_VELOCITY: u8 = 50;
+/// Longest tempo map that is imported
+pub const MAX_BARS: u64 = 10_000;
+/// 120 BPM, until the first tempo event
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// Velocity of a beat, the accents of `Measure::new` are at the top
+fn velocity(volume_modifier: f32) -> u8 {
+    (127.0 * volume_modifier / ACCENT_VOLUME).clamp(1.0, 127.0) as u8
+}
+
+/// Turns events at absolute ticks into a track, events at the same tick keep their order
+fn track<'a>(mut events: Vec<(u64, TrackEventKind<'a>)>) -> Track<'a> {
+    events.sort_by_key(|(tick, _)| *tick);
+    let mut last = 0;
+    let mut track = Vec::with_capacity(events.len() + 1);
+    for (tick, kind) in events {
+        track.push(TrackEvent {
+            delta: u28::new((tick - last) as u32),
+            kind,
+        });
+        last = tick;
+    }
+    track.push(TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    track
+}
+
+/// A type 1 file with the tempo map in the first track and the clicks on the percussion channel
+/// in the second one. The humanize is left out.
+pub fn export(sections: &[Section]) -> Smf<'static> {
+    let mut tempo_map = vec![(0, TrackEventKind::Meta(MetaMessage::TrackName(b"Tempo")))];
+    let mut clicks = vec![(0, TrackEventKind::Meta(MetaMessage::TrackName(b"Click")))];
+    let note = |key: u8, vel: u8, on: bool| TrackEventKind::Midi {
+        channel: u4::new(CHANNEL),
+        message: if on {
+            MidiMessage::NoteOn {
+                key: u7::new(key),
+                vel: u7::new(vel),
+            }
+        } else {
+            MidiMessage::NoteOff {
+                key: u7::new(key),
+                vel: u7::new(0),
+            }
+        },
+    };
+
+    // Tick, key and velocity of every click, in order
+    let mut notes = Vec::new();
+    let mut start = 0;
+    for section in sections {
+        let measure = &section.measure;
+        let signature = measure.time_signature;
+        tempo_map.push((
+            start,
+            TrackEventKind::Meta(MetaMessage::TimeSignature(
+                signature.numerator as u8,
+                signature.denominator.trailing_zeros() as u8,
+                (96 / signature.denominator) as u8,
+                8,
+            )),
+        ));
+        // At 60 BPM a nanosecond is a billionth of a quarter note
+        let quarter = 1_000_000_000.0 / TICKS_PER_QUARTER as f64;
+        let length = (measure.duration(60) as f64 / quarter).round() as u64;
+        let onsets = measure.onsets(60);
+        for bar in 0..section.bars {
+            if bar == 0 || section.ramp_to.is_some() {
+                let bpm = section.bpm_at(bar).max(1);
+                tempo_map.push((
+                    start,
+                    TrackEventKind::Meta(MetaMessage::Tempo(u24::new((60_000_000 / bpm) as u32))),
+                ));
+            }
+            for onset in &onsets {
+                let sound = &measure.data[onset.beat].0[0];
+                if sound.hidden {
+                    continue;
+                }
+                let (key, vel) = match (onset.sub, &sound.sound_type) {
+                    (0, SoundType::Up | SoundType::Mid) => {
+                        (HIGH_WOOD_BLOCK, velocity(sound.volume_modifier))
+                    }
+                    (0, SoundType::Down) => (LOW_WOOD_BLOCK, velocity(sound.volume_modifier)),
+                    _ => (LOW_WOOD_BLOCK, SUBDIVISION_VELOCITY),
+                };
+                notes.push((start + (onset.time / quarter).round() as u64, key, vel));
+            }
+            start += length;
+        }
+    }
+
+    // Every note ends by the time the next one on its key starts, a note at the same tick as
+    // the next one is left out
+    let mut next = [u64::MAX; 128];
+    let mut ends = vec![0; notes.len()];
+    for (end, &(tick, key, _)) in ends.iter_mut().zip(&notes).rev() {
+        *end = tick + NOTE_TICKS.min(next[key as usize].saturating_sub(tick));
+        next[key as usize] = tick;
+    }
+    for (&(tick, key, vel), end) in notes.iter().zip(ends) {
+        if end > tick {
+            clicks.push((tick, note(key, vel, true)));
+            clicks.push((end, note(key, vel, false)));
+        }
+    }
+
+    let mut smf = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(u15::new(TICKS_PER_QUARTER)),
+    ));
+    smf.tracks = vec![track(tempo_map), track(clicks)];
+    smf
+}
+
+pub fn write<W: io::Write>(writer: W, sections: &[Section]) -> io::Result<()> {
+    export(sections).write_std(writer)
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Parse(midly::Error),
+    /// The file counts SMPTE frames instead of quarter notes
+    Timecode,
+    /// A type 2 file, its tracks are separate songs
+    Sequential,
+    /// The denominator isn't 2, 4, 8 or 16, or the numerator is 0
+    TimeSignature {
+        numerator: u8,
+        denominator: u32,
+        bar: u64,
+    },
+    TooLong,
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Parse(e) => write!(f, "not a MIDI file: {e}"),
+            ImportError::Timecode => write!(
+                f,
+                "SMPTE timing isn't supported, only ticks per quarter note"
+            ),
+            ImportError::Sequential => write!(f, "type 2 MIDI files aren't supported"),
+            ImportError::TimeSignature {
+                numerator,
+                denominator,
+                bar,
+            } => write!(
+                f,
+                "the time signature {numerator}/{denominator} of measure {bar} isn't supported"
+            ),
+            ImportError::TooLong => {
+                write!(f, "tempo maps can't be longer than {MAX_BARS} measures")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+/// The measures of a MIDI file, and what couldn't be imported exactly
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    pub sections: Vec<Section>,
+    pub warnings: Vec<String>,
+}
+
+/// Measures counted from 1, e.g. `3, 4, 7`
+fn list(bars: &[u64]) -> String {
+    bars.iter()
+        .map(|bar| bar.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Reads the tempo and time signature events of every track, the other events are left out. Each
+/// measure gets the tempo it has on average, so the measures line up with the arrangement even
+/// where the tempo changes in the middle of one.
+pub fn import(data: &[u8]) -> Result<TempoMap, ImportError> {
+    let smf = Smf::parse(data).map_err(ImportError::Parse)?;
+    let Timing::Metrical(quarter) = smf.header.timing else {
+        return Err(ImportError::Timecode);
+    };
+    if smf.header.format == Format::Sequential {
+        return Err(ImportError::Sequential);
+    }
+    let quarter = quarter.as_int().max(1) as u64;
+
+    let mut tempos = Vec::new();
+    let mut signatures = Vec::new();
+    let mut warnings = Vec::new();
+    let mut end = 0;
+    for track in &smf.tracks {
+        let mut tick = 0;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                    tempos.push((tick, tempo.as_int().max(1)))
+                }
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, ..)) => {
+                    signatures.push((tick, numerator, denominator))
+                }
+                TrackEventKind::Meta(MetaMessage::SmpteOffset(_)) => {
+                    warnings.push(String::from("the SMPTE offset is left out"))
+                }
+                _ => {}
+            }
+        }
+        end = end.max(tick);
+    }
+    tempos.sort_by_key(|(tick, _)| *tick);
+    signatures.sort_by_key(|(tick, ..)| *tick);
+
+    let mut sections: Vec<Section> = Vec::new();
+    let mut signature = TimeSignature::new(4, 4).unwrap();
+    let mut tempo = DEFAULT_TEMPO;
+    let (mut next_tempo, mut next_signature) = (0, 0);
+    let mut misplaced = Vec::new();
+    let mut averaged = Vec::new();
+    let mut rounded = Vec::new();
+    let mut clamped = Vec::new();
+    let mut start = 0;
+    for bar in 1.. {
+        if bar > MAX_BARS {
+            return Err(ImportError::TooLong);
+        }
+        // Time signatures only change on bar lines
+        while let Some(&(tick, numerator, denominator)) = signatures.get(next_signature) {
+            if tick > start {
+                break;
+            }
+            if tick < start && misplaced.last() != Some(&bar) {
+                misplaced.push(bar);
+            }
+            let denominator = 1u32.checked_shl(denominator as u32).unwrap_or(0);
+            signature = TimeSignature::new(numerator as usize, denominator as usize).ok_or(
+                ImportError::TimeSignature {
+                    numerator,
+                    denominator,
+                    bar,
+                },
+            )?;
+            next_signature += 1;
+        }
+
+        let length = quarter * 4 * signature.numerator as u64 / signature.denominator as u64;
+        let bar_end = start + length.max(1);
+        let mut micros = 0.0;
+        let mut at = start;
+        while let Some(&(tick, new)) = tempos.get(next_tempo) {
+            if tick >= bar_end {
+                break;
+            }
+            if tick > start && new != tempo {
+                micros += (tick - at) as f64 * tempo as f64 / quarter as f64;
+                at = tick;
+                if averaged.last() != Some(&bar) {
+                    averaged.push(bar);
+                }
+            }
+            tempo = new;
+            next_tempo += 1;
+        }
+        micros += (bar_end - at) as f64 * tempo as f64 / quarter as f64;
+        let exact = (bar_end - start) as f64 / quarter as f64 * 60_000_000.0 / micros;
+        let whole = exact.round() as u64;
+        let measure = Measure::with_time_signature(signature);
+        // A measure can't last longer than `measure::MAX_DURATION` either
+        let slowest = measure.slowest_bpm().max(*BPM_RANGE.start());
+        let bpm = whole.clamp(slowest, *BPM_RANGE.end());
+        if bpm != whole {
+            clamped.push(bar);
+        } else if (bpm as f64 - exact).abs() > 0.01 {
+            rounded.push(bar);
+        }
+
+        match sections.last_mut() {
+            Some(section) if section.bpm == bpm && section.measure.time_signature == signature => {
+                section.bars += 1
+            }
+            _ => sections.push(Section::new(bpm, measure, 1)),
+        }
+        start = bar_end;
+        if start >= end {
+            break;
+        }
+    }
+
+    if !misplaced.is_empty() {
+        warnings.push(format!(
+            "time signature changes in the middle of a measure start with the next one, in measures {}",
+            list(&misplaced)
+        ));
+    }
+    if !averaged.is_empty() {
+        warnings.push(format!(
+            "the tempo changes in the middle of measures {}, they are played at their average tempo",
+            list(&averaged)
+        ));
+    }
+    if !rounded.is_empty() {
+        warnings.push(format!(
+            "the tempo of measures {} is rounded to a whole BPM",
+            list(&rounded)
+        ));
+    }
+    if !clamped.is_empty() {
+        warnings.push(format!(
+            "the tempo of measures {} is out of {BPM_RANGE:?} BPM or too slow for their length, they are played at the nearest one",
+            list(&clamped)
+        ));
+    }
+    Ok(TempoMap { sections, warnings })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{measure::Measure, rhythm::Rhythm};
+
+    fn absolute<'a>(track: &Track<'a>) -> Vec<(u64, TrackEventKind<'a>)> {
+        let mut tick = 0;
+        track
+            .iter()
+            .map(|event| {
+                tick += event.delta.as_int() as u64;
+                (tick, event.kind)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dense_subdivisions() {
+        // Sixteenths of a sixteenth are 30 ticks apart, closer than the length of a note
+        let measure = "4/16: A m m m | sub=sixteenths".parse::<Measure>().unwrap();
+        let smf = export(&[Section::new(120, measure, 2)]);
+        let mut playing = [None; 128];
+        let mut lengths = Vec::new();
+        for (tick, kind) in absolute(&smf.tracks[1]) {
+            let TrackEventKind::Midi { message, .. } = kind else {
+                continue;
+            };
+            match message {
+                MidiMessage::NoteOn { key, .. } => {
+                    let overlaps = playing[key.as_int() as usize].replace(tick);
+                    assert_eq!(overlaps, None, "key {key} at {tick}");
+                }
+                MidiMessage::NoteOff { key, .. } => {
+                    let on = playing[key.as_int() as usize].take().unwrap();
+                    lengths.push(tick - on);
+                }
+                _ => {}
+            }
+        }
+        assert!(playing.iter().all(Option::is_none));
+        // 15 subdivisions on the low wood block in each measure, the last one is followed by
+        // the accent on the high one
+        assert_eq!(lengths.len(), 32);
+        assert_eq!(lengths.iter().filter(|length| **length == 30).count(), 28);
+        assert!(lengths
+            .iter()
+            .all(|length| *length == 30 || *length == NOTE_TICKS));
+    }
+
+    #[test]
+    fn parses_back() {
+        let mut swung = "3/4: A m m".parse::<Measure>().unwrap();
+        swung.subdivision = Rhythm::Eighth;
+        swung.swing = 2.0 / 3.0;
+        let sections = [
+            Section::new(120, Measure::default(), 2),
+            Section::new(90, swung, 1),
+            Section {
+                ramp_to: Some(150),
+                ..Section::new(100, "7/8:".parse().unwrap(), 3)
+            },
+        ];
+        let mut data = Vec::new();
+        write(&mut data, &sections).unwrap();
+        let smf = Smf::parse(&data).unwrap();
+
+        assert_eq!(smf.header.format, Format::Parallel);
+        assert_eq!(smf.tracks.len(), 2);
+
+        let tempos = absolute(&smf.tracks[0])
+            .into_iter()
+            .filter_map(|(tick, kind)| match kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(t)) => Some((tick, t.as_int())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // 2 bars of 4/4, then 1 bar of 3/4, then 3 bars of 7/8
+        assert_eq!(
+            tempos,
+            [
+                (0, 500_000),
+                (3840, 666_666),
+                (5280, 600_000),
+                (6960, 480_000),
+                (8640, 400_000),
+            ]
+        );
+        let signatures = absolute(&smf.tracks[0])
+            .into_iter()
+            .filter_map(|(tick, kind)| match kind {
+                TrackEventKind::Meta(MetaMessage::TimeSignature(n, d, _, _)) => {
+                    Some((tick, n, 1 << d))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(signatures, [(0, 4, 4), (3840, 3, 4), (5280, 7, 8)]);
+
+        let notes = absolute(&smf.tracks[1])
+            .into_iter()
+            .filter_map(|(tick, kind)| match kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOn { key, .. },
+                    ..
+                } => Some((tick, key.as_int())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(notes.len(), 8 + 6 + 21);
+        assert_eq!(
+            notes[..5],
+            [
+                (0, HIGH_WOOD_BLOCK),
+                (480, LOW_WOOD_BLOCK),
+                (960, LOW_WOOD_BLOCK),
+                (1440, LOW_WOOD_BLOCK),
+                (1920, HIGH_WOOD_BLOCK),
+            ]
+        );
+        // Swung eighths in 3/4
+        assert_eq!(
+            notes[8..12],
+            [
+                (3840, HIGH_WOOD_BLOCK),
+                (4160, LOW_WOOD_BLOCK),
+                (4320, LOW_WOOD_BLOCK),
+                (4640, LOW_WOOD_BLOCK),
+            ]
+        );
+        // 7/8 grouped 2+2+3
+        assert_eq!(
+            notes[14..18],
+            [
+                (5280, HIGH_WOOD_BLOCK),
+                (5520, LOW_WOOD_BLOCK),
+                (5760, HIGH_WOOD_BLOCK),
+                (6000, LOW_WOOD_BLOCK),
+            ]
+        );
+    }
+
+    fn summary(map: &TempoMap) -> Vec<(u64, String, u64)> {
+        map.sections
+            .iter()
+            .map(|section| {
+                (
+                    section.bpm,
+                    section.measure.time_signature.to_string(),
+                    section.bars,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn imports_exported() {
+        let sections = [
+            Section::new(120, Measure::default(), 2),
+            Section::new(90, "6/8:".parse().unwrap(), 1),
+            Section {
+                ramp_to: Some(120),
+                ..Section::new(100, "7/8:".parse().unwrap(), 3)
+            },
+        ];
+        let mut data = Vec::new();
+        write(&mut data, &sections).unwrap();
+        let map = import(&data).unwrap();
+        assert_eq!(
+            summary(&map),
+            [
+                (120, String::from("4/4"), 2),
+                (90, String::from("6/8"), 1),
+                (100, String::from("7/8"), 1),
+                (110, String::from("7/8"), 1),
+                (120, String::from("7/8"), 1),
+            ]
+        );
+        assert!(map.warnings.is_empty(), "{:?}", map.warnings);
+    }
+
+    #[test]
+    fn averages_tempo_changes() {
+        let meta = |delta: u32, message| TrackEvent {
+            delta: u28::new(delta),
+            kind: TrackEventKind::Meta(message),
+        };
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(u15::new(TICKS_PER_QUARTER)),
+        ));
+        smf.tracks.push(vec![
+            meta(0, MetaMessage::Tempo(u24::new(500_000))),
+            // 2 beats at 120 then 2 beats at 60 in the second measure
+            meta(2880, MetaMessage::Tempo(u24::new(1_000_000))),
+            // 3/4 from the middle of the third measure on
+            meta(1440, MetaMessage::TimeSignature(3, 2, 24, 8)),
+            meta(2880, MetaMessage::EndOfTrack),
+        ]);
+        let mut data = Vec::new();
+        smf.write_std(&mut data).unwrap();
+        let map = import(&data).unwrap();
+        assert_eq!(
+            summary(&map),
+            [
+                (120, String::from("4/4"), 1),
+                (80, String::from("4/4"), 1),
+                (60, String::from("4/4"), 1),
+                (60, String::from("3/4"), 1),
+            ]
+        );
+        assert_eq!(map.warnings.len(), 2, "{:?}", map.warnings);
+
+        smf.header.timing = Timing::Timecode(midly::Fps::Fps25, 40);
+        data.clear();
+        smf.write_std(&mut data).unwrap();
+        assert!(matches!(import(&data), Err(ImportError::Timecode)));
+    }
+
+    #[test]
+    fn speeds_up_long_measures() {
+        let meta = |delta: u32, message| TrackEvent {
+            delta: u28::new(delta),
+            kind: TrackEventKind::Meta(message),
+        };
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(u15::new(TICKS_PER_QUARTER)),
+        ));
+        // 30/2 at about 3.6 BPM lasts more than a quarter of an hour
+        smf.tracks.push(vec![
+            meta(0, MetaMessage::Tempo(u24::max_value())),
+            meta(0, MetaMessage::TimeSignature(30, 1, 24, 8)),
+            meta(60 * TICKS_PER_QUARTER as u32, MetaMessage::EndOfTrack),
+        ]);
+        let mut data = Vec::new();
+        smf.write_std(&mut data).unwrap();
+        let map = import(&data).unwrap();
+        assert_eq!(summary(&map), [(15, String::from("30/2"), 1)]);
+        assert!(!map.sections[0].measure.too_long(15));
+        assert_eq!(map.warnings.len(), 1, "{:?}", map.warnings);
+    }
+}
@@ -0,0 +1,160 @@
+//! Tap tempo, used by the REST API, MIDI and the desktop app
+//!
+//! The tempo is the average of the intervals between the last taps, leaving out the ones too far
+//! from their median, e.g. a missed or doubled tap.
+
+use std::time::{Duration, Instant};
+
+use crate::player::BPM_RANGE;
+
+/// Taps further apart than this start a new tempo
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_TAPS: usize = 8;
+/// Intervals further than this fraction from the median are left out
+const TOLERANCE: f64 = 0.25;
+
+#[derive(Debug, Clone, Default)]
+pub struct TapTempo {
+    taps: Vec<Instant>,
+}
+
+impl TapTempo {
+    /// Records a tap, the tempo is known from the second tap on
+    pub fn tap(&mut self, now: Instant) -> Option<u64> {
+        if self
+            .taps
+            .last()
+            .is_some_and(|last| now.saturating_duration_since(*last) > TIMEOUT)
+        {
+            self.taps.clear();
+        }
+        self.taps.push(now);
+        if self.taps.len() > MAX_TAPS {
+            self.taps.remove(0);
+        }
+        self.bpm()
+    }
+
+    /// In `BPM_RANGE`, taps closer together than its fastest tempo are counted at that tempo
+    pub fn bpm(&self) -> Option<u64> {
+        let intervals = self
+            .taps
+            .windows(2)
+            .map(|pair| pair[1].saturating_duration_since(pair[0]).as_secs_f64())
+            .collect::<Vec<f64>>();
+        bpm(&intervals).map(|bpm| (bpm.round() as u64).clamp(*BPM_RANGE.start(), *BPM_RANGE.end()))
+    }
+
+    /// The next tap starts a new tempo, e.g. after stopping
+    pub fn reset(&mut self) {
+        self.taps.clear();
+    }
+}
+
+/// Tempo from the intervals between taps in seconds, `None` without a usable interval
+pub fn bpm(intervals: &[f64]) -> Option<f64> {
+    let mut sorted = intervals
+        .iter()
+        .copied()
+        .filter(|interval| *interval > 0.0)
+        .collect::<Vec<f64>>();
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    let kept = sorted
+        .iter()
+        .filter(|interval| (*interval - median).abs() <= median * TOLERANCE)
+        .collect::<Vec<&f64>>();
+    let average = kept.iter().copied().sum::<f64>() / kept.len() as f64;
+    Some(60.0 / average)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn taps(tempo: &mut TapTempo, start: Instant, millis: &[u64]) -> Option<u64> {
+        let mut result = None;
+        for ms in millis {
+            result = tempo.tap(start + Duration::from_millis(*ms));
+        }
+        result
+    }
+
+    #[test]
+    fn needs_two_taps() {
+        let mut tempo = TapTempo::default();
+        assert_eq!(tempo.tap(Instant::now()), None);
+    }
+
+    #[test]
+    fn steady_taps() {
+        let mut tempo = TapTempo::default();
+        assert_eq!(
+            taps(&mut tempo, Instant::now(), &[0, 500, 1000, 1500]),
+            Some(120)
+        );
+    }
+
+    #[test]
+    fn rejects_outliers() {
+        let mut tempo = TapTempo::default();
+        // A late tap followed by an early one
+        let bpm = taps(
+            &mut tempo,
+            Instant::now(),
+            &[0, 500, 1000, 1800, 2000, 2500, 3000],
+        );
+        assert_eq!(bpm, Some(120));
+    }
+
+    #[test]
+    fn resets_after_timeout() {
+        let mut tempo = TapTempo::default();
+        let start = Instant::now();
+        taps(&mut tempo, start, &[0, 1000, 2000]);
+        assert_eq!(taps(&mut tempo, start, &[5000, 5500]), Some(120));
+    }
+
+    #[test]
+    fn keeps_the_last_taps() {
+        let mut tempo = TapTempo::default();
+        let start = Instant::now();
+        taps(
+            &mut tempo,
+            start,
+            &[0, 1000, 2000, 3000, 4000, 5000, 6000, 7000],
+        );
+        let bpm = taps(
+            &mut tempo,
+            start,
+            &[7600, 8200, 8800, 9400, 10000, 10600, 11200],
+        );
+        assert_eq!(bpm, Some(100));
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut tempo = TapTempo::default();
+        let start = Instant::now();
+        taps(&mut tempo, start, &[0, 1000]);
+        tempo.reset();
+        assert_eq!(taps(&mut tempo, start, &[1500]), None);
+        assert_eq!(taps(&mut tempo, start, &[2000]), Some(120));
+    }
+
+    #[test]
+    fn fastest_tempo() {
+        let mut tempo = TapTempo::default();
+        let start = Instant::now();
+        assert_eq!(taps(&mut tempo, start, &[0, 10, 20]), Some(1000));
+    }
+
+    #[test]
+    fn intervals() {
+        assert_eq!(bpm(&[]), None);
+        assert_eq!(bpm(&[0.5, 0.5, 1.0]), Some(120.0));
+    }
+}
@@ -0,0 +1,236 @@
+//! Optional HTTPS for the REST API and the web remote, e.g. on a shared venue network. The
+//! certificate is either given by the user or self-signed. Clients can't verify a self-signed
+//! certificate, so they pin its fingerprint instead, which the QR code carries.
+
+use std::{
+    fmt::Display,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use local_ip_address::local_ip;
+use poem::listener::{RustlsCertificate, RustlsConfig};
+use rustls::{crypto::ring::sign::any_supported_type, sign::CertifiedKey};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug)]
+pub enum TlsError {
+    /// No PEM certificate in the certificate file
+    InvalidCertificate,
+    /// No PEM private key of a supported type in the key file
+    InvalidKey,
+    /// The private key isn't the certificate's
+    KeyMismatch,
+    Generate(rcgen::Error),
+    Io(io::Error),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::InvalidCertificate => write!(f, "no PEM certificate found"),
+            TlsError::InvalidKey => write!(f, "no supported PEM private key found"),
+            TlsError::KeyMismatch => write!(f, "the private key doesn't match the certificate"),
+            TlsError::Generate(e) => write!(f, "couldn't generate a certificate: {e}"),
+            TlsError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<io::Error> for TlsError {
+    fn from(e: io::Error) -> Self {
+        TlsError::Io(e)
+    }
+}
+
+impl From<rcgen::Error> for TlsError {
+    fn from(e: rcgen::Error) -> Self {
+        TlsError::Generate(e)
+    }
+}
+
+/// A certificate and its private key, both PEM encoded
+pub struct Tls {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+    fingerprint: String,
+}
+
+impl Tls {
+    /// Where the self-signed certificate is kept
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("racoon").join("tls"))
+    }
+
+    /// Reads a certificate, optionally followed by its chain, and its private key
+    pub fn load(cert: &Path, key: &Path) -> Result<Self, TlsError> {
+        Self::new(fs::read(cert)?, fs::read(key)?)
+    }
+
+    /// The self-signed certificate kept in `dir`, generated the first time. It's kept so the
+    /// fingerprint pinned by the clients doesn't change on every start.
+    pub fn self_signed(dir: &Path) -> Result<Self, TlsError> {
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        if cert_path.exists() && key_path.exists() {
+            return Self::load(&cert_path, &key_path);
+        }
+
+        // The clients pin the fingerprint, so the names only matter to browsers
+        let mut names = vec![String::from("localhost")];
+        if let Ok(ip) = local_ip() {
+            names.push(ip.to_string());
+        }
+        let certified = rcgen::generate_simple_self_signed(names)?;
+        let cert = certified.cert.pem();
+        let key = certified.key_pair.serialize_pem();
+
+        fs::create_dir_all(dir)?;
+        fs::write(&cert_path, &cert)?;
+        write_private(&key_path, key.as_bytes())?;
+        Self::new(cert.into_bytes(), key.into_bytes())
+    }
+
+    fn new(cert: Vec<u8>, key: Vec<u8>) -> Result<Self, TlsError> {
+        // The first certificate is the server's, the others are its chain
+        let der =
+            CertificateDer::from_pem_slice(&cert).map_err(|_| TlsError::InvalidCertificate)?;
+        let key_der = PrivateKeyDer::from_pem_slice(&key).map_err(|_| TlsError::InvalidKey)?;
+        // Checked now, the server would only fail on the first connection otherwise
+        let signing_key = any_supported_type(&key_der).map_err(|_| TlsError::InvalidKey)?;
+        CertifiedKey::new(vec![der.clone()], signing_key)
+            .keys_match()
+            .map_err(|_| TlsError::KeyMismatch)?;
+        Ok(Tls {
+            fingerprint: fingerprint(&der),
+            cert,
+            key,
+        })
+    }
+
+    /// SHA-256 fingerprint of the certificate, e.g. `3A:F1:...`, the same as
+    /// `openssl x509 -noout -fingerprint -sha256` prints
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub(crate) fn config(&self) -> RustlsConfig {
+        RustlsConfig::new().fallback(
+            RustlsCertificate::new()
+                .cert(self.cert.clone())
+                .key(self.key.clone()),
+        )
+    }
+}
+
+fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Only readable by the user, where the platform allows it
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("racoon-tls-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn fingerprints() {
+        // The SHA-256 test vector, formatted the way openssl prints it
+        assert_eq!(
+            fingerprint(b"abc"),
+            "BA:78:16:BF:8F:01:CF:EA:41:41:40:DE:5D:AE:22:23:\
+             B0:03:61:A3:96:17:7A:9C:B4:10:FF:61:F2:00:15:AD"
+        );
+    }
+
+    #[test]
+    fn fingerprint_of_the_certificate() {
+        let dir = temp_dir("fingerprint");
+        let tls = Tls::self_signed(&dir).unwrap();
+        let cert = fs::read(dir.join("cert.pem")).unwrap();
+        let der = CertificateDer::from_pem_slice(&cert).unwrap();
+        assert_eq!(tls.fingerprint(), fingerprint(&der));
+        assert_eq!(tls.fingerprint().len(), 32 * 3 - 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn self_signed_is_kept() {
+        let dir = temp_dir("kept");
+        let first = Tls::self_signed(&dir).unwrap();
+        let cert = fs::read(dir.join("cert.pem")).unwrap();
+        let second = Tls::self_signed(&dir).unwrap();
+        assert_eq!(first.fingerprint(), second.fingerprint());
+        assert_eq!(fs::read(dir.join("cert.pem")).unwrap(), cert);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.join("key.pem"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bad_files() {
+        let dir = temp_dir("bad");
+        Tls::self_signed(&dir).unwrap();
+        let other = temp_dir("other");
+        Tls::self_signed(&other).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let garbage = dir.join("garbage.pem");
+        fs::write(&garbage, "not a PEM file").unwrap();
+
+        assert!(matches!(
+            Tls::load(&garbage, &key),
+            Err(TlsError::InvalidCertificate)
+        ));
+        // The key where the certificate should be
+        assert!(matches!(
+            Tls::load(&key, &key),
+            Err(TlsError::InvalidCertificate)
+        ));
+        assert!(matches!(
+            Tls::load(&cert, &garbage),
+            Err(TlsError::InvalidKey)
+        ));
+        assert!(matches!(
+            Tls::load(&cert, &other.join("key.pem")),
+            Err(TlsError::KeyMismatch)
+        ));
+        assert!(matches!(
+            Tls::load(&dir.join("missing.pem"), &key),
+            Err(TlsError::Io(_))
+        ));
+        assert!(Tls::load(&cert, &key).is_ok());
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(other).unwrap();
+    }
+}
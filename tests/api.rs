@@ -0,0 +1,633 @@
+//! The REST API on a player that records instead of playing, so no sound card is needed
+
+use std::time::{Duration, Instant};
+
+use futures_util::StreamExt;
+use poem::{http::StatusCode, test::TestClient};
+use racoon::{
+    backend::RecordingSink,
+    measure::Measure,
+    pairing::{Pairing, Role},
+    player::Player,
+    presets::Presets,
+    render::Section,
+    server, smf, AppState, State,
+};
+
+fn state() -> (AppState, RecordingSink) {
+    let sink = RecordingSink::new();
+    let state = State::new(
+        Player::new(sink.clone()),
+        Presets::default(),
+        Default::default(),
+    );
+    (state, sink)
+}
+
+#[tokio::test]
+async fn health() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state, 0, false));
+    client.get("/api/health").send().await.assert_status_is_ok();
+}
+
+#[tokio::test]
+async fn remote() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state, 0, false));
+
+    let response = client.get("/").send().await;
+    response.assert_status_is_ok();
+    response.assert_content_type("text/html; charset=utf-8");
+    let page = response.0.into_body().into_string().await.unwrap();
+    assert!(page.contains("remote.js"));
+    client
+        .get("/remote.js")
+        .send()
+        .await
+        .assert_content_type("text/javascript; charset=utf-8");
+    client.get("/remote.css").send().await.assert_status_is_ok();
+}
+
+#[tokio::test]
+async fn start_and_stop() {
+    let (state, sink) = state();
+    let client = TestClient::new(server::app(state.clone(), 0, false));
+
+    client.post("/api/start").send().await.assert_status_is_ok();
+    assert!(state.player.lock().unwrap().playing());
+    let recording = sink.recording();
+    assert!(!recording.is_empty());
+    assert!(recording.iter().any(|sample| *sample != 0.0));
+
+    client.post("/api/stop").send().await.assert_status_is_ok();
+    assert!(!state.player.lock().unwrap().playing());
+}
+
+#[tokio::test]
+async fn start_options() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state.clone(), 0, false));
+
+    for options in [
+        serde_json::json!({ "stop_after_bars": 0 }),
+        serde_json::json!({ "stop_after_minutes": 0.0 }),
+        serde_json::json!({ "stop_after_minutes": -1.0 }),
+        serde_json::json!({ "count_in": 17 }),
+        serde_json::json!({ "count_in": u64::MAX }),
+    ] {
+        client
+            .post("/api/start")
+            .body_json(&options)
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+    }
+    assert!(!state.player.lock().unwrap().playing());
+    client
+        .post("/api/start")
+        .body_json(&serde_json::json!({ "count_in": 1, "stop_after_bars": 4, "end_sound": true }))
+        .send()
+        .await
+        .assert_status_is_ok();
+    assert!(state.player.lock().unwrap().playing());
+}
+
+#[tokio::test]
+async fn bpm_range() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state.clone(), 0, false));
+
+    client.post("/api/start").send().await.assert_status_is_ok();
+    for bpm in [0, 1001] {
+        client
+            .post(format!("/api/set_bpm/{bpm}"))
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+    }
+    client
+        .post("/api/set_bpm/1000")
+        .send()
+        .await
+        .assert_status_is_ok();
+    assert_eq!(state.player.lock().unwrap().bpm(), 1000);
+
+    // Whatever else sets the tempo is clamped
+    state.set_bpm(0);
+    assert_eq!(state.player.lock().unwrap().bpm(), 1);
+}
+
+#[tokio::test]
+async fn tap() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state.clone(), 0, false));
+
+    let tap = || async { client.post("/api/tap").send().await.json().await };
+    tap().await.value().assert_null();
+    tap()
+        .await
+        .value()
+        .assert_i64(state.player.lock().unwrap().bpm() as i64);
+    // Stopping forgets the taps
+    client.post("/api/stop").send().await.assert_status_is_ok();
+    tap().await.value().assert_null();
+}
+
+#[tokio::test]
+async fn layers() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state.clone(), 0, false));
+
+    for volume in ["-1", "2.5", "NaN", "inf"] {
+        client
+            .post("/api/layers")
+            .query("volume", &volume)
+            .content_type("text/plain")
+            .body("3/4: A m m")
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+    }
+    client
+        .post("/api/layers")
+        .query("volume", &0.5)
+        .query("fit", &true)
+        .content_type("text/plain")
+        .body("3/4: A m m")
+        .send()
+        .await
+        .assert_status_is_ok();
+    let layers = state.player.lock().unwrap().layers().to_vec();
+    assert_eq!(layers.len(), 1);
+    assert_eq!(layers[0].volume, 0.5);
+    assert!(layers[0].fit);
+}
+
+#[tokio::test]
+async fn long_measures() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state.clone(), 0, false));
+
+    // 4/4 at the slowest tempo is as long as a measure gets
+    client
+        .post("/api/set_bpm/1")
+        .send()
+        .await
+        .assert_status_is_ok();
+    client
+        .post("/api/measure")
+        .content_type("text/plain")
+        .body("5/4:")
+        .send()
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+
+    client
+        .post("/api/set_bpm/120")
+        .send()
+        .await
+        .assert_status_is_ok();
+    client
+        .post("/api/measure")
+        .content_type("text/plain")
+        .body("32/2:")
+        .send()
+        .await
+        .assert_status_is_ok();
+    client
+        .post("/api/set_bpm/15")
+        .send()
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+    client
+        .post("/api/set_bpm/16")
+        .send()
+        .await
+        .assert_status_is_ok();
+    assert_eq!(state.player.lock().unwrap().bpm(), 16);
+
+    client
+        .post("/api/render")
+        .body_json(&serde_json::json!({
+            "sections": [{ "bpm": 120, "ramp_to": 1, "measure": "8/4:", "bars": 1 }]
+        }))
+        .send()
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn measure() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state, 0, false));
+
+    client
+        .post("/api/measure")
+        .content_type("text/plain")
+        .body("7/8: Am Mm Mmm")
+        .send()
+        .await
+        .assert_status_is_ok();
+    client
+        .get("/api/measure")
+        .send()
+        .await
+        .assert_text("7/8: Am Mm Mmm")
+        .await;
+    // A dot for every beat on the remote
+    let response = client.get("/api/status").send().await;
+    response.assert_status_is_ok();
+    response
+        .json()
+        .await
+        .value()
+        .object()
+        .get("beats")
+        .assert_string_array(&["up", "down", "mid", "down", "mid", "down", "down"]);
+
+    client
+        .post("/api/measure")
+        .content_type("text/plain")
+        .body("7/7: A")
+        .send()
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+    for huge in ["18446744073709551615/4: A", "1000000000/4:"] {
+        client
+            .post("/api/measure")
+            .content_type("text/plain")
+            .body(huge)
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+    }
+}
+
+#[tokio::test]
+async fn levels() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state.clone(), 0, false));
+
+    client.post("/api/start").send().await.assert_status_is_ok();
+    client
+        .post("/api/levels")
+        .body_json(&serde_json::json!({ "master": -6.0, "limiter": false }))
+        .send()
+        .await
+        .assert_status_is_ok();
+    let response = client.get("/api/status").send().await;
+    response.assert_status_is_ok();
+    let status = response.json().await;
+    let status = status.value().object();
+    status.get("playing").assert_bool(true);
+    let levels = status.get("levels").object();
+    levels.get("master").assert_f64(-6.0);
+    levels.get("up").assert_f64(0.0);
+    levels.get("limiter").assert_bool(false);
+    assert_eq!(state.current.read().unwrap().levels.master, -6.0);
+
+    client
+        .post("/api/levels")
+        .body_json(&serde_json::json!({ "down": 100.0 }))
+        .send()
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn silent() {
+    let (state, sink) = state();
+    let client = TestClient::new(server::app(state.clone(), 0, false));
+
+    client
+        .post("/api/set_silent/true")
+        .send()
+        .await
+        .assert_status_is_ok();
+    // The recording sink plays every measure right away, the latency keeps the last beat ahead
+    client
+        .post("/api/set_latency/800")
+        .send()
+        .await
+        .assert_status_is_ok();
+    client.post("/api/start").send().await.assert_status_is_ok();
+    let recording = sink.recording();
+    assert!(!recording.is_empty());
+    assert!(recording.iter().all(|sample| *sample == 0.0));
+
+    let response = client.get("/api/cue").send().await;
+    response.assert_status_is_ok();
+    let cue = response.json().await;
+    let cue = cue.value().object();
+    cue.get("beat").assert_i64(3);
+    cue.get("sound_type").assert_string("down");
+    cue.get("vibration").assert_i64_array(&[40]);
+}
+
+#[tokio::test]
+async fn cue_waits_for_the_start() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state, 0, false));
+
+    let waited = Instant::now();
+    let (response, ()) = tokio::join!(client.get("/api/cue").send(), async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        client
+            .post("/api/set_latency/800")
+            .send()
+            .await
+            .assert_status_is_ok();
+        client.post("/api/start").send().await.assert_status_is_ok();
+    });
+    response.assert_status_is_ok();
+    assert!(waited.elapsed() < Duration::from_secs(5));
+    let cue = response.json().await;
+    let in_ms = cue.value().object().get("in_ms").i64();
+    assert!((0..2000).contains(&in_ms), "{in_ms}");
+}
+
+#[tokio::test]
+async fn events() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state, 0, false));
+
+    let response = client.get("/api/events").send().await;
+    response.assert_status_is_ok();
+    response.assert_content_type("text/event-stream");
+    let mut events = response.json_sse_stream();
+
+    client
+        .post("/api/set_bpm/140")
+        .send()
+        .await
+        .assert_status_is_ok();
+    client
+        .post("/api/measure")
+        .content_type("text/plain")
+        .body("3/4: A m -")
+        .send()
+        .await
+        .assert_status_is_ok();
+    client.post("/api/stop").send().await.assert_status_is_ok();
+
+    let event = events.next().await.unwrap();
+    event.value().object().get("type").assert_string("bpm");
+    event.value().object().get("bpm").assert_i64(140);
+    let event = events.next().await.unwrap();
+    event.value().object().get("type").assert_string("measure");
+    event
+        .value()
+        .object()
+        .get("measure")
+        .assert_string("3/4: A m -");
+    event
+        .value()
+        .object()
+        .get("beats")
+        .assert_string_array(&["up", "down", "rest"]);
+    let event = events.next().await.unwrap();
+    event.value().object().get("type").assert_string("stop");
+}
+
+#[tokio::test]
+async fn pairing() {
+    let (state, _) = state();
+    state.set_pairing(Pairing::new(false, false));
+    let code = state.pairing.get().unwrap().code(Role::Admin).to_string();
+    let client = TestClient::new(server::app(state.clone(), 0, false));
+
+    client
+        .get("/api/status")
+        .send()
+        .await
+        .assert_status(StatusCode::UNAUTHORIZED);
+    client.get("/").send().await.assert_status_is_ok();
+    client
+        .post("/api/pair")
+        .body_json(&serde_json::json!({ "code": "wrong", "name": "phone" }))
+        .send()
+        .await
+        .assert_status(StatusCode::FORBIDDEN);
+
+    let response = client
+        .post("/api/pair")
+        .body_json(&serde_json::json!({ "code": code, "name": "phone" }))
+        .send()
+        .await;
+    response.assert_status_is_ok();
+    let json = response.json().await;
+    let id = json.value().object().get("id").i64();
+    let token = json.value().object().get("token").string().to_string();
+
+    client
+        .post("/api/set_bpm/140")
+        .header("Authorization", format!("Bearer {token}"))
+        .send()
+        .await
+        .assert_status_is_ok();
+    assert_eq!(state.player.lock().unwrap().bpm(), 140);
+    client
+        .get("/api/status")
+        .query("token", &token)
+        .send()
+        .await
+        .assert_status_is_ok();
+    let response = client
+        .get("/api/clients")
+        .header("Authorization", format!("Bearer {token}"))
+        .send()
+        .await;
+    response.assert_status_is_ok();
+    let json = response.json().await;
+    json.value()
+        .array()
+        .get(0)
+        .object()
+        .get("name")
+        .assert_string("phone");
+
+    client
+        .delete(format!("/api/clients/{id}"))
+        .header("Authorization", format!("Bearer {token}"))
+        .send()
+        .await
+        .assert_status_is_ok();
+    client
+        .get("/api/status")
+        .header("Authorization", format!("Bearer {token}"))
+        .send()
+        .await
+        .assert_status(StatusCode::UNAUTHORIZED);
+    client
+        .delete(format!("/api/clients/{id}"))
+        .send()
+        .await
+        .assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn open_reads() {
+    let (state, _) = state();
+    state.set_pairing(Pairing::new(true, false));
+    let client = TestClient::new(server::app(state, 0, false));
+
+    let response = client.get("/api/status").send().await;
+    response.assert_status_is_ok();
+    let json = response.json().await;
+    json.value().object().get("role").assert_string("viewer");
+    client
+        .post("/api/start")
+        .send()
+        .await
+        .assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn viewer() {
+    let (state, _) = state();
+    state.set_pairing(Pairing::new(false, false));
+    let code = state.pairing.get().unwrap().code(Role::Viewer).to_string();
+    let client = TestClient::new(server::app(state.clone(), 0, false));
+
+    let response = client
+        .post("/api/pair")
+        .body_json(&serde_json::json!({ "code": code, "name": "bass" }))
+        .send()
+        .await;
+    response.assert_status_is_ok();
+    let json = response.json().await;
+    json.value().object().get("role").assert_string("viewer");
+    let token = json.value().object().get("token").string().to_string();
+    let bearer = format!("Bearer {token}");
+
+    let response = client
+        .get("/api/status")
+        .header("Authorization", &bearer)
+        .send()
+        .await;
+    response.assert_status_is_ok();
+    let json = response.json().await;
+    json.value().object().get("role").assert_string("viewer");
+    client
+        .get("/api/events")
+        .header("Authorization", &bearer)
+        .send()
+        .await
+        .assert_status_is_ok();
+
+    client
+        .post("/api/set_bpm/140")
+        .header("Authorization", &bearer)
+        .send()
+        .await
+        .assert_status(StatusCode::FORBIDDEN);
+    assert_eq!(state.player.lock().unwrap().bpm(), 120);
+    client
+        .post("/api/start")
+        .header("Authorization", &bearer)
+        .send()
+        .await
+        .assert_status(StatusCode::FORBIDDEN);
+    assert!(!state.player.lock().unwrap().playing());
+    client
+        .get("/api/clients")
+        .header("Authorization", &bearer)
+        .send()
+        .await
+        .assert_status(StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn render() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state, 0, false));
+
+    let response = client
+        .post("/api/render")
+        .body_json(&serde_json::json!({ "bars": 1 }))
+        .send()
+        .await;
+    response.assert_status_is_ok();
+    response.assert_content_type("audio/wav");
+    let wav = response.0.into_body().into_vec().await.unwrap();
+    assert_eq!(&wav[..4], b"RIFF");
+    // 2 seconds of 16 bit stereo at 48 kHz
+    assert_eq!(wav.len(), 44 + 2 * 48_000 * 4);
+
+    // The limit is 20 minutes at 48 kHz, and 5 at 192 kHz
+    let sections = serde_json::json!([{ "bpm": 60, "measure": "4/4:", "bars": 90 }]);
+    client
+        .post("/api/render")
+        .body_json(&serde_json::json!({ "sections": sections, "sample_rate": 192_000 }))
+        .send()
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+    for section in [
+        serde_json::json!({ "bpm": 1001, "measure": "4/4:", "bars": 1 }),
+        serde_json::json!({ "bpm": 60, "measure": "4/4:", "bars": 100_001 }),
+    ] {
+        client
+            .post("/api/render")
+            .body_json(&serde_json::json!({ "sections": [section] }))
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+    }
+}
+
+#[tokio::test]
+async fn import_midi() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state.clone(), 0, false));
+
+    let sections = [
+        Section::new(100, Measure::default(), 2),
+        Section::new(140, "6/8:".parse().unwrap(), 1),
+    ];
+    let mut file = Vec::new();
+    smf::write(&mut file, &sections).unwrap();
+    client
+        .post("/api/arrangement/midi")
+        .content_type("application/octet-stream")
+        .body(file)
+        .send()
+        .await
+        .assert_status_is_ok();
+    assert_eq!(state.player.lock().unwrap().arrangement().len(), 2);
+
+    client
+        .post("/api/arrangement/midi")
+        .content_type("application/octet-stream")
+        .body("not a MIDI file")
+        .send()
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+
+    client
+        .delete("/api/arrangement")
+        .send()
+        .await
+        .assert_status_is_ok();
+    assert!(state.player.lock().unwrap().arrangement().is_empty());
+}
+
+#[tokio::test]
+async fn devices_without_output() {
+    let (state, _) = state();
+    let client = TestClient::new(server::app(state, 0, false));
+
+    client
+        .get("/api/devices")
+        .send()
+        .await
+        .assert_json(serde_json::json!([]))
+        .await;
+    client
+        .post("/api/device")
+        .content_type("text/plain")
+        .body("In-ears")
+        .send()
+        .await
+        .assert_status(StatusCode::CONFLICT);
+}